@@ -0,0 +1,101 @@
+//! Constant expressions: numbers and symbols combined with `+ - * /` and parentheses.
+
+use super::ErrorKind;
+use crate::lex::{Tok, Token};
+
+#[derive(Debug, Clone)]
+pub enum Expr {
+    Num(i64),
+    Sym(String, usize), // Symbol name and column
+    Neg(Box<Expr>),
+    Bin(char, Box<Expr>, Box<Expr>, usize), // Operator, operands and column of the operator
+}
+
+/// Parse the whole token slice `tokens` as an expression.  `end` is the column following the
+/// last token, used to report a missing operand.
+pub fn parse(tokens: &[Token], end: usize) -> Result<Expr, (usize, ErrorKind)> {
+    let mut pos = 0;
+    let expr = sum(tokens, &mut pos, end)?;
+    match tokens.get(pos) {
+        None => Ok(expr),
+        Some(t) => Err((t.col, ErrorKind::Expected("end of expression"))),
+    }
+}
+
+fn sum(tokens: &[Token], pos: &mut usize, end: usize) -> Result<Expr, (usize, ErrorKind)> {
+    let mut lhs = product(tokens, pos, end)?;
+    while let Some(t) = tokens.get(*pos) {
+        if !(t.is_punct('+') || t.is_punct('-')) {
+            break;
+        }
+        *pos += 1;
+        let rhs = product(tokens, pos, end)?;
+        let op = if t.is_punct('+') { '+' } else { '-' };
+        lhs = Expr::Bin(op, Box::new(lhs), Box::new(rhs), t.col);
+    }
+    Ok(lhs)
+}
+
+fn product(tokens: &[Token], pos: &mut usize, end: usize) -> Result<Expr, (usize, ErrorKind)> {
+    let mut lhs = unary(tokens, pos, end)?;
+    while let Some(t) = tokens.get(*pos) {
+        if !(t.is_punct('*') || t.is_punct('/')) {
+            break;
+        }
+        *pos += 1;
+        let rhs = unary(tokens, pos, end)?;
+        let op = if t.is_punct('*') { '*' } else { '/' };
+        lhs = Expr::Bin(op, Box::new(lhs), Box::new(rhs), t.col);
+    }
+    Ok(lhs)
+}
+
+fn unary(tokens: &[Token], pos: &mut usize, end: usize) -> Result<Expr, (usize, ErrorKind)> {
+    let t = match tokens.get(*pos) {
+        Some(t) => t,
+        None => return Err((end, ErrorKind::Expected("expression"))),
+    };
+    *pos += 1;
+    match &t.tok {
+        Tok::Num(n) => Ok(Expr::Num(*n)),
+        Tok::Ident(s) => Ok(Expr::Sym(s.to_string(), t.col)),
+        Tok::Punct('-') => Ok(Expr::Neg(Box::new(unary(tokens, pos, end)?))),
+        Tok::Punct('+') => unary(tokens, pos, end),
+        Tok::Punct('(') => {
+            let expr = sum(tokens, pos, end)?;
+            match tokens.get(*pos) {
+                Some(t) if t.is_punct(')') => {
+                    *pos += 1;
+                    Ok(expr)
+                }
+                Some(t) => Err((t.col, ErrorKind::Expected("`)`"))),
+                None => Err((end, ErrorKind::Expected("`)`"))),
+            }
+        }
+        _ => Err((t.col, ErrorKind::Expected("expression"))),
+    }
+}
+
+impl Expr {
+    /// Evaluate the expression, resolving symbols with `sym`.
+    pub fn eval<F>(&self, sym: &mut F) -> Result<i64, (usize, ErrorKind)>
+    where
+        F: FnMut(&str, usize) -> Result<i64, (usize, ErrorKind)>,
+    {
+        Ok(match self {
+            Expr::Num(n) => *n,
+            Expr::Sym(name, col) => sym(name, *col)?,
+            Expr::Neg(e) => e.eval(sym)?.wrapping_neg(),
+            Expr::Bin(op, a, b, col) => {
+                let (a, b) = (a.eval(sym)?, b.eval(sym)?);
+                match op {
+                    '+' => a.wrapping_add(b),
+                    '-' => a.wrapping_sub(b),
+                    '*' => a.wrapping_mul(b),
+                    _ if b == 0 => return Err((*col, ErrorKind::DivisionByZero)),
+                    _ => a.wrapping_div(b),
+                }
+            }
+        })
+    }
+}
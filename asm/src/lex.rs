@@ -0,0 +1,125 @@
+//! Tokenizer for one line of assembly source.
+
+use super::ErrorKind;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Tok<'a> {
+    Ident(&'a str),
+    Num(i64),
+    Str(Vec<u8>),
+    Punct(char),
+}
+
+#[derive(Debug, Clone)]
+pub struct Token<'a> {
+    pub tok: Tok<'a>,
+    pub col: usize, // Column of the first character, starting at 1
+}
+
+impl<'a> Token<'a> {
+    pub fn ident(&self) -> Option<&'a str> {
+        match self.tok {
+            Tok::Ident(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn is_punct(&self, c: char) -> bool {
+        self.tok == Tok::Punct(c)
+    }
+}
+
+const PUNCT: &str = ",:[]()+-*/=";
+
+fn is_ident_start(c: char) -> bool {
+    c.is_ascii_alphabetic() || c == '_' || c == '.'
+}
+
+fn is_ident(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '.'
+}
+
+/// Parse a number literal: decimal, `0x`/`#`/`$` hexadecimal or `0b`/`%` binary.
+fn number(s: &str) -> Option<i64> {
+    let (digits, radix) = if let Some(d) = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        (d, 16)
+    } else if let Some(d) = s.strip_prefix("0b").or_else(|| s.strip_prefix("0B")) {
+        (d, 2)
+    } else if let Some(d) = s.strip_prefix('#').or_else(|| s.strip_prefix('$')) {
+        (d, 16)
+    } else if let Some(d) = s.strip_prefix('%') {
+        (d, 2)
+    } else {
+        (s, 10)
+    };
+    if digits.is_empty() {
+        return None;
+    }
+    i64::from_str_radix(&digits.replace('_', ""), radix).ok()
+}
+
+/// Split `line` into tokens, stopping at a `;` comment.  On error returns the column and the
+/// reason.
+pub fn lex(line: &str) -> Result<Vec<Token<'_>>, (usize, ErrorKind)> {
+    let mut tokens = Vec::new();
+    let mut chars = line.char_indices().peekable();
+    while let Some(&(start, c)) = chars.peek() {
+        let col = line[..start].chars().count() + 1;
+        let word_end = |chars: &mut core::iter::Peekable<core::str::CharIndices>| {
+            chars.next();
+            while let Some(&(_, c)) = chars.peek() {
+                if !is_ident(c) {
+                    break;
+                }
+                chars.next();
+            }
+            chars.peek().map(|&(i, _)| i).unwrap_or_else(|| line.len())
+        };
+        let tok = if c == ';' {
+            break;
+        } else if c.is_whitespace() {
+            chars.next();
+            continue;
+        } else if is_ident_start(c) {
+            let end = word_end(&mut chars);
+            Tok::Ident(&line[start..end])
+        } else if c.is_ascii_digit()
+            || ((c == '#' || c == '$' || c == '%')
+                && line[start + 1..].starts_with(|c: char| c.is_ascii_hexdigit()))
+        {
+            let end = word_end(&mut chars);
+            let text = &line[start..end];
+            match number(text) {
+                Some(n) => Tok::Num(n),
+                None => return Err((col, ErrorKind::InvalidNumber(text.to_string()))),
+            }
+        } else if c == '"' {
+            chars.next();
+            let mut bytes = Vec::new();
+            loop {
+                let c = match chars.next() {
+                    Some((_, '"')) => break,
+                    Some((_, '\\')) => match chars.next() {
+                        Some((_, 'n')) => '\n',
+                        Some((_, 't')) => '\t',
+                        Some((_, '0')) => '\0',
+                        Some((_, c)) => c,
+                        None => return Err((col, ErrorKind::UnterminatedString)),
+                    },
+                    Some((_, c)) => c,
+                    None => return Err((col, ErrorKind::UnterminatedString)),
+                };
+                let mut buf = [0; 4];
+                bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+            }
+            Tok::Str(bytes)
+        } else if PUNCT.contains(c) {
+            chars.next();
+            Tok::Punct(c)
+        } else {
+            return Err((col, ErrorKind::UnexpectedChar(c)));
+        };
+        tokens.push(Token { tok, col });
+    }
+    Ok(tokens)
+}
@@ -0,0 +1,777 @@
+//! CHIP-8 assembler using the mnemonics of Cowgod's Chip-8 Technical Reference.
+//!
+//! A source file has one statement per line, and `;` starts a comment:
+//!
+//! ```text
+//! SPEED = 2                 ; constant, also `SPEED EQU 2`
+//! start:  LD V0, 0          ; label
+//!         LD I, sprite
+//! loop:   DRW V0, V1, 5
+//!         ADD V0, SPEED * 4
+//!         JP loop
+//! sprite: db 0xF0, #90, $90, %10010000, 0b11110000
+//!         dw 0x1234, start + 2
+//!         db "text"
+//!         include "lib.s"   ; relative to the including file
+//! ```
+//!
+//! Numbers are decimal, hexadecimal (`0x`, `#`, `$`) or binary (`0b`, `%`), and operands can be
+//! expressions using `+ - * /` and parentheses over numbers, labels and constants.  Mnemonics,
+//! registers and directives are case insensitive; labels and constants are not.
+//!
+//! Besides Cowgod's mnemonics, including `JP V0, addr`, the SUPER-CHIP and XO-CHIP instructions
+//! are written `SCD n`, `SCU n`, `SCR`, `SCL`, `EXIT`, `LOW`, `HIGH`, `LD HF, Vx`, `LD R, Vx`,
+//! `LD Vx, R`, `SAVE Vx, Vy`, `LOAD Vx, Vy`, `LD I, LONG addr`, `PLANE n`, `AUDIO` and
+//! `PITCH Vx`.  `SHR Vx` and `SHL Vx` shift Vx into itself.  The program is
+//! assembled at `chip8::ROM_ADDR`, so the output can be passed to `Chip8::load_rom` as is.  It
+//! must fit in `chip8::MEM_SIZE`, which covers 64 KiB with the default `xo-chip` feature.
+
+mod expr;
+mod lex;
+pub mod octo;
+
+use chip8::{Instruction, MEM_SIZE, ROM_ADDR};
+use expr::Expr;
+use lex::{Tok, Token};
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Maximum nesting of `include` directives
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+/// Mnemonics of the instruction set
+const MNEMONICS: &[&str] = &[
+    "SYS", "CLS", "RET", "SCD", "SCU", "SCR", "SCL", "EXIT", "LOW", "HIGH", "JP", "CALL", "SE",
+    "SNE", "SAVE", "LOAD", "LD", "ADD", "OR", "AND", "XOR", "SUB", "SHR", "SUBN", "SHL", "RND",
+    "DRW", "SKP", "SKNP", "PLANE", "AUDIO", "PITCH",
+];
+
+#[derive(Debug)]
+pub enum ErrorKind {
+    Io(io::Error),
+    UnexpectedChar(char),
+    UnterminatedString,
+    InvalidNumber(String),
+    Expected(&'static str),
+    UnknownMnemonic(String),
+    InvalidOperands(String),
+    UndefinedSymbol(String),
+    DuplicateSymbol(String),
+    ReservedName(String),
+    OutOfRange(i64),
+    DivisionByZero,
+    RecursiveConstant(String),
+    IncludeTooDeep,
+    ProgramTooBig,
+    Unexpected(String),
+    Unclosed(&'static str),
+    MissingMain,
+    MacroTooDeep,
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ErrorKind::Io(err) => write!(f, "{}", err),
+            ErrorKind::UnexpectedChar(c) => write!(f, "unexpected character `{}`", c),
+            ErrorKind::UnterminatedString => write!(f, "unterminated string"),
+            ErrorKind::InvalidNumber(s) => write!(f, "invalid number `{}`", s),
+            ErrorKind::Expected(what) => write!(f, "expected {}", what),
+            ErrorKind::UnknownMnemonic(m) => write!(f, "unknown mnemonic `{}`", m),
+            ErrorKind::InvalidOperands(m) => write!(f, "invalid operands for `{}`", m),
+            ErrorKind::UndefinedSymbol(s) => write!(f, "undefined symbol `{}`", s),
+            ErrorKind::DuplicateSymbol(s) => write!(f, "symbol `{}` is already defined", s),
+            ErrorKind::ReservedName(s) => write!(f, "`{}` is a reserved name", s),
+            ErrorKind::OutOfRange(v) => write!(f, "value {} is out of range", v),
+            ErrorKind::DivisionByZero => write!(f, "division by zero"),
+            ErrorKind::RecursiveConstant(s) => {
+                write!(f, "constant `{}` is defined in terms of itself", s)
+            }
+            ErrorKind::IncludeTooDeep => write!(f, "includes are nested too deeply"),
+            ErrorKind::ProgramTooBig => write!(f, "program doesn't fit in memory"),
+            ErrorKind::Unexpected(s) => write!(f, "unexpected `{}`", s),
+            ErrorKind::Unclosed(s) => write!(f, "`{}` is never closed", s),
+            ErrorKind::MissingMain => write!(f, "program has no `main` label"),
+            ErrorKind::MacroTooDeep => write!(f, "macros are expanded too many times"),
+        }
+    }
+}
+
+/// Assembly error with its location in the source.  Lines and columns start at 1; they are 0
+/// when the error is not related to a position, such as failing to read the main file.
+#[derive(Debug)]
+pub struct Error {
+    pub file: String,
+    pub line: usize,
+    pub col: usize,
+    pub kind: ErrorKind,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.line == 0 {
+            write!(f, "{}: {}", self.file, self.kind)
+        } else {
+            write!(f, "{}:{}:{}: {}", self.file, self.line, self.col, self.kind)
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Assemble `source` into a ROM.  Includes are resolved relative to the current directory.
+pub fn assemble(source: &str) -> Result<Vec<u8>, Error> {
+    let mut asm = Assembler::new();
+    asm.source("<input>", Path::new(""), source, 0)?;
+    asm.finish()
+}
+
+/// Assemble the source file at `path` into a ROM.
+pub fn assemble_file<P: AsRef<Path>>(path: P) -> Result<Vec<u8>, Error> {
+    let path = path.as_ref();
+    let name = path.display().to_string();
+    let source = fs::read_to_string(path).map_err(|err| Error {
+        file: name.clone(),
+        line: 0,
+        col: 0,
+        kind: ErrorKind::Io(err),
+    })?;
+    let mut asm = Assembler::new();
+    asm.source(
+        &name,
+        path.parent().unwrap_or_else(|| Path::new("")),
+        &source,
+        0,
+    )?;
+    asm.finish()
+}
+
+/// Location of a statement
+#[derive(Debug, Clone, Copy)]
+struct Loc {
+    file: usize, // Index in Assembler::files
+    line: usize,
+}
+
+/// Operand keywords other than the V registers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Keyword {
+    I,
+    Dt,
+    St,
+    K,
+    F,
+    Hf,
+    B,
+    R,
+}
+
+#[derive(Debug)]
+enum Operand {
+    Reg(u8),
+    Keyword(Keyword),
+    IndirectI,          // [I]
+    Long(Expr, usize),  // LONG expression, for the XO-CHIP 16 bit load of I
+    Value(Expr, usize), // Expression and its column
+}
+
+#[derive(Debug)]
+enum Data {
+    Bytes(Vec<u8>),
+    Value(Expr, usize),
+}
+
+#[derive(Debug)]
+enum Kind {
+    Inst(String, usize, Vec<Operand>), // Mnemonic, its column and the operands
+    Db(Vec<Data>),
+    Dw(Vec<(Expr, usize)>),
+}
+
+#[derive(Debug)]
+struct Stmt {
+    loc: Loc,
+    kind: Kind,
+}
+
+#[derive(Debug)]
+enum Symbol {
+    Label(usize),
+    Const(Expr),
+}
+
+struct Assembler {
+    files: Vec<String>,                      // Names of the source files
+    stmts: Vec<Stmt>,                        // Statements that produce bytes, in order
+    symbols: HashMap<String, (Symbol, Loc)>, // Labels and constants with their definition
+    consts: Vec<String>,                     // Constants in definition order
+    addr: usize,                             // Address of the next statement
+}
+
+/// Parse a register name `V0`..`VF`
+fn register(name: &str) -> Option<u8> {
+    let mut chars = name.chars();
+    match (chars.next(), chars.next(), chars.next()) {
+        (Some('V'), Some(d), None) | (Some('v'), Some(d), None) => d.to_digit(16).map(|d| d as u8),
+        _ => None,
+    }
+}
+
+fn keyword(name: &str) -> Option<Keyword> {
+    Some(match name.to_ascii_uppercase().as_str() {
+        "I" => Keyword::I,
+        "DT" => Keyword::Dt,
+        "ST" => Keyword::St,
+        "K" => Keyword::K,
+        "F" => Keyword::F,
+        "HF" => Keyword::Hf,
+        "B" => Keyword::B,
+        "R" => Keyword::R,
+        _ => return None,
+    })
+}
+
+fn reserved(name: &str) -> bool {
+    register(name).is_some()
+        || keyword(name).is_some()
+        || name.eq_ignore_ascii_case("LONG")
+        || name.eq_ignore_ascii_case("EQU")
+}
+
+/// Split the operands of a statement on commas
+fn split_operands<'t, 'a>(
+    tokens: &'t [Token<'a>],
+    end: usize,
+) -> Result<Vec<&'t [Token<'a>]>, (usize, ErrorKind)> {
+    if tokens.is_empty() {
+        return Ok(Vec::new());
+    }
+    let mut operands = Vec::new();
+    let mut start = 0;
+    for (i, t) in tokens.iter().enumerate() {
+        if t.is_punct(',') {
+            if i == start {
+                return Err((t.col, ErrorKind::Expected("operand")));
+            }
+            operands.push(&tokens[start..i]);
+            start = i + 1;
+        }
+    }
+    if start == tokens.len() {
+        return Err((end, ErrorKind::Expected("operand")));
+    }
+    operands.push(&tokens[start..]);
+    Ok(operands)
+}
+
+fn operand(tokens: &[Token], end: usize) -> Result<Operand, (usize, ErrorKind)> {
+    let first = tokens[0].ident();
+    if tokens.len() == 1 {
+        if let Some(name) = first {
+            if let Some(x) = register(name) {
+                return Ok(Operand::Reg(x));
+            }
+            if let Some(k) = keyword(name) {
+                return Ok(Operand::Keyword(k));
+            }
+        }
+    }
+    if tokens.len() == 3
+        && tokens[0].is_punct('[')
+        && tokens[1].ident().map(keyword) == Some(Some(Keyword::I))
+        && tokens[2].is_punct(']')
+    {
+        return Ok(Operand::IndirectI);
+    }
+    match first {
+        Some(name) if name.eq_ignore_ascii_case("LONG") => {
+            let col = tokens.get(1).map(|t| t.col).unwrap_or(end);
+            Ok(Operand::Long(expr::parse(&tokens[1..], end)?, col))
+        }
+        _ => Ok(Operand::Value(expr::parse(tokens, end)?, tokens[0].col)),
+    }
+}
+
+/// Check that `v`, the value of the operand at column `col`, is in `min..=max`
+fn range(v: i64, col: usize, min: i64, max: i64) -> Result<i64, (usize, ErrorKind)> {
+    if v < min || v > max {
+        return Err((col, ErrorKind::OutOfRange(v)));
+    }
+    Ok(v)
+}
+
+/// Select the instruction for `mnemonic` (in upper case) and its operands, evaluating
+/// expressions with `eval`.
+fn select<F>(
+    mnemonic: &str,
+    col: usize,
+    operands: &[Operand],
+    eval: &mut F,
+) -> Result<Instruction, (usize, ErrorKind)>
+where
+    F: FnMut(&Expr) -> Result<i64, (usize, ErrorKind)>,
+{
+    use Instruction::*;
+    use Keyword as Kw;
+    use Operand::{IndirectI, Keyword as K, Long, Reg, Value};
+
+    let mut value = |e: &Expr, col: usize, min: i64, max: i64| range(eval(e)?, col, min, max);
+
+    Ok(match (mnemonic, operands) {
+        ("SYS", [Value(e, c)]) => Sys(value(e, *c, 0, 0xfff)? as u16),
+        ("CLS", []) => Cls,
+        ("RET", []) => Ret,
+        ("SCD", [Value(e, c)]) => Scd(value(e, *c, 0, 0xf)? as u8),
+        ("SCU", [Value(e, c)]) => Scu(value(e, *c, 0, 0xf)? as u8),
+        ("SCR", []) => Scr,
+        ("SCL", []) => Scl,
+        ("EXIT", []) => Exit,
+        ("LOW", []) => Low,
+        ("HIGH", []) => High,
+        ("JP", [Value(e, c)]) => Jp(value(e, *c, 0, 0xfff)? as u16),
+        ("JP", [Reg(0), Value(e, c)]) => JpV0(value(e, *c, 0, 0xfff)? as u16),
+        ("CALL", [Value(e, c)]) => Call(value(e, *c, 0, 0xfff)? as u16),
+        ("SE", [Reg(x), Value(e, c)]) => SeByte(*x, value(e, *c, -0x80, 0xff)? as u8),
+        ("SE", [Reg(x), Reg(y)]) => SeReg(*x, *y),
+        ("SNE", [Reg(x), Value(e, c)]) => SneByte(*x, value(e, *c, -0x80, 0xff)? as u8),
+        ("SNE", [Reg(x), Reg(y)]) => SneReg(*x, *y),
+        ("SAVE", [Reg(x), Reg(y)]) => Save(*x, *y),
+        ("LOAD", [Reg(x), Reg(y)]) => Load(*x, *y),
+        ("LD", [Reg(x), Reg(y)]) => LdReg(*x, *y),
+        ("LD", [Reg(x), Value(e, c)]) => LdByte(*x, value(e, *c, -0x80, 0xff)? as u8),
+        ("LD", [K(Kw::I), Value(e, c)]) => LdI(value(e, *c, 0, 0xfff)? as u16),
+        ("LD", [K(Kw::I), Long(e, c)]) => LdILong(value(e, *c, 0, 0xffff)? as u16),
+        ("LD", [Reg(x), K(Kw::Dt)]) => LdVxDt(*x),
+        ("LD", [Reg(x), K(Kw::K)]) => LdVxK(*x),
+        ("LD", [K(Kw::Dt), Reg(x)]) => LdDtVx(*x),
+        ("LD", [K(Kw::St), Reg(x)]) => LdStVx(*x),
+        ("LD", [K(Kw::F), Reg(x)]) => LdF(*x),
+        ("LD", [K(Kw::Hf), Reg(x)]) => LdHf(*x),
+        ("LD", [K(Kw::B), Reg(x)]) => LdB(*x),
+        ("LD", [IndirectI, Reg(x)]) => LdIVx(*x),
+        ("LD", [Reg(x), IndirectI]) => LdVxI(*x),
+        ("LD", [K(Kw::R), Reg(x)]) => LdRVx(*x),
+        ("LD", [Reg(x), K(Kw::R)]) => LdVxR(*x),
+        ("ADD", [Reg(x), Value(e, c)]) => AddByte(*x, value(e, *c, -0x80, 0xff)? as u8),
+        ("ADD", [Reg(x), Reg(y)]) => AddReg(*x, *y),
+        ("ADD", [K(Kw::I), Reg(x)]) => AddI(*x),
+        ("OR", [Reg(x), Reg(y)]) => Or(*x, *y),
+        ("AND", [Reg(x), Reg(y)]) => And(*x, *y),
+        ("XOR", [Reg(x), Reg(y)]) => Xor(*x, *y),
+        ("SUB", [Reg(x), Reg(y)]) => Sub(*x, *y),
+        ("SUBN", [Reg(x), Reg(y)]) => Subn(*x, *y),
+        // Without Vy, shift Vx into itself so that the result doesn't depend on the quirks
+        ("SHR", [Reg(x)]) => Shr(*x, *x),
+        ("SHR", [Reg(x), Reg(y)]) => Shr(*x, *y),
+        ("SHL", [Reg(x)]) => Shl(*x, *x),
+        ("SHL", [Reg(x), Reg(y)]) => Shl(*x, *y),
+        ("RND", [Reg(x), Value(e, c)]) => Rnd(*x, value(e, *c, -0x80, 0xff)? as u8),
+        ("DRW", [Reg(x), Reg(y), Value(e, c)]) => Drw(*x, *y, value(e, *c, 0, 0xf)? as u8),
+        ("SKP", [Reg(x)]) => Skp(*x),
+        ("SKNP", [Reg(x)]) => Sknp(*x),
+        ("PLANE", [Value(e, c)]) => Plane(value(e, *c, 0, 0xf)? as u8),
+        ("AUDIO", []) => Audio,
+        ("PITCH", [Reg(x)]) => Pitch(*x),
+        _ if MNEMONICS.contains(&mnemonic) => {
+            return Err((col, ErrorKind::InvalidOperands(mnemonic.to_string())))
+        }
+        _ => return Err((col, ErrorKind::UnknownMnemonic(mnemonic.to_string()))),
+    })
+}
+
+impl Assembler {
+    fn new() -> Self {
+        Self {
+            files: Vec::new(),
+            stmts: Vec::new(),
+            symbols: HashMap::new(),
+            consts: Vec::new(),
+            addr: ROM_ADDR,
+        }
+    }
+
+    fn error(&self, loc: Loc, (col, kind): (usize, ErrorKind)) -> Error {
+        Error {
+            file: self.files[loc.file].clone(),
+            line: loc.line,
+            col,
+            kind,
+        }
+    }
+
+    /// First pass over a source file: parse the statements and assign addresses to the labels.
+    /// `dir` is the directory includes are relative to.
+    fn source(&mut self, name: &str, dir: &Path, source: &str, depth: usize) -> Result<(), Error> {
+        let file = self.files.len();
+        self.files.push(name.to_string());
+        for (i, line) in source.lines().enumerate() {
+            let loc = Loc { file, line: i + 1 };
+            let end = line.chars().count() + 1;
+            let tokens = lex::lex(line).map_err(|e| self.error(loc, e))?;
+            match self.statement(loc, &tokens, end) {
+                Ok(Some((path, col))) => {
+                    if depth + 1 >= MAX_INCLUDE_DEPTH {
+                        return Err(self.error(loc, (col, ErrorKind::IncludeTooDeep)));
+                    }
+                    let path = dir.join(path);
+                    let source = fs::read_to_string(&path)
+                        .map_err(|err| self.error(loc, (col, ErrorKind::Io(err))))?;
+                    let name = path.display().to_string();
+                    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+                    self.source(&name, dir, &source, depth + 1)?;
+                }
+                Ok(None) => {}
+                Err(e) => return Err(self.error(loc, e)),
+            }
+        }
+        Ok(())
+    }
+
+    fn define(
+        &mut self,
+        name: &str,
+        col: usize,
+        sym: Symbol,
+        loc: Loc,
+    ) -> Result<(), (usize, ErrorKind)> {
+        if reserved(name) {
+            return Err((col, ErrorKind::ReservedName(name.to_string())));
+        }
+        if self.symbols.contains_key(name) {
+            return Err((col, ErrorKind::DuplicateSymbol(name.to_string())));
+        }
+        if let Symbol::Const(_) = sym {
+            self.consts.push(name.to_string());
+        }
+        self.symbols.insert(name.to_string(), (sym, loc));
+        Ok(())
+    }
+
+    /// Parse a statement.  Returns the path and column of an include directive, which must be
+    /// processed by the caller.
+    fn statement(
+        &mut self,
+        loc: Loc,
+        tokens: &[Token],
+        end: usize,
+    ) -> Result<Option<(String, usize)>, (usize, ErrorKind)> {
+        let mut tokens = tokens;
+        if let (Some(name), Some(true)) = (
+            tokens.first().and_then(|t| t.ident()),
+            tokens.get(1).map(|t| t.is_punct(':')),
+        ) {
+            self.define(name, tokens[0].col, Symbol::Label(self.addr), loc)?;
+            tokens = &tokens[2..];
+        }
+        let first = match tokens.first() {
+            Some(t) => t,
+            None => return Ok(None),
+        };
+        let name = first
+            .ident()
+            .ok_or((first.col, ErrorKind::Expected("mnemonic or directive")))?;
+        let is_const = match tokens.get(1) {
+            Some(t) if t.is_punct('=') => true,
+            Some(t) => matches!(t.ident(), Some(s) if s.eq_ignore_ascii_case("EQU")),
+            None => false,
+        };
+        if is_const {
+            let expr = expr::parse(&tokens[2..], end)?;
+            self.define(name, first.col, Symbol::Const(expr), loc)?;
+            return Ok(None);
+        }
+
+        let operands = split_operands(&tokens[1..], end)?;
+        let (kind, size) = match name.to_ascii_uppercase().as_str() {
+            "INCLUDE" => {
+                return match operands.as_slice() {
+                    [[Token {
+                        tok: Tok::Str(path),
+                        col,
+                    }]] => Ok(Some((String::from_utf8_lossy(path).into_owned(), *col))),
+                    _ => Err((first.col, ErrorKind::Expected("file name string"))),
+                };
+            }
+            "DB" => {
+                let mut data = Vec::new();
+                let mut size = 0;
+                for op in operands {
+                    match op {
+                        [Token {
+                            tok: Tok::Str(bytes),
+                            ..
+                        }] => {
+                            size += bytes.len();
+                            data.push(Data::Bytes(bytes.clone()));
+                        }
+                        _ => {
+                            size += 1;
+                            data.push(Data::Value(expr::parse(op, end)?, op[0].col));
+                        }
+                    }
+                }
+                (Kind::Db(data), size)
+            }
+            "DW" => {
+                let mut data = Vec::new();
+                for op in operands {
+                    data.push((expr::parse(op, end)?, op[0].col));
+                }
+                let size = 2 * data.len();
+                (Kind::Dw(data), size)
+            }
+            mnemonic => {
+                let operands = operands
+                    .into_iter()
+                    .map(|op| operand(op, end))
+                    .collect::<Result<Vec<_>, _>>()?;
+                // Symbols may not be defined yet: validate the operands with dummy values
+                let inst = select(mnemonic, first.col, &operands, &mut |_| Ok(0))?;
+                let kind = Kind::Inst(mnemonic.to_string(), first.col, operands);
+                (kind, inst.size())
+            }
+        };
+        if self.addr + size > MEM_SIZE {
+            return Err((first.col, ErrorKind::ProgramTooBig));
+        }
+        self.addr += size;
+        self.stmts.push(Stmt { loc, kind });
+        Ok(None)
+    }
+
+    /// Value of the symbol `name` referenced at column `col`
+    fn value(&self, name: &str, col: usize, depth: usize) -> Result<i64, (usize, ErrorKind)> {
+        match self.symbols.get(name) {
+            None => Err((col, ErrorKind::UndefinedSymbol(name.to_string()))),
+            Some((Symbol::Label(addr), _)) => Ok(*addr as i64),
+            Some((Symbol::Const(expr), _)) => {
+                if depth > self.consts.len() {
+                    return Err((col, ErrorKind::RecursiveConstant(name.to_string())));
+                }
+                // Errors inside the constant are reported at the reference
+                expr.eval(&mut |name, _| self.value(name, col, depth + 1))
+            }
+        }
+    }
+
+    /// Second pass: evaluate the constants and encode the statements.
+    fn finish(self) -> Result<Vec<u8>, Error> {
+        for name in self.consts.iter() {
+            if let Some((Symbol::Const(expr), loc)) = self.symbols.get(name) {
+                expr.eval(&mut |name, col| self.value(name, col, 0))
+                    .map_err(|e| self.error(*loc, e))?;
+            }
+        }
+
+        let mut rom = Vec::with_capacity(self.addr - ROM_ADDR);
+        for stmt in self.stmts.iter() {
+            let eval = |e: &Expr| e.eval(&mut |name, col| self.value(name, col, 0));
+            let res = match &stmt.kind {
+                Kind::Inst(mnemonic, col, operands) => {
+                    select(mnemonic, *col, operands, &mut { eval }).map(|inst| {
+                        let mut buf = [0; 4];
+                        inst.encode_bytes(&mut buf);
+                        rom.extend_from_slice(&buf[..inst.size()]);
+                    })
+                }
+                Kind::Db(data) => data.iter().try_for_each(|d| {
+                    match d {
+                        Data::Bytes(bytes) => rom.extend_from_slice(bytes),
+                        Data::Value(e, col) => rom.push(range(eval(e)?, *col, -0x80, 0xff)? as u8),
+                    }
+                    Ok(())
+                }),
+                Kind::Dw(data) => data.iter().try_for_each(|(e, col)| {
+                    let v = range(eval(e)?, *col, -0x8000, 0xffff)? as u16;
+                    rom.extend_from_slice(&v.to_be_bytes());
+                    Ok(())
+                }),
+            };
+            res.map_err(|e| self.error(stmt.loc, e))?;
+        }
+        Ok(rom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chip8::Instruction::*;
+
+    /// Assemble `source` and decode the single instruction it produces
+    fn decode(source: &str) -> Instruction {
+        let rom = assemble(source).unwrap_or_else(|e| panic!("{}: {}", source, e));
+        let inst = Instruction::decode_bytes(&rom)
+            .unwrap_or_else(|| panic!("{}: invalid opcode {:02x?}", source, rom));
+        assert_eq!(rom.len(), inst.size(), "{}", source);
+        inst
+    }
+
+    fn kind(source: &str) -> ErrorKind {
+        assemble(source).unwrap_err().kind
+    }
+
+    #[test]
+    fn mnemonics() {
+        let cases = [
+            ("SYS 0x123", Sys(0x123)),
+            ("CLS", Cls),
+            ("RET", Ret),
+            ("SCD 4", Scd(4)),
+            ("SCU 3", Scu(3)),
+            ("SCR", Scr),
+            ("SCL", Scl),
+            ("EXIT", Exit),
+            ("LOW", Low),
+            ("HIGH", High),
+            ("JP 0x345", Jp(0x345)),
+            ("JP V0, 0x300", JpV0(0x300)),
+            ("CALL 0x456", Call(0x456)),
+            ("SE V1, 0x12", SeByte(1, 0x12)),
+            ("SE V1, V2", SeReg(1, 2)),
+            ("SNE V3, -1", SneByte(3, 0xff)),
+            ("SNE V3, V4", SneReg(3, 4)),
+            ("SAVE V2, V5", Save(2, 5)),
+            ("LOAD V5, V2", Load(5, 2)),
+            ("LD V5, VA", LdReg(5, 0xa)),
+            ("LD V6, 200", LdByte(6, 200)),
+            ("LD I, 0x678", LdI(0x678)),
+            ("LD I, LONG 0xabcd", LdILong(0xabcd)),
+            ("LD V7, DT", LdVxDt(7)),
+            ("LD V8, K", LdVxK(8)),
+            ("LD DT, V9", LdDtVx(9)),
+            ("LD ST, VA", LdStVx(0xa)),
+            ("LD F, VB", LdF(0xb)),
+            ("LD HF, VC", LdHf(0xc)),
+            ("LD B, VD", LdB(0xd)),
+            ("LD [I], VE", LdIVx(0xe)),
+            ("LD VF, [I]", LdVxI(0xf)),
+            ("LD R, V7", LdRVx(7)),
+            ("LD V7, R", LdVxR(7)),
+            ("ADD V1, 3", AddByte(1, 3)),
+            ("ADD V1, V2", AddReg(1, 2)),
+            ("ADD I, V3", AddI(3)),
+            ("OR V1, V2", Or(1, 2)),
+            ("AND V1, V2", And(1, 2)),
+            ("XOR V1, V2", Xor(1, 2)),
+            ("SUB V1, V2", Sub(1, 2)),
+            ("SUBN V1, V2", Subn(1, 2)),
+            ("SHR V4", Shr(4, 4)),
+            ("SHR V4, V5", Shr(4, 5)),
+            ("SHL V4", Shl(4, 4)),
+            ("SHL V4, V5", Shl(4, 5)),
+            ("RND V2, 0x0f", Rnd(2, 0x0f)),
+            ("DRW V1, V2, 5", Drw(1, 2, 5)),
+            ("SKP V3", Skp(3)),
+            ("SKNP V3", Sknp(3)),
+            ("PLANE 3", Plane(3)),
+            ("AUDIO", Audio),
+            ("PITCH V2", Pitch(2)),
+        ];
+        for (source, inst) in cases.iter() {
+            assert_eq!(decode(source), *inst, "{}", source);
+            // Mnemonics and registers are case insensitive
+            assert_eq!(decode(&source.to_lowercase()), *inst, "{}", source);
+        }
+    }
+
+    #[test]
+    fn invalid_operands() {
+        assert!(matches!(kind("JP V1, 0x300"), ErrorKind::InvalidOperands(m) if m == "JP"));
+        assert!(matches!(kind("LD DT, 3"), ErrorKind::InvalidOperands(m) if m == "LD"));
+        assert!(matches!(kind("MOV V0, V1"), ErrorKind::UnknownMnemonic(m) if m == "MOV"));
+        assert!(matches!(kind("LD V0, 256"), ErrorKind::OutOfRange(256)));
+        assert!(matches!(kind("LD V0, -129"), ErrorKind::OutOfRange(-129)));
+        assert!(matches!(kind("JP 0x1000"), ErrorKind::OutOfRange(0x1000)));
+        assert!(matches!(kind("DRW V0, V1, 16"), ErrorKind::OutOfRange(16)));
+    }
+
+    #[test]
+    fn labels() {
+        let source = "
+            start:  JP end          ; forward reference
+            loop:   CALL loop
+                    JP V0, start + 2
+            end:    LD I, loop
+        ";
+        let rom = assemble(source).unwrap();
+        assert_eq!(rom, [0x12, 0x06, 0x22, 0x02, 0xb2, 0x02, 0xa2, 0x02]);
+        assert!(matches!(kind("a: CLS\na: CLS"), ErrorKind::DuplicateSymbol(s) if s == "a"));
+        assert!(matches!(kind("JP nowhere"), ErrorKind::UndefinedSymbol(s) if s == "nowhere"));
+        assert!(matches!(kind("V0: CLS"), ErrorKind::ReservedName(_)));
+    }
+
+    #[test]
+    fn constants() {
+        let source = "
+            SPEED = 2
+            DOUBLE EQU SPEED * 2
+            LD V0, (DOUBLE + 1) * SPEED - 1
+            ADD V1, DOUBLE / SPEED
+        ";
+        assert_eq!(assemble(source).unwrap(), [0x60, 0x09, 0x71, 0x02]);
+        assert!(matches!(
+            kind("X = Y\nY = X\nLD V0, X"),
+            ErrorKind::RecursiveConstant(_)
+        ));
+        assert!(matches!(kind("LD V0, 1 / 0"), ErrorKind::DivisionByZero));
+    }
+
+    #[test]
+    fn data() {
+        let source = "
+            db 0xF0, #90, $90, %10010000, 0b11110000
+            db \"ab\", -1
+            here: dw 0x1234, here
+        ";
+        let rom = assemble(source).unwrap();
+        assert_eq!(
+            rom,
+            [0xf0, 0x90, 0x90, 0x90, 0xf0, b'a', b'b', 0xff, 0x12, 0x34, 0x02, 0x08]
+        );
+        assert!(matches!(kind("db 256"), ErrorKind::OutOfRange(256)));
+    }
+
+    #[test]
+    fn includes() {
+        let dir = std::env::temp_dir().join(format!("asm-includes-{}", std::process::id()));
+        fs::create_dir_all(dir.join("lib")).unwrap();
+        fs::write(dir.join("main.s"), "CALL sub\ninclude \"lib/sub.s\"\n").unwrap();
+        fs::write(dir.join("lib/sub.s"), "sub: RET\ninclude \"data.s\"\n").unwrap();
+        fs::write(dir.join("lib/data.s"), "db 1\n  LD V0, missing\n").unwrap();
+        let err = assemble_file(dir.join("main.s")).unwrap_err();
+        // Includes are relative to the including file, and errors point into them
+        assert!(matches!(&err.kind, ErrorKind::UndefinedSymbol(s) if s == "missing"));
+        assert!(err.file.ends_with("data.s"), "{}", err.file);
+        assert_eq!((err.line, err.col), (2, 10));
+        fs::write(dir.join("lib/data.s"), "db 1\n").unwrap();
+        let rom = assemble_file(dir.join("main.s")).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(rom, [0x22, 0x02, 0x00, 0xee, 0x01]);
+    }
+
+    #[test]
+    fn error_locations() {
+        let err = assemble("CLS\n\n  LD V0, 1 + nowhere").unwrap_err();
+        assert_eq!((err.line, err.col), (3, 14));
+        assert_eq!(err.to_string(), "<input>:3:14: undefined symbol `nowhere`");
+        let err = assemble("CLS\n   FOO V0").unwrap_err();
+        assert_eq!((err.line, err.col), (2, 4));
+        let err = assemble("LD V0, @").unwrap_err();
+        assert!(matches!(err.kind, ErrorKind::UnexpectedChar('@')));
+        assert_eq!((err.line, err.col), (1, 8));
+    }
+
+    #[test]
+    fn program_too_big() {
+        let fill = "dw 0\n".repeat((MEM_SIZE - ROM_ADDR) / 2);
+        assert_eq!(assemble(&fill).unwrap().len(), MEM_SIZE - ROM_ADDR);
+        let err = assemble(&format!("{}db 0", fill)).unwrap_err();
+        assert!(matches!(err.kind, ErrorKind::ProgramTooBig));
+        assert_eq!(err.line, (MEM_SIZE - ROM_ADDR) / 2 + 1);
+    }
+}
@@ -0,0 +1,61 @@
+use clap::{App, Arg};
+
+use std::fs;
+use std::path::Path;
+use std::process;
+
+pub fn main() {
+    let app = App::new("Chip8-rs assembler")
+        .version("0.0.1")
+        .author("Dhole")
+        .arg(
+            Arg::with_name("output")
+                .short("o")
+                .long("output")
+                .value_name("FILE")
+                .help("Sets the rom file [default: source file with the .ch8 extension]")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("syntax")
+                .short("s")
+                .long("syntax")
+                .value_name("SYNTAX")
+                .help("Sets the source syntax [default: octo for .8o files, cowgod otherwise]")
+                .takes_value(true)
+                .possible_values(&["cowgod", "octo"]),
+        )
+        .arg(
+            Arg::with_name("path")
+                .help("Path to the source file")
+                .index(1)
+                .required(true),
+        )
+        .get_matches();
+
+    let path = Path::new(app.value_of("path").unwrap());
+    let output = match app.value_of("output") {
+        Some(output) => Path::new(output).to_path_buf(),
+        None => path.with_extension("ch8"),
+    };
+    let octo = match app.value_of("syntax") {
+        Some(syntax) => syntax == "octo",
+        None => path.extension() == Some("8o".as_ref()),
+    };
+    let rom = if octo {
+        asm::octo::compile_file(path)
+    } else {
+        asm::assemble_file(path)
+    };
+    let rom = match rom {
+        Ok(rom) => rom,
+        Err(err) => {
+            eprintln!("error: {}", err);
+            process::exit(1);
+        }
+    };
+    if let Err(err) = fs::write(&output, &rom) {
+        eprintln!("error: {}: {}", output.display(), err);
+        process::exit(1);
+    }
+}
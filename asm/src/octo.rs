@@ -0,0 +1,1074 @@
+//! Compiler for the Octo assembly language.
+//!
+//! Supports labels (`: name`), register assignments and arithmetic (`v0 := 5`, `v1 += v2`,
+//! `i := label`), conditionals (`if v0 == 3 then ...`, `if v0 key begin ... else ... end`,
+//! including the `< > <= >=` comparisons through VF), `loop`/`while`/`again`, `:alias`, `:const`,
+//! `:calc`, `:macro`, `:org`, `:byte`, `:call`, sprite data as bare numbers, and the SUPER-CHIP
+//! and XO-CHIP statements (`hires`, `scroll-*`, `saveflags`, `plane`, `audio`, `pitch`,
+//! `i := long`, `save vx - vy`).  `:calc` expressions are evaluated right to left without
+//! operator precedence, as in Octo.
+//!
+//! As in Octo, the program starts with a jump to the `main` label, which is omitted when `main`
+//! is the first thing in the program.
+
+use super::{Error, ErrorKind};
+use chip8::{Instruction, MEM_SIZE, ROM_ADDR};
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+/// Maximum number of macro expansions, to stop recursive macros
+const MAX_MACRO_EXPANSIONS: usize = 0x10000;
+/// Index of VF, used as a temporary by the comparisons
+const VF: u8 = 0xf;
+
+/// Compile Octo `source` into a ROM.
+pub fn compile(source: &str) -> Result<Vec<u8>, Error> {
+    Compiler::new("<input>", source).compile()
+}
+
+/// Compile the Octo source file at `path` into a ROM.
+pub fn compile_file<P: AsRef<Path>>(path: P) -> Result<Vec<u8>, Error> {
+    let name = path.as_ref().display().to_string();
+    let source = fs::read_to_string(path).map_err(|err| Error {
+        file: name.clone(),
+        line: 0,
+        col: 0,
+        kind: ErrorKind::Io(err),
+    })?;
+    Compiler::new(&name, &source).compile()
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Token<'a> {
+    text: &'a str,
+    line: usize,
+    col: usize,
+}
+
+/// Split `source` into whitespace separated tokens, skipping `#` comments.  Strings in double
+/// quotes are a single token.
+fn tokenize(source: &str) -> Vec<Token<'_>> {
+    let mut tokens = Vec::new();
+    for (i, line) in source.lines().enumerate() {
+        let mut chars = line.char_indices().enumerate().peekable();
+        while let Some((col, (start, c))) = chars.next() {
+            if c.is_whitespace() {
+                continue;
+            }
+            if c == '#' {
+                break;
+            }
+            let mut end = line.len();
+            while let Some(&(_, (pos, next))) = chars.peek() {
+                if c == '"' {
+                    chars.next();
+                    if next == '"' {
+                        end = pos + 1;
+                        break;
+                    }
+                } else if next.is_whitespace() {
+                    end = pos;
+                    break;
+                } else {
+                    chars.next();
+                }
+            }
+            tokens.push(Token {
+                text: &line[start..end],
+                line: i + 1,
+                col: col + 1,
+            });
+        }
+    }
+    tokens
+}
+
+/// Parse a number: decimal, `0x` hexadecimal or `0b` binary, optionally negative
+fn number(text: &str) -> Option<f64> {
+    let (neg, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+    let v = if let Some(hex) = digits.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16).ok()? as f64
+    } else if let Some(bin) = digits.strip_prefix("0b") {
+        i64::from_str_radix(bin, 2).ok()? as f64
+    } else if digits.starts_with(|c: char| c.is_ascii_digit()) {
+        digits.parse::<f64>().ok()?
+    } else {
+        return None;
+    };
+    Some(if neg { -v } else { v })
+}
+
+/// Parse a register name `v0`..`vf`
+fn register(text: &str) -> Option<u8> {
+    let mut chars = text.chars();
+    match (chars.next(), chars.next(), chars.next()) {
+        (Some('v'), Some(d), None) | (Some('V'), Some(d), None) => d.to_digit(16).map(|d| d as u8),
+        _ => None,
+    }
+}
+
+fn binary_op(op: &str, a: f64, b: f64) -> Option<f64> {
+    let (ia, ib) = (a as i64, b as i64);
+    let bool = |b: bool| if b { 1.0 } else { 0.0 };
+    Some(match op {
+        "+" => a + b,
+        "-" => a - b,
+        "*" => a * b,
+        "/" => a / b,
+        "%" => a % b,
+        "&" => (ia & ib) as f64,
+        "|" => (ia | ib) as f64,
+        "^" => (ia ^ ib) as f64,
+        "<<" => ia.wrapping_shl(ib as u32) as f64,
+        ">>" => ia.wrapping_shr(ib as u32) as f64,
+        "pow" => a.powf(b),
+        "min" => a.min(b),
+        "max" => a.max(b),
+        "<" => bool(a < b),
+        "<=" => bool(a <= b),
+        "==" => bool(a == b),
+        "!=" => bool(a != b),
+        ">=" => bool(a >= b),
+        ">" => bool(a > b),
+        _ => return None,
+    })
+}
+
+fn unary_op(op: &str, a: f64) -> Option<f64> {
+    Some(match op {
+        "-" => -a,
+        "~" => !(a as i64) as f64,
+        "!" => (a == 0.0) as i64 as f64,
+        "sin" => a.sin(),
+        "cos" => a.cos(),
+        "tan" => a.tan(),
+        "exp" => a.exp(),
+        "log" => a.ln(),
+        "abs" => a.abs(),
+        "sqrt" => a.sqrt(),
+        "sign" => a.signum(),
+        "ceil" => a.ceil(),
+        "floor" => a.floor(),
+        _ => return None,
+    })
+}
+
+/// Operand that is either a register or an 8 bit value
+#[derive(Clone, Copy)]
+enum Rhs {
+    Reg(u8),
+    Byte(u8),
+}
+
+/// Reference to a label that is patched when the label is defined
+#[derive(Clone, Copy)]
+enum Fixup {
+    Addr, // 12 bit address in the low bits of the opcode
+    Long, // 16 bit address
+}
+
+struct Macro<'a> {
+    args: Vec<&'a str>,
+    body: Vec<Token<'a>>,
+}
+
+enum Block {
+    Branch(usize),                             // Address of the jump to patch
+    Loop { start: usize, breaks: Vec<usize> }, // Start address and `while` jumps to patch
+}
+
+struct Compiler<'a> {
+    file: String,
+    tokens: Vec<Token<'a>>,
+    pos: usize,
+    rom: Vec<u8>,                           // Bytes from ROM_ADDR
+    here: usize,                            // Address of the next byte
+    main_jump: bool,                        // Whether ROM_ADDR holds a jump to main
+    labels: HashMap<&'a str, usize>,        // Label addresses
+    consts: HashMap<&'a str, f64>,          // Values of :const and :calc
+    aliases: HashMap<&'a str, u8>,          // Register aliases
+    macros: HashMap<&'a str, Macro<'a>>,    // Macro definitions
+    fixups: Vec<(usize, Fixup, Token<'a>)>, // Forward references to labels
+    blocks: Vec<(Block, Token<'a>)>,        // Open blocks and the tokens that opened them
+    expansions: usize,                      // Number of macro expansions
+}
+
+impl<'a> Compiler<'a> {
+    fn new(file: &str, source: &'a str) -> Self {
+        Self {
+            file: file.to_string(),
+            tokens: tokenize(source),
+            pos: 0,
+            rom: vec![0; 2],
+            here: ROM_ADDR + 2,
+            main_jump: true,
+            labels: HashMap::new(),
+            consts: HashMap::new(),
+            aliases: HashMap::new(),
+            macros: HashMap::new(),
+            fixups: Vec::new(),
+            blocks: Vec::new(),
+            expansions: 0,
+        }
+    }
+
+    fn error(&self, tok: Token, kind: ErrorKind) -> Error {
+        Error {
+            file: self.file.clone(),
+            line: tok.line,
+            col: tok.col,
+            kind,
+        }
+    }
+
+    /// Error for a token missing at the end of the source
+    fn eof(&self, what: &'static str) -> Error {
+        let (line, col) = match self.tokens.last() {
+            Some(t) => (t.line, t.col + t.text.chars().count()),
+            None => (1, 1),
+        };
+        Error {
+            file: self.file.clone(),
+            line,
+            col,
+            kind: ErrorKind::Expected(what),
+        }
+    }
+
+    fn next(&mut self, what: &'static str) -> Result<Token<'a>, Error> {
+        let tok = self
+            .tokens
+            .get(self.pos)
+            .copied()
+            .ok_or_else(|| self.eof(what))?;
+        self.pos += 1;
+        Ok(tok)
+    }
+
+    fn expect(&mut self, text: &'static str, what: &'static str) -> Result<Token<'a>, Error> {
+        let tok = self.next(what)?;
+        if tok.text != text {
+            return Err(self.error(tok, ErrorKind::Expected(what)));
+        }
+        Ok(tok)
+    }
+
+    fn register(&self, tok: Token) -> Option<u8> {
+        register(tok.text).or_else(|| self.aliases.get(tok.text).copied())
+    }
+
+    fn reg(&mut self) -> Result<u8, Error> {
+        let tok = self.next("register")?;
+        self.register(tok)
+            .ok_or_else(|| self.error(tok, ErrorKind::Expected("register")))
+    }
+
+    /// Value of a number, constant or defined label
+    fn value(&self, tok: Token) -> Result<f64, Error> {
+        if let Some(v) = number(tok.text) {
+            return Ok(v);
+        }
+        if let Some(v) = self.consts.get(tok.text) {
+            return Ok(*v);
+        }
+        if let Some(addr) = self.labels.get(tok.text) {
+            return Ok(*addr as f64);
+        }
+        Err(self.error(tok, ErrorKind::UndefinedSymbol(tok.text.to_string())))
+    }
+
+    fn int(&self, tok: Token, min: i64, max: i64) -> Result<i64, Error> {
+        let v = self.value(tok)? as i64;
+        if v < min || v > max {
+            return Err(self.error(tok, ErrorKind::OutOfRange(v)));
+        }
+        Ok(v)
+    }
+
+    fn byte(&mut self) -> Result<u8, Error> {
+        let tok = self.next("value")?;
+        Ok(self.int(tok, -0x80, 0xff)? as u8)
+    }
+
+    fn nibble(&mut self) -> Result<u8, Error> {
+        let tok = self.next("value")?;
+        Ok(self.int(tok, 0, 0xf)? as u8)
+    }
+
+    fn rhs(&mut self) -> Result<Rhs, Error> {
+        let tok = self.next("register or value")?;
+        match self.register(tok) {
+            Some(y) => Ok(Rhs::Reg(y)),
+            None => Ok(Rhs::Byte(self.int(tok, -0x80, 0xff)? as u8)),
+        }
+    }
+
+    fn emit(&mut self, tok: Token, bytes: &[u8]) -> Result<(), Error> {
+        if self.here + bytes.len() > MEM_SIZE {
+            return Err(self.error(tok, ErrorKind::ProgramTooBig));
+        }
+        let start = self.here - ROM_ADDR;
+        if self.rom.len() < start + bytes.len() {
+            self.rom.resize(start + bytes.len(), 0);
+        }
+        self.rom[start..start + bytes.len()].copy_from_slice(bytes);
+        self.here += bytes.len();
+        Ok(())
+    }
+
+    fn inst(&mut self, tok: Token, inst: Instruction) -> Result<(), Error> {
+        let mut buf = [0; 4];
+        inst.encode_bytes(&mut buf);
+        self.emit(tok, &buf[..inst.size()])
+    }
+
+    /// Emit the instruction `inst` taking the 12 bit address of the next token, which may be a
+    /// label defined later.
+    fn addr_inst(&mut self, tok: Token, inst: fn(u16) -> Instruction) -> Result<(), Error> {
+        let target = self.next("address")?;
+        let addr = match self.value(target) {
+            Ok(_) => self.int(target, 0, 0xfff)? as u16,
+            Err(_) if number(target.text).is_none() => {
+                self.fixups.push((self.here, Fixup::Addr, target));
+                0
+            }
+            Err(err) => return Err(err),
+        };
+        self.inst(tok, inst(addr))
+    }
+
+    /// Write the 12 bit address `addr` into the opcode at `at`
+    fn patch_addr(&mut self, tok: Token, at: usize, addr: usize) -> Result<(), Error> {
+        if addr > 0xfff {
+            return Err(self.error(tok, ErrorKind::OutOfRange(addr as i64)));
+        }
+        let at = at - ROM_ADDR;
+        self.rom[at] = (self.rom[at] & 0xf0) | (addr >> 8) as u8;
+        self.rom[at + 1] = addr as u8;
+        Ok(())
+    }
+
+    /// Emit the instructions that skip the next one when the condition that follows is `expect`
+    fn condition(&mut self, expect: bool) -> Result<(), Error> {
+        use Instruction::*;
+        let x = self.reg()?;
+        let op = self.next("comparison")?;
+        let skip = match op.text {
+            "key" | "-key" => {
+                if (op.text == "key") == expect {
+                    Skp(x)
+                } else {
+                    Sknp(x)
+                }
+            }
+            "==" | "!=" => match ((op.text == "==") == expect, self.rhs()?) {
+                (true, Rhs::Reg(y)) => SeReg(x, y),
+                (true, Rhs::Byte(kk)) => SeByte(x, kk),
+                (false, Rhs::Reg(y)) => SneReg(x, y),
+                (false, Rhs::Byte(kk)) => SneByte(x, kk),
+            },
+            "<" | ">" | "<=" | ">=" => {
+                // Compute VF = (p >= q) with a subtraction
+                let rhs = self.rhs()?;
+                match (op.text, rhs) {
+                    ("<", Rhs::Reg(y)) | (">=", Rhs::Reg(y)) => {
+                        self.inst(op, LdReg(VF, x))?;
+                        self.inst(op, Sub(VF, y))?;
+                    }
+                    ("<", Rhs::Byte(kk)) | (">=", Rhs::Byte(kk)) => {
+                        self.inst(op, LdByte(VF, kk))?;
+                        self.inst(op, Subn(VF, x))?;
+                    }
+                    (_, Rhs::Reg(y)) => {
+                        self.inst(op, LdReg(VF, y))?;
+                        self.inst(op, Sub(VF, x))?;
+                    }
+                    (_, Rhs::Byte(kk)) => {
+                        self.inst(op, LdByte(VF, kk))?;
+                        self.inst(op, Sub(VF, x))?;
+                    }
+                }
+                // The condition holds when VF is set for `>=` and `<=`, clear for `<` and `>`
+                let flag = op.text.ends_with('=');
+                SeByte(VF, (flag == expect) as u8)
+            }
+            _ => return Err(self.error(op, ErrorKind::Expected("comparison"))),
+        };
+        self.inst(op, skip)
+    }
+
+    /// Emit a jump to be patched later and return its address
+    fn forward_jump(&mut self, tok: Token) -> Result<usize, Error> {
+        let at = self.here;
+        self.inst(tok, Instruction::Jp(0))?;
+        Ok(at)
+    }
+
+    /// Tokens between `{` and the matching `}`, which are consumed
+    fn braces(&mut self) -> Result<(Vec<Token<'a>>, Token<'a>), Error> {
+        self.expect("{", "`{`")?;
+        let mut depth = 0;
+        let mut body = Vec::new();
+        loop {
+            let tok = self.next("`}`")?;
+            match tok.text {
+                "{" => depth += 1,
+                "}" if depth == 0 => return Ok((body, tok)),
+                "}" => depth -= 1,
+                _ => {}
+            }
+            body.push(tok);
+        }
+    }
+
+    /// Evaluate a `:calc` expression: binary operators are right associative and have no
+    /// precedence.  `end` is the closing brace.
+    fn calc(&self, tokens: &[Token<'a>], pos: &mut usize, end: Token) -> Result<f64, Error> {
+        let lhs = self.calc_term(tokens, pos, end)?;
+        match tokens.get(*pos) {
+            Some(op) if op.text != ")" => {
+                *pos += 1;
+                let rhs = self.calc(tokens, pos, end)?;
+                binary_op(op.text, lhs, rhs)
+                    .ok_or_else(|| self.error(*op, ErrorKind::Expected("operator")))
+            }
+            _ => Ok(lhs),
+        }
+    }
+
+    fn calc_term(&self, tokens: &[Token<'a>], pos: &mut usize, end: Token) -> Result<f64, Error> {
+        let tok = match tokens.get(*pos) {
+            Some(tok) => *tok,
+            None => return Err(self.error(end, ErrorKind::Expected("expression"))),
+        };
+        *pos += 1;
+        match tok.text {
+            "(" => {
+                let v = self.calc(tokens, pos, end)?;
+                match tokens.get(*pos) {
+                    Some(t) if t.text == ")" => {
+                        *pos += 1;
+                        Ok(v)
+                    }
+                    Some(t) => Err(self.error(*t, ErrorKind::Expected("`)`"))),
+                    None => Err(self.error(end, ErrorKind::Expected("`)`"))),
+                }
+            }
+            "HERE" => Ok(self.here as f64),
+            "PI" => Ok(core::f64::consts::PI),
+            "E" => Ok(core::f64::consts::E),
+            text => match unary_op(text, 0.0) {
+                Some(_) if number(text).is_none() => {
+                    let v = self.calc_term(tokens, pos, end)?;
+                    Ok(unary_op(text, v).unwrap())
+                }
+                _ => self.value(tok),
+            },
+        }
+    }
+
+    fn define_label(&mut self, tok: Token<'a>) -> Result<(), Error> {
+        if self.labels.contains_key(tok.text) || self.consts.contains_key(tok.text) {
+            return Err(self.error(tok, ErrorKind::DuplicateSymbol(tok.text.to_string())));
+        }
+        if register(tok.text).is_some() || number(tok.text).is_some() {
+            return Err(self.error(tok, ErrorKind::ReservedName(tok.text.to_string())));
+        }
+        // Drop the jump to main when main is the first thing in the program
+        if tok.text == "main" && self.main_jump && self.here == ROM_ADDR + 2 && self.rom.len() == 2
+        {
+            self.main_jump = false;
+            self.rom.clear();
+            self.here = ROM_ADDR;
+        }
+        self.labels.insert(tok.text, self.here);
+        Ok(())
+    }
+
+    fn define_const(&mut self, tok: Token<'a>, v: f64) -> Result<(), Error> {
+        if self.labels.contains_key(tok.text) || self.consts.contains_key(tok.text) {
+            return Err(self.error(tok, ErrorKind::DuplicateSymbol(tok.text.to_string())));
+        }
+        if register(tok.text).is_some() || number(tok.text).is_some() {
+            return Err(self.error(tok, ErrorKind::ReservedName(tok.text.to_string())));
+        }
+        self.consts.insert(tok.text, v);
+        Ok(())
+    }
+
+    /// Replace the macro invocation starting with `tok` by the macro body
+    fn expand(&mut self, tok: Token<'a>) -> Result<(), Error> {
+        self.expansions += 1;
+        if self.expansions > MAX_MACRO_EXPANSIONS {
+            return Err(self.error(tok, ErrorKind::MacroTooDeep));
+        }
+        let m = &self.macros[tok.text];
+        let nargs = m.args.len();
+        if self.pos + nargs > self.tokens.len() {
+            return Err(self.eof("macro argument"));
+        }
+        let args = &self.tokens[self.pos..self.pos + nargs];
+        let body: Vec<_> = m
+            .body
+            .iter()
+            .map(|t| match m.args.iter().position(|a| *a == t.text) {
+                Some(i) => args[i],
+                None => *t,
+            })
+            .collect();
+        self.tokens.splice(self.pos..self.pos + nargs, body);
+        Ok(())
+    }
+
+    fn statement(&mut self, tok: Token<'a>) -> Result<(), Error> {
+        use Instruction::*;
+        match tok.text {
+            ":" => {
+                let name = self.next("label name")?;
+                self.define_label(name)?;
+            }
+            ":alias" => {
+                let name = self.next("alias name")?;
+                let x = self.reg()?;
+                self.aliases.insert(name.text, x);
+            }
+            ":const" => {
+                let name = self.next("constant name")?;
+                let value = self.next("value")?;
+                let v = self.value(value)?;
+                self.define_const(name, v)?;
+            }
+            ":calc" => {
+                let name = self.next("constant name")?;
+                let (body, end) = self.braces()?;
+                let mut pos = 0;
+                let v = self.calc(&body, &mut pos, end)?;
+                self.define_const(name, v)?;
+            }
+            ":macro" => {
+                let name = self.next("macro name")?;
+                let mut args = Vec::new();
+                while self.tokens.get(self.pos).map(|t| t.text) != Some("{") {
+                    args.push(self.next("`{`")?.text);
+                }
+                let (body, _) = self.braces()?;
+                self.macros.insert(name.text, Macro { args, body });
+            }
+            ":org" => {
+                let addr = self.next("address")?;
+                self.here = self.int(addr, ROM_ADDR as i64, MEM_SIZE as i64)? as usize;
+            }
+            ":byte" => {
+                let v = if self.tokens.get(self.pos).map(|t| t.text) == Some("{") {
+                    let (body, end) = self.braces()?;
+                    let v = self.calc(&body, &mut 0, end)? as i64;
+                    if !(-0x80..=0xff).contains(&v) {
+                        return Err(self.error(end, ErrorKind::OutOfRange(v)));
+                    }
+                    v as u8
+                } else {
+                    self.byte()?
+                };
+                self.emit(tok, &[v])?;
+            }
+            ":call" => self.addr_inst(tok, Call)?,
+            ":breakpoint" => {
+                self.next("breakpoint name")?;
+            }
+            "return" | ";" => self.inst(tok, Ret)?,
+            "clear" => self.inst(tok, Cls)?,
+            "bcd" => {
+                let x = self.reg()?;
+                self.inst(tok, LdB(x))?;
+            }
+            "save" | "load" => {
+                let x = self.reg()?;
+                let range = self.tokens.get(self.pos).map(|t| t.text) == Some("-");
+                let inst = if range {
+                    self.pos += 1;
+                    let y = self.reg()?;
+                    if tok.text == "save" {
+                        Save(x, y)
+                    } else {
+                        Load(x, y)
+                    }
+                } else if tok.text == "save" {
+                    LdIVx(x)
+                } else {
+                    LdVxI(x)
+                };
+                self.inst(tok, inst)?;
+            }
+            "saveflags" => {
+                let x = self.reg()?;
+                self.inst(tok, LdRVx(x))?;
+            }
+            "loadflags" => {
+                let x = self.reg()?;
+                self.inst(tok, LdVxR(x))?;
+            }
+            "sprite" => {
+                let x = self.reg()?;
+                let y = self.reg()?;
+                let n = self.nibble()?;
+                self.inst(tok, Drw(x, y, n))?;
+            }
+            "jump" => self.addr_inst(tok, Jp)?,
+            "jump0" => self.addr_inst(tok, JpV0)?,
+            "native" => self.addr_inst(tok, Sys)?,
+            "hires" => self.inst(tok, High)?,
+            "lores" => self.inst(tok, Low)?,
+            "exit" => self.inst(tok, Exit)?,
+            "scroll-down" => {
+                let n = self.nibble()?;
+                self.inst(tok, Scd(n))?;
+            }
+            "scroll-up" => {
+                let n = self.nibble()?;
+                self.inst(tok, Scu(n))?;
+            }
+            "scroll-left" => self.inst(tok, Scl)?,
+            "scroll-right" => self.inst(tok, Scr)?,
+            "plane" => {
+                let n = self.nibble()?;
+                self.inst(tok, Plane(n))?;
+            }
+            "audio" => self.inst(tok, Audio)?,
+            "delay" | "buzzer" | "pitch" => {
+                self.expect(":=", "`:=`")?;
+                let x = self.reg()?;
+                let inst = match tok.text {
+                    "delay" => LdDtVx(x),
+                    "buzzer" => LdStVx(x),
+                    _ => Pitch(x),
+                };
+                self.inst(tok, inst)?;
+            }
+            "i" => {
+                let op = self.next("`:=` or `+=`")?;
+                match op.text {
+                    ":=" => match self.tokens.get(self.pos).map(|t| t.text) {
+                        Some("long") => {
+                            self.pos += 1;
+                            let target = self.next("address")?;
+                            let addr = match self.value(target) {
+                                Ok(_) => self.int(target, 0, 0xffff)? as u16,
+                                Err(_) if number(target.text).is_none() => {
+                                    self.fixups.push((self.here + 2, Fixup::Long, target));
+                                    0
+                                }
+                                Err(err) => return Err(err),
+                            };
+                            self.inst(tok, LdILong(addr))?;
+                        }
+                        Some("hex") => {
+                            self.pos += 1;
+                            let x = self.reg()?;
+                            self.inst(tok, LdF(x))?;
+                        }
+                        Some("bighex") => {
+                            self.pos += 1;
+                            let x = self.reg()?;
+                            self.inst(tok, LdHf(x))?;
+                        }
+                        _ => self.addr_inst(tok, LdI)?,
+                    },
+                    "+=" => {
+                        let x = self.reg()?;
+                        self.inst(tok, AddI(x))?;
+                    }
+                    _ => return Err(self.error(op, ErrorKind::Expected("`:=` or `+=`"))),
+                }
+            }
+            "if" => {
+                let at = self.pos;
+                // Find out the form of the conditional before emitting anything
+                let form = self.tokens[at..]
+                    .iter()
+                    .take(4)
+                    .find(|t| t.text == "then" || t.text == "begin")
+                    .copied()
+                    .ok_or_else(|| match self.tokens.get(at + 3) {
+                        Some(t) => self.error(*t, ErrorKind::Expected("`then` or `begin`")),
+                        None => self.eof("`then` or `begin`"),
+                    })?;
+                if form.text == "then" {
+                    self.condition(false)?;
+                    self.expect("then", "`then`")?;
+                } else {
+                    self.condition(true)?;
+                    self.expect("begin", "`begin`")?;
+                    let jump = self.forward_jump(tok)?;
+                    self.blocks.push((Block::Branch(jump), tok));
+                }
+            }
+            "else" => match self.blocks.pop() {
+                Some((Block::Branch(jump), open)) => {
+                    let end = self.forward_jump(tok)?;
+                    let here = self.here;
+                    self.patch_addr(tok, jump, here)?;
+                    self.blocks.push((Block::Branch(end), open));
+                }
+                _ => return Err(self.error(tok, ErrorKind::Unexpected(tok.text.to_string()))),
+            },
+            "end" => match self.blocks.pop() {
+                Some((Block::Branch(jump), _)) => {
+                    let here = self.here;
+                    self.patch_addr(tok, jump, here)?;
+                }
+                _ => return Err(self.error(tok, ErrorKind::Unexpected(tok.text.to_string()))),
+            },
+            "loop" => {
+                let start = self.here;
+                let block = Block::Loop {
+                    start,
+                    breaks: Vec::new(),
+                };
+                self.blocks.push((block, tok));
+            }
+            "while" => {
+                self.condition(true)?;
+                let jump = self.forward_jump(tok)?;
+                let block = self.blocks.iter_mut().rev().find_map(|(b, _)| match b {
+                    Block::Loop { breaks, .. } => Some(breaks),
+                    _ => None,
+                });
+                match block {
+                    Some(breaks) => breaks.push(jump),
+                    None => {
+                        return Err(self.error(tok, ErrorKind::Unexpected(tok.text.to_string())))
+                    }
+                }
+            }
+            "again" => match self.blocks.pop() {
+                Some((Block::Loop { start, breaks }, _)) => {
+                    self.inst(tok, Jp(0))?;
+                    self.patch_addr(tok, self.here - 2, start)?;
+                    let here = self.here;
+                    for jump in breaks {
+                        self.patch_addr(tok, jump, here)?;
+                    }
+                }
+                _ => return Err(self.error(tok, ErrorKind::Unexpected(tok.text.to_string()))),
+            },
+            _ => {
+                if let Some(x) = self.register(tok) {
+                    return self.assignment(tok, x);
+                }
+                if self.macros.contains_key(tok.text) {
+                    return self.expand(tok);
+                }
+                if number(tok.text).is_some() || self.consts.contains_key(tok.text) {
+                    // Sprite data
+                    let v = self.int(tok, -0x80, 0xff)? as u8;
+                    return self.emit(tok, &[v]);
+                }
+                if tok.text.starts_with(':') || tok.text.starts_with('"') {
+                    return Err(self.error(tok, ErrorKind::Unexpected(tok.text.to_string())));
+                }
+                // A bare label calls the subroutine
+                self.pos -= 1;
+                self.addr_inst(tok, Call)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Statements starting with the register Vx
+    fn assignment(&mut self, tok: Token<'a>, x: u8) -> Result<(), Error> {
+        use Instruction::*;
+        let op = self.next("operator")?;
+        let inst = match op.text {
+            ":=" => match self.tokens.get(self.pos).map(|t| t.text) {
+                Some("random") => {
+                    self.pos += 1;
+                    Rnd(x, self.byte()?)
+                }
+                Some("key") => {
+                    self.pos += 1;
+                    LdVxK(x)
+                }
+                Some("delay") => {
+                    self.pos += 1;
+                    LdVxDt(x)
+                }
+                _ => match self.rhs()? {
+                    Rhs::Reg(y) => LdReg(x, y),
+                    Rhs::Byte(kk) => LdByte(x, kk),
+                },
+            },
+            "+=" => match self.rhs()? {
+                Rhs::Reg(y) => AddReg(x, y),
+                Rhs::Byte(kk) => AddByte(x, kk),
+            },
+            "-=" => match self.rhs()? {
+                Rhs::Reg(y) => Sub(x, y),
+                Rhs::Byte(kk) => AddByte(x, kk.wrapping_neg()),
+            },
+            "=-" | "|=" | "&=" | "^=" | ">>=" | "<<=" => {
+                let y = self.reg()?;
+                match op.text {
+                    "=-" => Subn(x, y),
+                    "|=" => Or(x, y),
+                    "&=" => And(x, y),
+                    "^=" => Xor(x, y),
+                    ">>=" => Shr(x, y),
+                    _ => Shl(x, y),
+                }
+            }
+            _ => return Err(self.error(op, ErrorKind::Expected("operator"))),
+        };
+        self.inst(tok, inst)
+    }
+
+    fn compile(mut self) -> Result<Vec<u8>, Error> {
+        while self.pos < self.tokens.len() {
+            let tok = self.tokens[self.pos];
+            self.pos += 1;
+            self.statement(tok)?;
+        }
+        if let Some((block, tok)) = self.blocks.last() {
+            let what = match block {
+                Block::Branch(_) => "begin",
+                Block::Loop { .. } => "loop",
+            };
+            return Err(self.error(*tok, ErrorKind::Unclosed(what)));
+        }
+        if self.main_jump {
+            match self.labels.get("main") {
+                Some(&main) => {
+                    let tok = Token {
+                        text: "main",
+                        line: 0,
+                        col: 0,
+                    };
+                    self.patch_addr(tok, ROM_ADDR, main)?;
+                    self.rom[0] |= 0x10;
+                }
+                None => {
+                    return Err(Error {
+                        file: self.file,
+                        line: 0,
+                        col: 0,
+                        kind: ErrorKind::MissingMain,
+                    })
+                }
+            }
+        }
+        for (at, fixup, tok) in core::mem::take(&mut self.fixups) {
+            let addr = match self.labels.get(tok.text) {
+                Some(&addr) => addr,
+                None => {
+                    return Err(self.error(tok, ErrorKind::UndefinedSymbol(tok.text.to_string())))
+                }
+            };
+            match fixup {
+                Fixup::Addr => self.patch_addr(tok, at, addr)?,
+                Fixup::Long => {
+                    let at = at - ROM_ADDR;
+                    self.rom[at..at + 2].copy_from_slice(&(addr as u16).to_be_bytes());
+                }
+            }
+        }
+        Ok(self.rom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Big endian bytes of the opcodes
+    fn words(ops: &[u16]) -> Vec<u8> {
+        ops.iter()
+            .flat_map(|op| op.to_be_bytes().to_vec())
+            .collect()
+    }
+
+    fn kind(source: &str) -> ErrorKind {
+        compile(source).unwrap_err().kind
+    }
+
+    #[test]
+    fn then_comparisons() {
+        let cases: &[(&str, &[u16])] = &[
+            ("v1 == 3", &[0x4103]),
+            ("v1 != 3", &[0x3103]),
+            ("v1 == v2", &[0x9120]),
+            ("v1 != v2", &[0x5120]),
+            ("v1 key", &[0xe1a1]),
+            ("v1 -key", &[0xe19e]),
+            ("v1 < 3", &[0x6f03, 0x8f17, 0x3f01]),
+            ("v1 >= 3", &[0x6f03, 0x8f17, 0x3f00]),
+            ("v1 > 3", &[0x6f03, 0x8f15, 0x3f01]),
+            ("v1 <= 3", &[0x6f03, 0x8f15, 0x3f00]),
+            ("v1 < v2", &[0x8f10, 0x8f25, 0x3f01]),
+            ("v1 >= v2", &[0x8f10, 0x8f25, 0x3f00]),
+            ("v1 > v2", &[0x8f20, 0x8f15, 0x3f01]),
+            ("v1 <= v2", &[0x8f20, 0x8f15, 0x3f00]),
+        ];
+        for (cond, ops) in cases {
+            let source = format!(": main if {} then v0 := 1", cond);
+            let mut expected = ops.to_vec();
+            expected.push(0x6001);
+            assert_eq!(compile(&source).unwrap(), words(&expected), "{}", cond);
+        }
+    }
+
+    #[test]
+    fn begin_comparisons() {
+        // The skip is inverted: it jumps over the branch to the end when the condition holds
+        let cases: &[(&str, &[u16])] = &[
+            ("v1 == 3", &[0x3103]),
+            ("v1 != 3", &[0x4103]),
+            ("v1 == v2", &[0x5120]),
+            ("v1 != v2", &[0x9120]),
+            ("v1 key", &[0xe19e]),
+            ("v1 -key", &[0xe1a1]),
+            ("v1 < 3", &[0x6f03, 0x8f17, 0x3f00]),
+            ("v1 >= 3", &[0x6f03, 0x8f17, 0x3f01]),
+            ("v1 > 3", &[0x6f03, 0x8f15, 0x3f00]),
+            ("v1 <= 3", &[0x6f03, 0x8f15, 0x3f01]),
+            ("v1 < v2", &[0x8f10, 0x8f25, 0x3f00]),
+            ("v1 >= v2", &[0x8f10, 0x8f25, 0x3f01]),
+            ("v1 > v2", &[0x8f20, 0x8f15, 0x3f00]),
+            ("v1 <= v2", &[0x8f20, 0x8f15, 0x3f01]),
+        ];
+        for (cond, ops) in cases {
+            let source = format!(": main if {} begin v0 := 1 else v0 := 2 end", cond);
+            let skip = ROM_ADDR as u16 + ops.len() as u16 * 2;
+            let mut expected = ops.to_vec();
+            // Jump to the else branch, the then branch, jump to the end, the else branch
+            expected.extend(&[0x1000 | (skip + 6), 0x6001, 0x1000 | (skip + 8), 0x6002]);
+            assert_eq!(compile(&source).unwrap(), words(&expected), "{}", cond);
+        }
+    }
+
+    #[test]
+    fn nested_blocks() {
+        let source = "
+            : main
+            loop
+                if v0 == 1 begin
+                    loop
+                        while v1 != 0
+                        v1 += -1
+                    again
+                end
+                v0 += 1
+            again
+        ";
+        let expected = words(&[
+            0x3001, // 200: if v0 == 1 begin
+            0x120c, // 202: jump to end
+            0x4100, // 204: while v1 != 0
+            0x120c, // 206: break out of the inner loop
+            0x71ff, // 208: v1 += -1
+            0x1204, // 20a: again
+            0x7001, // 20c: end, v0 += 1
+            0x1200, // 20e: again
+        ]);
+        assert_eq!(compile(source).unwrap(), expected);
+    }
+
+    #[test]
+    fn macro_arguments() {
+        let source = "
+            :macro set-both reg value { reg := value v0 := value }
+            : main
+            set-both v3 7
+            set-both v4 0x20
+        ";
+        let expected = words(&[0x6307, 0x6007, 0x6420, 0x6020]);
+        assert_eq!(compile(source).unwrap(), expected);
+    }
+
+    #[test]
+    fn calc_right_to_left() {
+        // 2 * 3 + 4 is 2 * (3 + 4) and 10 - 4 - 3 is 10 - (4 - 3)
+        let source = "
+            :calc a { 2 * 3 + 4 }
+            :calc b { 10 - 4 - 3 }
+            :calc c { ( 2 * 3 ) + 4 }
+            : main
+            v0 := a
+            v1 := b
+            v2 := c
+        ";
+        let expected = words(&[0x600e, 0x6109, 0x620a]);
+        assert_eq!(compile(source).unwrap(), expected);
+    }
+
+    #[test]
+    fn org() {
+        let source = ": main jump next :org 0x208 : next v0 := 1";
+        let expected = words(&[0x1208, 0x0000, 0x0000, 0x0000, 0x6001]);
+        assert_eq!(compile(source).unwrap(), expected);
+        assert!(matches!(kind(":org 0x100"), ErrorKind::OutOfRange(0x100)));
+    }
+
+    #[test]
+    fn i_long() {
+        let source = ": main i := long data i := long 0x1234 : data 0xaa";
+        let expected = vec![0xf0, 0x00, 0x02, 0x08, 0xf0, 0x00, 0x12, 0x34, 0xaa];
+        assert_eq!(compile(source).unwrap(), expected);
+    }
+
+    #[test]
+    fn forward_labels() {
+        let source = ": main i := data jump0 table :call sub sub : sub return : table : data";
+        let expected = words(&[0xa20a, 0xb20a, 0x2208, 0x2208, 0x00ee]);
+        assert_eq!(compile(source).unwrap(), expected);
+    }
+
+    #[test]
+    fn main_jump() {
+        // Without main first, the program starts with a jump to it
+        let expected = words(&[0x1204, 0x00ee, 0x6001]);
+        assert_eq!(compile(": sub return : main v0 := 1").unwrap(), expected);
+        let expected = words(&[0x6001, 0x00ee]);
+        assert_eq!(compile(": main v0 := 1 : sub return").unwrap(), expected);
+        assert!(matches!(kind(": sub return"), ErrorKind::MissingMain));
+    }
+
+    #[test]
+    fn errors() {
+        let err = compile(": main\n  jump nowhere").unwrap_err();
+        assert!(matches!(&err.kind, ErrorKind::UndefinedSymbol(s) if s == "nowhere"));
+        assert_eq!((err.line, err.col), (2, 8));
+        assert!(matches!(
+            kind(": main v0 := undefined"),
+            ErrorKind::UndefinedSymbol(_)
+        ));
+        assert!(matches!(
+            kind(": main v0 := 256"),
+            ErrorKind::OutOfRange(256)
+        ));
+        assert!(matches!(
+            kind(": main v0 := -129"),
+            ErrorKind::OutOfRange(-129)
+        ));
+        assert!(matches!(
+            kind(": main jump 0x1000"),
+            ErrorKind::OutOfRange(0x1000)
+        ));
+        assert!(matches!(
+            kind(": main sprite v0 v1 16"),
+            ErrorKind::OutOfRange(16)
+        ));
+        assert!(matches!(
+            kind(": main :byte { 200 + 100 }"),
+            ErrorKind::OutOfRange(300)
+        ));
+        // Labels past 0xfff don't fit the 12 bit address of a jump
+        assert!(matches!(
+            kind(": main jump far :org 0x1000 : far"),
+            ErrorKind::OutOfRange(0x1000)
+        ));
+    }
+}
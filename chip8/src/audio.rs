@@ -0,0 +1,279 @@
+//! Audio generation: the tone of the sound timer rendered into PCM samples.
+//!
+//! Every run of the machine (`Chip8::frame` or `Chip8::run_for`) logs the positions where the tone
+//! starts and stops.  After the run, `Audio::render` produces the samples covering it, so that
+//! the output follows the machine with sub-frame accuracy at any sample rate.  Starts and stops
+//! are smoothed with linear ramps to avoid clicks.
+
+use super::*;
+
+/// Maximum number of tone changes logged in a run
+const MAX_TONE_CHANGES: usize = 16;
+/// Real time units per second, in 1/60 microseconds
+const SECOND: u64 = 60 * 1_000_000;
+
+/// Tone changes during a run
+pub(crate) struct ToneLog {
+    start: bool,                              // Tone at the start of the run
+    changes: [(u64, bool); MAX_TONE_CHANGES], // Positions in 1/60 us and new tone
+    len: usize,                               // Number of changes
+    duration: u64,                            // Duration of the run in 1/60 us
+}
+
+impl ToneLog {
+    pub(crate) fn new() -> Self {
+        Self {
+            start: false,
+            changes: [(0, false); MAX_TONE_CHANGES],
+            len: 0,
+            duration: 0,
+        }
+    }
+    /// Clear the log for a new run of `duration` 1/60 microseconds.
+    pub(crate) fn start(&mut self, tone: bool, duration: u64) {
+        self.start = tone;
+        self.len = 0;
+        self.duration = duration;
+    }
+    /// Log a tone change.  When the log is full, the last change is replaced so that the tone at
+    /// the end of the run is right.
+    fn push(&mut self, pos: u64, tone: bool) {
+        if self.len == MAX_TONE_CHANGES {
+            self.len -= 1;
+        }
+        self.changes[self.len] = (pos, tone);
+        self.len += 1;
+    }
+}
+
+impl<R: RngCore, T: Trap, M: Bus> Chip8<R, T, M> {
+    /// Turn the tone on or off at position `pos` of the run.
+    pub(crate) fn set_tone(&mut self, tone: bool, pos: u64) {
+        if tone != self.tone {
+            self.tone = tone;
+            self.tone_log.push(pos, tone);
+        }
+    }
+}
+
+/// Shape of the tone.  XO-CHIP machines play their audio pattern instead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Waveform {
+    Square,
+    Triangle,
+    Sawtooth,
+}
+
+/// Generator of PCM samples from the sound timer of a machine.
+pub struct Audio {
+    sample_rate: u32, // Samples per second
+    waveform: Waveform,
+    frequency: f32, // Tone frequency in Hz
+    volume: f32,    // Peak amplitude, from 0 to 1
+    ramp: f32,      // Change of the envelope per sample
+    phase: f32,     // Position in the waveform period, from 0 to 1
+    level: f32,     // Envelope, from 0 to 1
+    carry: u64,     // Time since the last sample, in 1/60 us times the rate
+}
+
+/// 2 to the power of `n / 48`
+fn exp2_48(n: i32) -> f32 {
+    const STEP: f32 = 1.014_545_3; // 2^(1/48)
+    let mut r = 1.0;
+    for _ in 0..n.rem_euclid(48) {
+        r *= STEP;
+    }
+    let octaves = n.div_euclid(48);
+    if octaves >= 0 {
+        r * (1u32 << octaves) as f32
+    } else {
+        r / (1u32 << -octaves) as f32
+    }
+}
+
+impl Audio {
+    /// Create a generator of a 440 Hz square wave at `sample_rate` samples per second, with ramps
+    /// of 2 ms.
+    pub fn new(sample_rate: u32) -> Self {
+        let mut audio = Self {
+            sample_rate,
+            waveform: Waveform::Square,
+            frequency: 440.0,
+            volume: 0.25,
+            ramp: 1.0,
+            phase: 0.0,
+            level: 0.0,
+            carry: 0,
+        };
+        audio.set_ramp(2000);
+        audio
+    }
+    pub fn set_waveform(&mut self, waveform: Waveform) {
+        self.waveform = waveform;
+    }
+    /// Set the frequency of the tone in Hz.
+    pub fn set_frequency(&mut self, frequency: f32) {
+        self.frequency = frequency;
+    }
+    /// Set the peak amplitude, from 0 to 1.
+    pub fn set_volume(&mut self, volume: f32) {
+        self.volume = volume;
+    }
+    /// Set the duration in microseconds of the fade in and out of the tone.
+    pub fn set_ramp(&mut self, ramp_us: u32) {
+        let samples = ramp_us as u64 * self.sample_rate as u64 / 1_000_000;
+        self.ramp = 1.0 / samples.max(1) as f32;
+    }
+    /// Number of samples that `render` will produce for the last run of `chip8`
+    pub fn samples<R: RngCore, T: Trap, M: Bus>(&self, chip8: &Chip8<R, T, M>) -> usize {
+        // Samples fall where the time multiplied by the rate is a multiple of SECOND
+        let end = self.carry + chip8.tone_log.duration * self.sample_rate as u64;
+        ((end + SECOND - 1) / SECOND - (self.carry + SECOND - 1) / SECOND) as usize
+    }
+    /// Render the samples covering the last run of `chip8` into `out`.  Returns the number of
+    /// samples written; the samples that don't fit in `out` are dropped.
+    pub fn render<R: RngCore, T: Trap, M: Bus>(
+        &mut self,
+        chip8: &Chip8<R, T, M>,
+        out: &mut [f32],
+    ) -> usize {
+        let log = &chip8.tone_log;
+        let rate = self.sample_rate as u64;
+        let n = self.samples(chip8);
+        // Position of the first sample in the run, in 1/60 us times the rate
+        let first = (SECOND - self.carry) % SECOND;
+        self.carry = (self.carry + log.duration * rate) % SECOND;
+
+        let (pattern, phase_inc) = if chip8.xo() {
+            let bits = (AUDIO_PATTERN_SIZE * 8) as f32;
+            let bit_rate = 4000.0 * exp2_48(chip8.pitch as i32 - 64);
+            (
+                Some(&chip8.pattern),
+                bit_rate / bits / self.sample_rate as f32,
+            )
+        } else {
+            (None, self.frequency / self.sample_rate as f32)
+        };
+        let mut tone = log.start;
+        let mut changes = log.changes[..log.len].iter().peekable();
+        for (i, x) in out.iter_mut().take(n).enumerate() {
+            let pos = (first + i as u64 * SECOND) / rate;
+            while let Some((_, t)) = changes.next_if(|(p, _)| *p <= pos) {
+                tone = *t;
+            }
+            self.level = if tone {
+                (self.level + self.ramp).min(1.0)
+            } else {
+                (self.level - self.ramp).max(0.0)
+            };
+            if self.level == 0.0 {
+                *x = 0.0;
+                continue;
+            }
+            let wave = match (pattern, self.waveform) {
+                (Some(pattern), _) => {
+                    let bit = (self.phase * (AUDIO_PATTERN_SIZE * 8) as f32) as usize;
+                    if pattern[bit / 8] & 0x80 >> (bit % 8) != 0 {
+                        1.0
+                    } else {
+                        -1.0
+                    }
+                }
+                (None, Waveform::Square) => {
+                    if self.phase < 0.5 {
+                        1.0
+                    } else {
+                        -1.0
+                    }
+                }
+                (None, Waveform::Triangle) => 4.0 * (self.phase - 0.5).abs() - 1.0,
+                (None, Waveform::Sawtooth) => 2.0 * self.phase - 1.0,
+            };
+            *x = wave * self.volume * self.level;
+            self.phase = (self.phase + phase_inc) % 1.0;
+        }
+        n.min(out.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Machine that sets the sound timer to `st` and loops
+    fn machine(st: u8) -> Chip8 {
+        // LD V0, st; LD ST, V0; JP 0x204
+        TestMachine::new()
+            .rom(&[0x60, st, 0xf0, 0x18, 0x12, 0x04])
+            .build()
+    }
+
+    #[test]
+    fn sample_counts() {
+        let mut chip8 = machine(0);
+        let mut out = [1.0; 1024];
+        for &(rate, samples) in [(44100, 735), (48000, 800), (8000, 133)].iter() {
+            let mut audio = Audio::new(rate);
+            let mut total = 0;
+            for _ in 0..60 {
+                chip8.frame(0).unwrap();
+                let n = audio.samples(&chip8);
+                assert!(n == samples || n == samples + 1, "{} {}", rate, n);
+                assert_eq!(audio.render(&chip8, &mut out), n);
+                total += n;
+            }
+            // A second of samples, with the fractions carried between frames
+            assert_eq!(total, rate as usize);
+            // Silent without a tone
+            assert!(out[..samples].iter().all(|&x| x == 0.0));
+        }
+
+        let mut audio = Audio::new(48000);
+        for _ in 0..10 {
+            chip8.run_for(1000).unwrap();
+            assert_eq!(audio.render(&chip8, &mut out), 48);
+        }
+        // Samples that don't fit are dropped
+        chip8.frame(0).unwrap();
+        assert_eq!(audio.samples(&chip8), 800);
+        assert_eq!(audio.render(&chip8, &mut out[..100]), 100);
+    }
+
+    #[test]
+    fn ramps() {
+        let mut chip8 = machine(2);
+        let mut audio = Audio::new(48000);
+        // 96 samples of ramp
+        audio.set_ramp(2000);
+        let mut out = [0.0; 800];
+        let volume = 0.25;
+        chip8.frame(0).unwrap();
+        assert!(chip8.tone());
+        assert_eq!(audio.render(&chip8, &mut out), 800);
+        // Fades in from the start of the tone, then plays at full volume
+        let start = out.iter().position(|&x| x != 0.0).unwrap();
+        // LD ST runs after the 27 us of LD V0, so the tone starts at the third sample
+        assert_eq!(start, 2);
+        for (i, x) in out[start..start + 96].iter().enumerate() {
+            let level = volume * (i + 1) as f32 / 96.0;
+            assert!((x.abs() - level).abs() < 1e-4, "{} {}", i, x);
+        }
+        assert!(out[start + 96..].iter().all(|x| x.abs() == volume));
+        // Square wave at 440 Hz: 7.3 periods in a frame
+        let flips = out.windows(2).filter(|w| w[0] * w[1] < 0.0).count();
+        assert!((14..=15).contains(&flips), "{}", flips);
+
+        // The timer stops the tone at the start of the third frame
+        chip8.frame(0).unwrap();
+        audio.render(&chip8, &mut out);
+        assert!(out.iter().all(|x| x.abs() == volume));
+        chip8.frame(0).unwrap();
+        assert!(!chip8.tone());
+        audio.render(&chip8, &mut out);
+        for (i, x) in out[..96].iter().enumerate() {
+            let level = volume * (95 - i) as f32 / 96.0;
+            assert!((x.abs() - level).abs() < 1e-4, "{} {}", i, x);
+        }
+        assert!(out[96..].iter().all(|&x| x == 0.0));
+    }
+}
@@ -0,0 +1,156 @@
+//! Memory bus: the storage behind the address space of the machine.
+//!
+//! All the accesses of the machine to its memory go through a `Bus`, so that embedders can serve
+//! regions from other storage (like a rom in flash), observe accesses, mirror regions or map
+//! peripherals.  Addresses are always below `MEM_SIZE`: the memory policy is applied before.
+//!
+//! The instructions of the program use `read` and `write`, including the fetch of opcodes.  The
+//! rest of the machine (loading fonts and roms, save states, checksums, fault reports and the
+//! debugger) uses `peek` and `poke`, which must not have side effects beyond storing the byte.
+
+use super::*;
+
+/// Address space of the machine.
+pub trait Bus {
+    /// Byte at `addr`, without side effects
+    fn peek(&self, addr: usize) -> u8;
+    /// Store `v` at `addr`, without side effects.
+    fn poke(&mut self, addr: usize, v: u8);
+    /// Byte at `addr` read by the program
+    fn read(&mut self, addr: usize) -> u8 {
+        self.peek(addr)
+    }
+    /// Store `v` at `addr` on behalf of the program.
+    fn write(&mut self, addr: usize, v: u8) {
+        self.poke(addr, v)
+    }
+}
+
+/// Plain RAM covering the whole address space: the default bus.
+#[derive(Clone)]
+pub struct Memory(pub [u8; MEM_SIZE]);
+
+impl Memory {
+    pub fn new() -> Self {
+        Self([0; MEM_SIZE])
+    }
+}
+
+impl Default for Memory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AsRef<[u8]> for Memory {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl AsMut<[u8]> for Memory {
+    fn as_mut(&mut self) -> &mut [u8] {
+        &mut self.0
+    }
+}
+
+impl Bus for Memory {
+    #[inline]
+    fn peek(&self, addr: usize) -> u8 {
+        self.0[addr]
+    }
+    #[inline]
+    fn poke(&mut self, addr: usize, v: u8) {
+        self.0[addr] = v;
+    }
+}
+
+impl<R: RngCore, T: Trap, M: Bus> Chip8<R, T, M> {
+    /// Memory bus of the machine
+    pub fn bus(&self) -> &M {
+        &self.mem
+    }
+    /// Mutable memory bus of the machine
+    pub fn bus_mut(&mut self) -> &mut M {
+        &mut self.mem
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Address of a counter that counts the reads of the program
+    const COUNTER: usize = 0xf00;
+
+    /// RAM with a read-only program and a counter
+    struct Mapped {
+        ram: Memory,
+        rom_end: usize,
+        reads: u8,
+        writes: usize,
+    }
+
+    impl Bus for Mapped {
+        fn peek(&self, addr: usize) -> u8 {
+            match addr {
+                COUNTER => self.reads,
+                _ => self.ram.peek(addr),
+            }
+        }
+        fn poke(&mut self, addr: usize, v: u8) {
+            self.ram.poke(addr, v)
+        }
+        fn read(&mut self, addr: usize) -> u8 {
+            if addr == COUNTER {
+                self.reads += 1;
+            }
+            self.peek(addr)
+        }
+        fn write(&mut self, addr: usize, v: u8) {
+            self.writes += 1;
+            if !(0x200..self.rom_end).contains(&addr) {
+                self.poke(addr, v)
+            }
+        }
+    }
+
+    #[test]
+    fn custom_bus() {
+        // LD I, 0xf00; LD V0, [I]; LD V0, [I]; LD I, 0x200; LD [I], V1; LD V2, 0x12
+        let rom = [
+            0xaf, 0x00, 0xf0, 0x65, 0xf0, 0x65, 0xa2, 0x00, 0xf1, 0x55, 0x62, 0x12,
+        ];
+        let bus = Mapped {
+            ram: Memory::new(),
+            rom_end: 0x200 + rom.len(),
+            reads: 0,
+            writes: 0,
+        };
+        let mut chip8 = TestMachine::new().rom(&rom).build_with_bus(bus);
+        // Fonts and rom are stored through the bus
+        assert_eq!(chip8.peek(0), 0xf0);
+        let mut buf = [0; 2];
+        chip8.peek_range(0x200, &mut buf);
+        assert_eq!(buf, [0xaf, 0x00]);
+
+        for _ in 0..3 {
+            chip8.step().unwrap();
+        }
+        // Each load reads the counter once, and the debugger view doesn't count
+        assert_eq!(chip8.v()[0], 2);
+        assert_eq!(chip8.peek(COUNTER as u16), 2);
+        assert_eq!(chip8.bus().reads, 2);
+
+        // The program can't overwrite itself
+        for _ in 0..3 {
+            chip8.step().unwrap();
+        }
+        assert_eq!(chip8.bus().writes, 2);
+        assert_eq!(chip8.peek(0x200), 0xaf);
+        assert_eq!(chip8.v()[2], 0x12);
+
+        chip8.bus_mut().reads = 10;
+        assert_eq!(chip8.peek(COUNTER as u16), 10);
+    }
+}
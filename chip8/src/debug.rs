@@ -0,0 +1,414 @@
+//! Debugger: breakpoints, watchpoints and single stepping.
+//!
+//! When a break condition is met, `Chip8::frame` returns early with `Error::Debug` holding the
+//! reason.  Breakpoints and opcode breaks stop before the instruction is executed, watchpoints
+//! stop right after the instruction that triggered them.  `Chip8::resume` continues the
+//! interrupted frame.  `Chip8::step` executes a single instruction as part of the interrupted
+//! frame, so the timers tick when it reaches their next tick; between frames they don't.
+
+use super::*;
+
+/// Maximum number of PC breakpoints
+pub const MAX_BREAKPOINTS: usize = 16;
+/// Maximum number of memory watchpoints
+pub const MAX_WATCHPOINTS: usize = 8;
+/// Maximum number of opcode breaks
+pub const MAX_OPCODE_BREAKS: usize = 8;
+
+/// Reason for the machine to stop
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Break {
+    /// The PC reached a breakpoint.
+    Breakpoint { pc: u16 },
+    /// The instruction at `pc` matches an opcode break.
+    Opcode { pc: u16, opcode: u16 },
+    /// The instruction at `pc` read the watched address `addr`.
+    MemRead { pc: u16, addr: u16 },
+    /// The instruction at `pc` wrote the watched address `addr`.
+    MemWrite { pc: u16, addr: u16 },
+    /// The instruction at `pc` changed the watched register Vx from `old` to `new`.
+    Reg { pc: u16, x: u8, old: u8, new: u8 },
+}
+
+/// Kind of memory access that triggers a watchpoint
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Watch {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+#[derive(Clone, Copy)]
+struct Watchpoint {
+    addr: u16,
+    len: u16,
+    kind: Watch,
+}
+
+#[derive(Clone, Copy)]
+struct OpcodeBreak {
+    mask: u16,
+    opcode: u16,
+}
+
+pub(crate) struct Debugger {
+    breakpoints: [Option<u16>; MAX_BREAKPOINTS],
+    watchpoints: [Option<Watchpoint>; MAX_WATCHPOINTS],
+    opcode_breaks: [Option<OpcodeBreak>; MAX_OPCODE_BREAKS],
+    regs: u16,                     // Mask of watched V registers
+    pub(crate) hit: Option<Break>, // Break reported by the current instruction
+    pub(crate) skip: bool,         // Don't break before the next instruction
+}
+
+/// Insert `item` in the first free slot of `table`.  Returns false if the table is full.
+fn insert<T>(table: &mut [Option<T>], item: T) -> bool {
+    match table.iter_mut().find(|slot| slot.is_none()) {
+        Some(slot) => {
+            *slot = Some(item);
+            true
+        }
+        None => false,
+    }
+}
+
+impl Debugger {
+    pub(crate) fn new() -> Self {
+        Self {
+            breakpoints: [None; MAX_BREAKPOINTS],
+            watchpoints: [None; MAX_WATCHPOINTS],
+            opcode_breaks: [None; MAX_OPCODE_BREAKS],
+            regs: 0,
+            hit: None,
+            skip: false,
+        }
+    }
+
+    /// Break condition before executing `opcode` at `pc`
+    pub(crate) fn check_exec(&self, pc: u16, opcode: u16) -> Option<Break> {
+        if self.breakpoints.contains(&Some(pc)) {
+            return Some(Break::Breakpoint { pc });
+        }
+        if self
+            .opcode_breaks
+            .iter()
+            .flatten()
+            .any(|b| opcode & b.mask == b.opcode & b.mask)
+        {
+            return Some(Break::Opcode { pc, opcode });
+        }
+        None
+    }
+
+    /// Record a hit if the access of `kind` to `addr` by the instruction at `pc` is watched
+    pub(crate) fn check_access(&mut self, pc: u16, addr: u16, kind: Watch) {
+        if self.hit.is_some() {
+            return;
+        }
+        let watched = self.watchpoints.iter().flatten().any(|w| {
+            addr.wrapping_sub(w.addr) < w.len && (w.kind == kind || w.kind == Watch::ReadWrite)
+        });
+        if watched {
+            self.hit = Some(match kind {
+                Watch::Write => Break::MemWrite { pc, addr },
+                _ => Break::MemRead { pc, addr },
+            });
+        }
+    }
+
+    /// Whether any memory access is watched
+    pub(crate) fn watching_mem(&self) -> bool {
+        self.watchpoints.iter().any(|w| w.is_some())
+    }
+
+    /// Mask of watched V registers
+    pub(crate) fn watching_regs(&self) -> u16 {
+        self.regs
+    }
+}
+
+impl<R: RngCore, T: Trap, M: Bus> Chip8<R, T, M> {
+    /// Break before executing the instruction at `pc`.  Returns false if there are already
+    /// `MAX_BREAKPOINTS` breakpoints.
+    pub fn add_breakpoint(&mut self, pc: u16) -> bool {
+        insert(&mut self.debug.breakpoints, pc)
+    }
+    /// Remove the breakpoint at `pc`
+    pub fn remove_breakpoint(&mut self, pc: u16) {
+        for b in self.debug.breakpoints.iter_mut() {
+            if *b == Some(pc) {
+                *b = None;
+            }
+        }
+    }
+    /// Break after an instruction accesses memory in `addr..addr + len` as `kind`.  Returns false
+    /// if there are already `MAX_WATCHPOINTS` watchpoints.
+    pub fn add_watchpoint(&mut self, addr: u16, len: u16, kind: Watch) -> bool {
+        insert(&mut self.debug.watchpoints, Watchpoint { addr, len, kind })
+    }
+    /// Remove the watchpoints starting at `addr`
+    pub fn remove_watchpoint(&mut self, addr: u16) {
+        for w in self.debug.watchpoints.iter_mut() {
+            if w.map(|w| w.addr) == Some(addr) {
+                *w = None;
+            }
+        }
+    }
+    /// Break after an instruction changes the value of Vx, or stop watching it
+    pub fn watch_reg(&mut self, x: u8, watch: bool) {
+        if watch {
+            self.debug.regs |= 1 << (x & 0x0f);
+        } else {
+            self.debug.regs &= !(1 << (x & 0x0f));
+        }
+    }
+    /// Break before executing an instruction matching `opcode` in the bits set in `mask`.
+    /// Returns false if there are already `MAX_OPCODE_BREAKS` opcode breaks.
+    pub fn add_opcode_break(&mut self, mask: u16, opcode: u16) -> bool {
+        insert(&mut self.debug.opcode_breaks, OpcodeBreak { mask, opcode })
+    }
+    /// Remove the opcode breaks matching `mask` and `opcode`
+    pub fn remove_opcode_break(&mut self, mask: u16, opcode: u16) {
+        for b in self.debug.opcode_breaks.iter_mut() {
+            if let Some(OpcodeBreak { mask: m, opcode: o }) = *b {
+                if m == mask && o == opcode {
+                    *b = None;
+                }
+            }
+        }
+    }
+    /// Remove all the breakpoints, watchpoints and opcode breaks
+    pub fn clear_breaks(&mut self) {
+        self.debug = Debugger::new();
+    }
+
+    /// Program Counter
+    pub fn pc(&self) -> u16 {
+        self.pc
+    }
+    /// Index Register
+    pub fn i(&self) -> u16 {
+        self.i
+    }
+    /// Register Set V0 through VF
+    pub fn v(&self) -> [u8; 0x10] {
+        self.v.0
+    }
+    /// Return addresses in the call stack, oldest first
+    pub fn stack(&self) -> &[u16] {
+        &self.stack[..self.sp as usize]
+    }
+    /// Delay Timer
+    pub fn dt(&self) -> u8 {
+        self.dt
+    }
+    /// Sound Timer
+    pub fn st(&self) -> u8 {
+        self.st
+    }
+    /// Byte at `addr` of memory, read without side effects
+    pub fn peek(&self, addr: u16) -> u8 {
+        self.mem.peek(addr as usize % MEM_SIZE)
+    }
+    /// Copy the memory starting at `addr` into `buf`, wrapping around at the end, without side
+    /// effects.  Works with any `Bus`, unlike `mem`.
+    pub fn peek_range(&self, addr: u16, buf: &mut [u8]) {
+        for (i, b) in buf.iter_mut().enumerate() {
+            *b = self.mem.peek((addr as usize + i) % MEM_SIZE);
+        }
+    }
+}
+
+impl<R: RngCore, T: Trap> Chip8<R, T> {
+    /// Memory view.  Only available with the default `Memory` bus, use `peek_range` with other
+    /// buses.
+    pub fn mem(&self) -> &[u8] {
+        self.mem.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 200: LD V0, 5; LD I, 0x300; LD [I], V0; LD V0, [I]; ADD V1, 1; JP 0x208
+    const PROGRAM: [u16; 6] = [0x6005, 0xa300, 0xf055, 0xf065, 0x7101, 0x1208];
+
+    /// Break that stopped the frame, None if it ran to the end
+    fn stopped(result: Result<(), Error>) -> Option<Break> {
+        match result {
+            Ok(()) => None,
+            Err(Error::Debug(b)) => Some(b),
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    fn machine() -> Chip8 {
+        TestMachine::new().words(&PROGRAM).build()
+    }
+
+    #[test]
+    fn breakpoints() {
+        let mut chip8 = machine();
+        assert!(chip8.add_breakpoint(0x204));
+        let b = Break::Breakpoint { pc: 0x204 };
+        assert_eq!(stopped(chip8.frame(0)), Some(b));
+        // Stopped before the store
+        assert_eq!(chip8.pc(), 0x204);
+        assert_eq!(chip8.peek(0x300), 0);
+        // The breakpoint at PC doesn't stop the resumed frame again
+        chip8.resume().unwrap();
+        assert_eq!(chip8.peek(0x300), 5);
+        chip8.remove_breakpoint(0x204);
+        chip8.frame(0).unwrap();
+
+        for pc in 0..MAX_BREAKPOINTS as u16 {
+            assert!(chip8.add_breakpoint(0x400 + pc));
+        }
+        assert!(!chip8.add_breakpoint(0x208));
+        chip8.clear_breaks();
+        assert!(chip8.add_breakpoint(0x208));
+    }
+
+    #[test]
+    fn opcode_breaks() {
+        let mut chip8 = machine();
+        assert!(chip8.add_opcode_break(0xf0ff, 0xf065));
+        let b = Break::Opcode {
+            pc: 0x206,
+            opcode: 0xf065,
+        };
+        assert_eq!(stopped(chip8.frame(0)), Some(b));
+        assert_eq!(chip8.pc(), 0x206);
+        chip8.remove_opcode_break(0xf0ff, 0xf065);
+        chip8.resume().unwrap();
+    }
+
+    #[test]
+    fn watchpoints() {
+        let mut chip8 = machine();
+        assert!(chip8.add_watchpoint(0x2ff, 2, Watch::Write));
+        let write = chip8.frame(0);
+        assert!(matches!(
+            write,
+            Err(Error::Debug(Break::MemWrite { addr: 0x300, .. }))
+        ));
+        // Stopped after the store
+        assert_eq!(chip8.peek(0x300), 5);
+        assert_eq!(chip8.pc(), 0x206);
+        chip8.resume().unwrap();
+
+        let mut chip8 = machine();
+        assert!(chip8.add_watchpoint(0x300, 1, Watch::Read));
+        let read = chip8.frame(0);
+        assert!(matches!(
+            read,
+            Err(Error::Debug(Break::MemRead { addr: 0x300, .. }))
+        ));
+        assert_eq!(chip8.pc(), 0x208);
+        chip8.remove_watchpoint(0x300);
+        chip8.resume().unwrap();
+
+        let mut chip8 = machine();
+        assert!(chip8.add_watchpoint(0x301, 0x10, Watch::ReadWrite));
+        chip8.frame(0).unwrap();
+    }
+
+    #[test]
+    fn register_watch() {
+        let mut chip8 = machine();
+        chip8.watch_reg(1, true);
+        let b = Break::Reg {
+            pc: 0x208,
+            x: 1,
+            old: 0,
+            new: 1,
+        };
+        assert_eq!(stopped(chip8.frame(0)), Some(b));
+        assert_eq!(chip8.v()[1], 1);
+        chip8.watch_reg(1, false);
+        chip8.resume().unwrap();
+        assert!(chip8.v()[1] > 1);
+    }
+
+    #[test]
+    fn resume() {
+        // An interrupted frame ends in the same state as an uninterrupted one
+        let mut reference = machine();
+        reference.frame(0).unwrap();
+        reference.frame(0).unwrap();
+
+        let mut chip8 = machine();
+        chip8.watch_reg(1, true);
+        let mut breaks = 0;
+        for _ in 0..2 {
+            let mut result = stopped(chip8.frame(0));
+            while result.is_some() {
+                breaks += 1;
+                result = stopped(chip8.resume());
+            }
+        }
+        assert_eq!(breaks, reference.v()[1] as usize);
+        assert_eq!(chip8.checksum(), reference.checksum());
+    }
+
+    #[test]
+    fn stepping() {
+        // LD V0, 16; LD DT, V0; JP 0x204
+        let mut chip8 = TestMachine::new().words(&[0x6010, 0xf015, 0x1204]).build();
+        chip8.add_breakpoint(0x202);
+        for _ in 0..100 {
+            chip8.step().unwrap();
+        }
+        // Breakpoints are ignored and the timers don't run between frames
+        assert_eq!(chip8.pc(), 0x204);
+        assert_eq!(chip8.dt(), 16);
+        chip8.frame(0).unwrap();
+        assert_eq!(chip8.dt(), 15);
+    }
+
+    #[test]
+    fn stepping_through_a_run() {
+        // LD V0, 16; LD DT, V0; JP 0x204
+        let quirks = Quirks {
+            timing: Timing::Tickrate(10),
+            ..Quirks::default()
+        };
+        let machine = || {
+            TestMachine::new()
+                .quirks(quirks)
+                .words(&[0x6010, 0xf015, 0x1204])
+                .build()
+        };
+        let mut reference = machine();
+        reference.run_for(33_333).unwrap();
+
+        // Two frames of 10 instructions, the second timer tick is reached by the 11th
+        let mut chip8 = machine();
+        chip8.add_breakpoint(0x204);
+        let b = Break::Breakpoint { pc: 0x204 };
+        assert_eq!(stopped(chip8.run_for(33_333)), Some(b));
+        for _ in 0..8 {
+            chip8.step().unwrap();
+        }
+        assert_eq!(chip8.dt(), 16);
+        chip8.step().unwrap();
+        assert_eq!(chip8.dt(), 15);
+        // The steps were part of the run
+        chip8.remove_breakpoint(0x204);
+        chip8.resume().unwrap();
+        assert_eq!(chip8.dt(), 15);
+        assert_eq!(chip8.checksum(), reference.checksum());
+    }
+
+    #[test]
+    fn memory_views() {
+        let chip8 = machine();
+        let mut buf = [0; 4];
+        chip8.peek_range(0x200, &mut buf);
+        assert_eq!(buf, [0x60, 0x05, 0xa3, 0x00]);
+        assert_eq!(&chip8.mem()[0x200..0x204], &buf);
+        // Wraps around at the end of memory
+        chip8.peek_range((MEM_SIZE - 2) as u16, &mut buf);
+        assert_eq!(buf, [0, 0, 0xf0, 0x90]);
+    }
+}
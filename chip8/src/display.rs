@@ -0,0 +1,414 @@
+//! Framebuffer access: pixels, iterators, conversions into common pixel layouts and tracking of
+//! the regions that changed.
+//!
+//! Pixels are addressed at the current resolution.  The color of a pixel is the combination of
+//! its bits in each plane, plane 0 being the lowest bit, so that it's 0 or 1 without XO-CHIP
+//! support and up to 3 with it.
+
+use super::*;
+
+/// Number of colors that a pixel can take
+pub const COLORS: usize = 1 << PLANES;
+
+/// RGB color of each pixel color
+pub type Palette = [[u8; 3]; COLORS];
+
+/// Region of the framebuffer modified since the last call to `Chip8::ack_dirty`.
+///
+/// The region is given as the rows and byte columns (groups of 8 pixels) that were touched at the
+/// current resolution: every modified byte lies in one of the rows and one of the columns.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Dirty {
+    rows: u64, // One bit per row
+    cols: u16, // One bit per byte column
+}
+
+impl Dirty {
+    /// Whether nothing changed
+    pub fn is_empty(&self) -> bool {
+        self.rows == 0 || self.cols == 0
+    }
+    /// Whether row `y` was touched
+    pub fn row(&self, y: usize) -> bool {
+        self.rows & 1 << y != 0
+    }
+    /// Whether byte column `col`, with pixels `col * 8` to `col * 8 + 7`, was touched
+    pub fn col(&self, col: usize) -> bool {
+        self.cols & 1 << col != 0
+    }
+    /// Whether the byte at byte column `col` of row `y` may have changed
+    pub fn contains(&self, col: usize, y: usize) -> bool {
+        self.row(y) && self.col(col)
+    }
+    /// Touched rows, from the top
+    pub fn rows(&self) -> impl Iterator<Item = usize> {
+        let rows = self.rows;
+        (0..64).filter(move |y| rows & 1 << y != 0)
+    }
+    /// Touched byte columns, from the left
+    pub fn cols(&self) -> impl Iterator<Item = usize> {
+        let cols = self.cols;
+        (0..16).filter(move |col| cols & 1 << col != 0)
+    }
+    /// Union of two regions
+    pub fn union(self, other: Dirty) -> Dirty {
+        Dirty {
+            rows: self.rows | other.rows,
+            cols: self.cols | other.cols,
+        }
+    }
+}
+
+/// Iterator over the colors of a run of pixels, in row-major order
+pub struct Pixels<'a> {
+    planes: [&'a [u8]; PLANES],
+    pos: usize, // Bit index of the next pixel
+    end: usize,
+}
+
+impl<'a> Iterator for Pixels<'a> {
+    type Item = u8;
+
+    fn next(&mut self) -> Option<u8> {
+        if self.pos == self.end {
+            return None;
+        }
+        let (byte, mask) = (self.pos / 8, 0x80 >> (self.pos % 8));
+        self.pos += 1;
+        Some(
+            self.planes
+                .iter()
+                .enumerate()
+                .filter(|(_, plane)| plane[byte] & mask != 0)
+                .fold(0, |color, (p, _)| color | 1 << p),
+        )
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.end - self.pos, Some(self.end - self.pos))
+    }
+}
+
+impl<'a> ExactSizeIterator for Pixels<'a> {}
+
+/// Iterator over the rows of the framebuffer, each one an iterator over its pixels
+pub struct Rows<'a> {
+    planes: [&'a [u8]; PLANES],
+    width: usize,
+    y: usize,
+    height: usize,
+}
+
+impl<'a> Iterator for Rows<'a> {
+    type Item = Pixels<'a>;
+
+    fn next(&mut self) -> Option<Pixels<'a>> {
+        if self.y == self.height {
+            return None;
+        }
+        let pos = self.y * self.width;
+        self.y += 1;
+        Some(Pixels {
+            planes: self.planes,
+            pos,
+            end: pos + self.width,
+        })
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.height - self.y, Some(self.height - self.y))
+    }
+}
+
+impl<'a> ExactSizeIterator for Rows<'a> {}
+
+impl<R: RngCore, T: Trap, M: Bus> Chip8<R, T, M> {
+    /// Borrowed view of the lo-res framebuffer
+    pub fn fb_ref(&self) -> &[u8; FB_SIZE] {
+        &self.fb[0]
+    }
+    /// Region of the framebuffer modified since the last call to `ack_dirty`
+    pub fn dirty(&self) -> Dirty {
+        self.dirty
+    }
+    /// Acknowledge the changes to the framebuffer, once the frontend has displayed them.
+    pub fn ack_dirty(&mut self) {
+        self.dirty = Dirty::default();
+    }
+    /// Mark the whole framebuffer at the current resolution as modified.
+    pub(crate) fn touch_all(&mut self) {
+        let (width, height) = self.resolution();
+        self.dirty = Dirty {
+            rows: u64::MAX >> (64 - height),
+            cols: u16::MAX >> (16 - width / 8),
+        };
+    }
+    /// Mark the byte at byte column `col` of row `y` as modified.
+    pub(crate) fn touch(&mut self, col: usize, y: usize) {
+        self.dirty.rows |= 1 << y;
+        self.dirty.cols |= 1 << col;
+    }
+    /// All bit planes at the current resolution
+    fn planes(&self) -> [&[u8]; PLANES] {
+        let mut planes = [&[][..]; PLANES];
+        for (p, plane) in planes.iter_mut().enumerate() {
+            *plane = self.plane(p);
+        }
+        planes
+    }
+    /// Color of the pixel at (x, y) of the current resolution.  Panics if it's out of the screen.
+    pub fn color(&self, x: usize, y: usize) -> u8 {
+        let (width, height) = self.resolution();
+        assert!(x < width && y < height, "pixel out of the screen");
+        let pos = y * width + x;
+        Pixels {
+            planes: self.planes(),
+            pos,
+            end: pos + 1,
+        }
+        .next()
+        .unwrap_or(0)
+    }
+    /// Whether the pixel at (x, y) of the current resolution is lit in any plane.  Panics if it's
+    /// out of the screen.
+    pub fn pixel(&self, x: usize, y: usize) -> bool {
+        self.color(x, y) != 0
+    }
+    /// Colors of all the pixels at the current resolution, in row-major order
+    pub fn pixels(&self) -> Pixels<'_> {
+        let (width, height) = self.resolution();
+        Pixels {
+            planes: self.planes(),
+            pos: 0,
+            end: width * height,
+        }
+    }
+    /// Rows of pixels at the current resolution, from the top
+    pub fn rows(&self) -> Rows<'_> {
+        let (width, height) = self.resolution();
+        Rows {
+            planes: self.planes(),
+            width,
+            y: 0,
+            height,
+        }
+    }
+    /// Write the screen as one bit per pixel, lit in any plane, in row-major order with the
+    /// leftmost pixel in the most significant bit.  Returns the number of bytes written, or None
+    /// if `out` is too small.
+    pub fn to_1bpp(&self, out: &mut [u8]) -> Option<usize> {
+        let (width, height) = self.resolution();
+        let out = out.get_mut(..width * height / 8)?;
+        let planes = self.planes();
+        for (i, byte) in out.iter_mut().enumerate() {
+            *byte = planes.iter().fold(0, |b, plane| b | plane[i]);
+        }
+        Some(out.len())
+    }
+    /// Write the screen as one byte per pixel holding its color.  Returns the number of bytes
+    /// written, or None if `out` is too small.
+    pub fn to_8bpp(&self, out: &mut [u8]) -> Option<usize> {
+        let (width, height) = self.resolution();
+        let out = out.get_mut(..width * height)?;
+        for (byte, color) in out.iter_mut().zip(self.pixels()) {
+            *byte = color;
+        }
+        Some(out.len())
+    }
+    /// Write the screen as three bytes per pixel with the RGB values of its color in `palette`.
+    /// Returns the number of bytes written, or None if `out` is too small.
+    pub fn to_rgb24(&self, palette: &Palette, out: &mut [u8]) -> Option<usize> {
+        let (width, height) = self.resolution();
+        let out = out.get_mut(..width * height * 3)?;
+        for (rgb, color) in out.chunks_exact_mut(3).zip(self.pixels()) {
+            rgb.copy_from_slice(&palette[color as usize]);
+        }
+        Some(out.len())
+    }
+    /// Write the screen as four bytes per pixel with the RGB values of its color in `palette` and
+    /// an opaque alpha.  Returns the number of bytes written, or None if `out` is too small.
+    pub fn to_rgba32(&self, palette: &Palette, out: &mut [u8]) -> Option<usize> {
+        let (width, height) = self.resolution();
+        let out = out.get_mut(..width * height * 4)?;
+        for (rgba, color) in out.chunks_exact_mut(4).zip(self.pixels()) {
+            rgba[..3].copy_from_slice(&palette[color as usize]);
+            rgba[3] = 0xff;
+        }
+        Some(out.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Machine showing "0" at (0, 0) and "1" at (9, 3)
+    fn machine(mode: Mode, hires: bool) -> Chip8 {
+        let mut chip8 = TestMachine::new()
+            .mode(mode)
+            .rom(&[0x00, if hires { 0xff } else { 0xe0 }])
+            .rom(&[
+                0x60, 0x00, 0x61, 0x00, 0xa0, 0x00, 0xd0, 0x15, // "0" at (0, 0)
+                0x60, 0x09, 0x61, 0x03, 0xa0, 0x05, 0xd0, 0x15, // "1" at (9, 3)
+            ])
+            .build();
+        for _ in 0..9 {
+            chip8.step().unwrap();
+        }
+        chip8
+    }
+
+    #[test]
+    fn accessors() {
+        let chip8 = machine(Mode::Chip8, false);
+        assert_eq!(chip8.resolution(), (64, 32));
+        assert!(chip8.pixel(0, 0) && chip8.pixel(3, 0) && !chip8.pixel(4, 0));
+        assert!(!chip8.pixel(1, 1) && chip8.pixel(3, 4));
+        assert!(chip8.pixel(11, 3) && !chip8.pixel(10, 3));
+        assert_eq!(chip8.color(0, 0), 1);
+        assert_eq!(chip8.color(63, 31), 0);
+        // The lo-res framebuffer holds 8 pixels per byte
+        assert_eq!(chip8.fb_ref()[0], 0xf0);
+        assert_eq!(chip8.fb_ref()[3 * 8 + 1], 0x10);
+
+        assert_eq!(chip8.pixels().len(), 64 * 32);
+        let lit: std::vec::Vec<_> = chip8
+            .pixels()
+            .enumerate()
+            .filter(|(_, c)| *c != 0)
+            .map(|(pos, _)| (pos % 64, pos / 64))
+            .collect();
+        // 14 pixels in "0" and 8 in "1"
+        assert_eq!(lit.len(), 22);
+        assert!(lit.iter().all(|&(x, y)| chip8.pixel(x, y)));
+
+        let rows = chip8.rows();
+        assert_eq!(rows.len(), 32);
+        for (y, row) in rows.enumerate() {
+            assert_eq!(row.len(), 64);
+            for (x, color) in row.enumerate() {
+                assert_eq!(color, chip8.color(x, y));
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "pixel out of the screen")]
+    fn out_of_screen() {
+        machine(Mode::Chip8, false).pixel(64, 0);
+    }
+
+    #[test]
+    fn conversions() {
+        let chip8 = machine(Mode::Chip8, false);
+        let mut out = [0xaa; 128 * 64 * 4 + 1];
+
+        assert_eq!(chip8.to_1bpp(&mut out[..255]), None);
+        assert_eq!(chip8.to_1bpp(&mut out), Some(256));
+        assert_eq!(&out[..256], &chip8.fb_ref()[..256]);
+
+        assert_eq!(chip8.to_8bpp(&mut out[..2047]), None);
+        assert_eq!(chip8.to_8bpp(&mut out), Some(2048));
+        assert!(out[..2048].iter().zip(chip8.pixels()).all(|(&b, c)| b == c));
+        assert_eq!(out[2048], 0xaa);
+
+        let mut palette = [[0; 3]; COLORS];
+        palette[0] = [1, 2, 3];
+        palette[1] = [4, 5, 6];
+        assert_eq!(chip8.to_rgb24(&palette, &mut out[..100]), None);
+        assert_eq!(chip8.to_rgb24(&palette, &mut out), Some(2048 * 3));
+        assert_eq!(&out[..9], &[4, 5, 6, 4, 5, 6, 4, 5, 6]);
+        assert_eq!(&out[4 * 3..5 * 3], &[1, 2, 3]);
+
+        assert_eq!(chip8.to_rgba32(&palette, &mut out[..100]), None);
+        assert_eq!(chip8.to_rgba32(&palette, &mut out), Some(2048 * 4));
+        assert_eq!(&out[..8], &[4, 5, 6, 0xff, 4, 5, 6, 0xff]);
+        assert_eq!(&out[4 * 4..5 * 4], &[1, 2, 3, 0xff]);
+    }
+
+    #[test]
+    fn hires() {
+        let chip8 = machine(Mode::SuperChip, true);
+        assert_eq!(chip8.resolution(), (128, 64));
+        assert_eq!(chip8.pixels().len(), 128 * 64);
+        assert_eq!(chip8.rows().len(), 64);
+        assert!(chip8.pixel(0, 0) && chip8.pixel(11, 3) && !chip8.pixel(127, 63));
+        let mut out = [0; 128 * 64 * 4];
+        assert_eq!(chip8.to_1bpp(&mut out), Some(1024));
+        assert_eq!(chip8.to_8bpp(&mut out), Some(128 * 64));
+        assert_eq!(out[128 * 3 + 11], 1);
+        let palette = [[0xff; 3]; COLORS];
+        assert_eq!(chip8.to_rgba32(&palette, &mut out), Some(128 * 64 * 4));
+    }
+
+    /// Region touched by drawing "0" at (x, y) in lo-res, clipped at the edges if `clip` is set
+    fn drawn(clip: bool, x: u8, y: u8) -> Dirty {
+        let quirks = Quirks {
+            clip_sprites: clip,
+            ..Quirks::default()
+        };
+        let mut chip8 = TestMachine::new()
+            .quirks(quirks)
+            .rom(&[0x60, x, 0x61, y, 0xd0, 0x15])
+            .build();
+        chip8.ack_dirty();
+        for _ in 0..3 {
+            chip8.step().unwrap();
+        }
+        chip8.dirty()
+    }
+
+    #[test]
+    fn dirty() {
+        let d = drawn(false, 8, 3);
+        assert!(!d.is_empty());
+        assert_eq!(d.rows().collect::<std::vec::Vec<_>>(), [3, 4, 5, 6, 7]);
+        assert_eq!(d.cols().collect::<std::vec::Vec<_>>(), [1]);
+        assert!(d.row(3) && !d.row(2) && !d.row(8));
+        assert!(d.col(1) && !d.col(0) && !d.col(2));
+        assert!(d.contains(1, 7) && !d.contains(2, 7));
+
+        // Unaligned sprites spill into the next column, wrapping around the edges
+        let d = drawn(false, 9, 0);
+        assert_eq!(d.cols().collect::<std::vec::Vec<_>>(), [1, 2]);
+        let d = drawn(false, 60, 30);
+        assert_eq!(d.cols().collect::<std::vec::Vec<_>>(), [0, 7]);
+        assert_eq!(d.rows().collect::<std::vec::Vec<_>>(), [0, 1, 2, 30, 31]);
+        // Clipped sprites don't touch the column they would have wrapped into
+        let d = drawn(true, 60, 30);
+        assert_eq!(d.cols().collect::<std::vec::Vec<_>>(), [7]);
+        assert_eq!(d.rows().collect::<std::vec::Vec<_>>(), [30, 31]);
+
+        let u = drawn(false, 8, 3).union(drawn(false, 60, 30));
+        assert!(u.contains(1, 3) && u.contains(7, 31));
+        assert_eq!(u.rows().count(), 10);
+        assert_eq!(Dirty::default().union(u), u);
+        assert!(Dirty::default().is_empty());
+    }
+
+    #[test]
+    fn dirty_screen() {
+        // CLS; HIGH; SCD 1; LOW
+        let mut chip8 = TestMachine::new()
+            .mode(Mode::SuperChip)
+            .rom(&[0x00, 0xe0, 0x00, 0xff, 0x00, 0xc1, 0x00, 0xfe])
+            .build();
+        chip8.ack_dirty();
+        assert!(chip8.dirty().is_empty());
+        let region = |c: &Chip8| (c.dirty().rows().count(), c.dirty().cols().count());
+        // Whole screen operations touch the whole screen at the current resolution
+        chip8.step().unwrap();
+        assert_eq!(region(&chip8), (32, 8));
+        chip8.ack_dirty();
+        chip8.step().unwrap();
+        assert_eq!(region(&chip8), (64, 16));
+        chip8.ack_dirty();
+        chip8.step().unwrap();
+        assert_eq!(region(&chip8), (64, 16));
+        chip8.ack_dirty();
+        chip8.step().unwrap();
+        assert_eq!(region(&chip8), (32, 8));
+        chip8.ack_dirty();
+        assert!(chip8.dirty().is_empty());
+    }
+}
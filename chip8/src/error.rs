@@ -0,0 +1,198 @@
+//! Errors and the machine state captured when execution faults.
+
+use core::fmt;
+
+use super::*;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The opcode at the PC is not valid in the current mode.
+    InvalidOp(Fault),
+    RomTooBig(usize),
+    /// The PC points outside of memory.
+    PcOutOfBounds(Fault),
+    StateBufferTooSmall(usize),
+    InvalidState,
+    /// The call at `pc` exceeded `Quirks::stack_depth`.
+    StackOverflow {
+        pc: u16,
+        fault: Fault,
+    },
+    /// The return at `pc` found the call stack empty.
+    StackUnderflow {
+        pc: u16,
+        fault: Fault,
+    },
+    /// An access to `addr` past the end of memory with `MemoryPolicy::Error`.
+    MemoryOutOfBounds {
+        addr: usize,
+        fault: Fault,
+    },
+    Debug(Break),
+    InvalidMovie,
+    /// The movie was recorded with another rom.
+    MovieRomMismatch,
+    /// The buffer of a movie recording is full.
+    MovieBufferFull,
+    /// The machine doesn't match the checksum recorded after this frame of the movie.
+    MovieDesync(u32),
+}
+
+impl Error {
+    /// State of the machine at the faulting instruction, for errors raised by the execution
+    pub fn fault(&self) -> Option<&Fault> {
+        match self {
+            Error::InvalidOp(fault)
+            | Error::PcOutOfBounds(fault)
+            | Error::StackOverflow { fault, .. }
+            | Error::StackUnderflow { fault, .. }
+            | Error::MemoryOutOfBounds { fault, .. } => Some(fault),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::InvalidOp(fault) => match fault.opcode {
+                Some(opcode) => write!(f, "invalid opcode {:04X} at 0x{:04X}", opcode, fault.pc),
+                None => write!(f, "invalid opcode at 0x{:04X}", fault.pc),
+            },
+            Error::RomTooBig(len) => write!(f, "rom of {} bytes doesn't fit in memory", len),
+            Error::PcOutOfBounds(fault) => write!(f, "PC out of bounds at 0x{:04X}", fault.pc),
+            Error::StateBufferTooSmall(len) => {
+                write!(f, "buffer of {} bytes is too small for a save state", len)
+            }
+            Error::InvalidState => write!(f, "invalid save state"),
+            Error::StackOverflow { pc, .. } => write!(f, "stack overflow at 0x{:04X}", pc),
+            Error::StackUnderflow { pc, .. } => write!(f, "stack underflow at 0x{:04X}", pc),
+            Error::MemoryOutOfBounds { addr, fault } => write!(
+                f,
+                "memory access to 0x{:X} out of bounds at 0x{:04X}",
+                addr, fault.pc
+            ),
+            Error::Debug(b) => write!(f, "debugger break: {:?}", b),
+            Error::InvalidMovie => write!(f, "invalid movie"),
+            Error::MovieRomMismatch => write!(f, "movie was recorded with a different rom"),
+            Error::MovieBufferFull => write!(f, "movie buffer is full"),
+            Error::MovieDesync(frame) => write!(f, "movie desynchronized at frame {}", frame),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for Error {}
+
+/// Snapshot of the machine taken at the instruction that caused an error.  Its `Display`
+/// implementation prints a crash report.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fault {
+    /// Address of the faulting instruction
+    pub pc: u16,
+    /// Opcode at the PC, if the PC is in memory
+    pub opcode: Option<u16>,
+    /// Decoded instruction, if the opcode is valid
+    pub instruction: Option<Instruction>,
+    /// Register Set V0 through VF
+    pub v: [u8; 0x10],
+    /// Index Register
+    pub i: u16,
+    stack: [u16; MAX_STACK_DEPTH],
+    sp: u8,
+}
+
+impl Fault {
+    /// Return addresses in the call stack, oldest first
+    pub fn stack(&self) -> &[u16] {
+        &self.stack[..self.sp as usize]
+    }
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PC: 0x{:04X}", self.pc)?;
+        if let Some(opcode) = self.opcode {
+            write!(f, "  opcode: {:04X}", opcode)?;
+        }
+        match self.instruction {
+            Some(inst) => writeln!(f, "  {}", inst)?,
+            None => writeln!(f)?,
+        }
+        write!(f, "I: 0x{:04X}", self.i)?;
+        for (x, v) in self.v.iter().enumerate() {
+            if x % 8 == 0 {
+                writeln!(f)?;
+            } else {
+                write!(f, " ")?;
+            }
+            write!(f, "V{:X}: {:02X}", x, v)?;
+        }
+        writeln!(f)?;
+        write!(f, "Call stack:")?;
+        if self.sp == 0 {
+            write!(f, " empty")?;
+        }
+        // The most recent call first, at the address before its return address
+        for addr in self.stack().iter().rev() {
+            write!(f, "\n  0x{:04X}", addr.wrapping_sub(2))?;
+        }
+        Ok(())
+    }
+}
+
+impl<R: RngCore, T: Trap, M: Bus> Chip8<R, T, M> {
+    /// Snapshot of the machine at the current instruction
+    pub(crate) fn fault(&self) -> Fault {
+        let pc = self.pc as usize;
+        let mut buf = [0; 4];
+        let len = MEM_SIZE.saturating_sub(pc).min(4);
+        for (i, b) in buf[..len].iter_mut().enumerate() {
+            *b = self.mem.peek(pc + i);
+        }
+        let bytes = &buf[..len];
+        Fault {
+            pc: self.pc,
+            opcode: match bytes {
+                [w0, w1, ..] => Some((*w0 as u16) << 8 | *w1 as u16),
+                _ => None,
+            },
+            instruction: Instruction::decode_bytes(bytes),
+            v: self.v.0,
+            i: self.i,
+            stack: self.stack,
+            sp: self.sp,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::string::ToString;
+
+    #[test]
+    fn stack_errors() {
+        let quirks = Quirks {
+            stack_depth: 2,
+            ..Quirks::default()
+        };
+        // Recursive call at 0x202
+        let mut chip8 = TestMachine::new()
+            .quirks(quirks)
+            .words(&[0x00e0, 0x2202])
+            .build();
+        let err = chip8.frame(0).unwrap_err();
+        assert!(matches!(err, Error::StackOverflow { pc: 0x202, .. }));
+        assert_eq!(err.fault().map(|f| f.stack().len()), Some(2));
+        assert_eq!(err.to_string(), "stack overflow at 0x0202");
+
+        let mut chip8 = TestMachine::new()
+            .quirks(quirks)
+            .words(&[0x00e0, 0x00ee])
+            .build();
+        let err = chip8.frame(0).unwrap_err();
+        assert!(matches!(err, Error::StackUnderflow { pc: 0x202, .. }));
+        assert_eq!(err.to_string(), "stack underflow at 0x0202");
+    }
+}
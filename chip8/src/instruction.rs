@@ -0,0 +1,666 @@
+//! Instruction decoding, encoding and disassembly.
+//!
+//! Mnemonics follow Cowgod's Chip-8 Technical Reference, extended with the SUPER-CHIP names used
+//! by the CHIPPER assembler, or the Octo assembly language syntax.
+
+use core::fmt;
+
+/// A decoded instruction.  `x` and `y` are register numbers, `kk` an 8 bit immediate value, `n`
+/// a 4 bit immediate value and `addr` a 12 bit address (16 bit for `LdILong`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Instruction {
+    /// 0nnn: Call machine code routine at addr.
+    Sys(u16),
+    /// 00E0: Clear the display.
+    Cls,
+    /// 00EE: Return from a subroutine.
+    Ret,
+    /// 00Cn: Scroll display n lines down (SUPER-CHIP).
+    Scd(u8),
+    /// 00Dn: Scroll display n lines up (XO-CHIP).
+    Scu(u8),
+    /// 00FB: Scroll display 4 pixels right (SUPER-CHIP).
+    Scr,
+    /// 00FC: Scroll display 4 pixels left (SUPER-CHIP).
+    Scl,
+    /// 00FD: Exit the interpreter (SUPER-CHIP).
+    Exit,
+    /// 00FE: Disable the hi-res display (SUPER-CHIP).
+    Low,
+    /// 00FF: Enable the hi-res display (SUPER-CHIP).
+    High,
+    /// 1nnn: Jump to addr.
+    Jp(u16),
+    /// 2nnn: Call subroutine at addr.
+    Call(u16),
+    /// 3xkk: Skip next instruction if Vx == kk.
+    SeByte(u8, u8),
+    /// 4xkk: Skip next instruction if Vx != kk.
+    SneByte(u8, u8),
+    /// 5xy0: Skip next instruction if Vx == Vy.
+    SeReg(u8, u8),
+    /// 5xy2: Store registers Vx through Vy in memory starting at location I (XO-CHIP).
+    Save(u8, u8),
+    /// 5xy3: Read registers Vx through Vy from memory starting at location I (XO-CHIP).
+    Load(u8, u8),
+    /// 6xkk: Set Vx = kk.
+    LdByte(u8, u8),
+    /// 7xkk: Set Vx = Vx + kk.
+    AddByte(u8, u8),
+    /// 8xy0: Set Vx = Vy.
+    LdReg(u8, u8),
+    /// 8xy1: Set Vx = Vx OR Vy.
+    Or(u8, u8),
+    /// 8xy2: Set Vx = Vx AND Vy.
+    And(u8, u8),
+    /// 8xy3: Set Vx = Vx XOR Vy.
+    Xor(u8, u8),
+    /// 8xy4: Set Vx = Vx + Vy, set VF = carry.
+    AddReg(u8, u8),
+    /// 8xy5: Set Vx = Vx - Vy, set VF = NOT borrow.
+    Sub(u8, u8),
+    /// 8xy6: Set Vx = Vx SHR 1.
+    Shr(u8, u8),
+    /// 8xy7: Set Vx = Vy - Vx, set VF = NOT borrow.
+    Subn(u8, u8),
+    /// 8xyE: Set Vx = Vx SHL 1.
+    Shl(u8, u8),
+    /// 9xy0: Skip next instruction if Vx != Vy.
+    SneReg(u8, u8),
+    /// Annn: Set I = addr.
+    LdI(u16),
+    /// Bnnn: Jump to addr + V0.
+    JpV0(u16),
+    /// Cxkk: Set Vx = random byte AND kk.
+    Rnd(u8, u8),
+    /// Dxyn: Display n-byte sprite starting at memory location I at (Vx, Vy), set VF = collision.
+    Drw(u8, u8, u8),
+    /// Ex9E: Skip next instruction if key with the value of Vx is pressed.
+    Skp(u8),
+    /// ExA1: Skip next instruction if key with the value of Vx is not pressed.
+    Sknp(u8),
+    /// F000 nnnn: Set I = the 16 bit addr that follows (XO-CHIP).
+    LdILong(u16),
+    /// Fn01: Select the bit planes n (XO-CHIP).
+    Plane(u8),
+    /// F002: Load 16 bytes starting at I into the audio pattern buffer (XO-CHIP).
+    Audio,
+    /// Fx07: Set Vx = delay timer value.
+    LdVxDt(u8),
+    /// Fx0A: Wait for a key press, store the value of the key in Vx.
+    LdVxK(u8),
+    /// Fx15: Set delay timer = Vx.
+    LdDtVx(u8),
+    /// Fx18: Set sound timer = Vx.
+    LdStVx(u8),
+    /// Fx1E: Set I = I + Vx.
+    AddI(u8),
+    /// Fx29: Set I = location of sprite for digit Vx.
+    LdF(u8),
+    /// Fx30: Set I = location of big sprite for digit Vx (SUPER-CHIP).
+    LdHf(u8),
+    /// Fx33: Store BCD representation of Vx in memory locations I, I+1, and I+2.
+    LdB(u8),
+    /// Fx3A: Set the audio pattern pitch = Vx (XO-CHIP).
+    Pitch(u8),
+    /// Fx55: Store registers V0 through Vx in memory starting at location I.
+    LdIVx(u8),
+    /// Fx65: Read registers V0 through Vx from memory starting at location I.
+    LdVxI(u8),
+    /// Fx75: Store registers V0 through Vx in RPL user flags (SUPER-CHIP).
+    LdRVx(u8),
+    /// Fx85: Read registers V0 through Vx from RPL user flags (SUPER-CHIP).
+    LdVxR(u8),
+}
+
+/// Assembly language syntax of the mnemonics
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Syntax {
+    /// Cowgod's Chip-8 Technical Reference: `LD V1, 0x0A`
+    Cowgod,
+    /// Octo assembly language: `v1 := 0x0A`
+    Octo,
+}
+
+impl Instruction {
+    /// Decode an instruction from its opcode.  Returns None for invalid opcodes and for `F000`,
+    /// which takes a second word: use `decode_bytes` to decode it.
+    pub fn decode(op: u16) -> Option<Self> {
+        use Instruction::*;
+        let x = ((op & 0x0f00) >> 8) as u8;
+        let y = ((op & 0x00f0) >> 4) as u8;
+        let n = (op & 0x000f) as u8;
+        let kk = (op & 0x00ff) as u8;
+        let nnn = op & 0x0fff;
+        Some(match op & 0xf000 {
+            0x0000 => match op {
+                0x00E0 => Cls,
+                0x00EE => Ret,
+                0x00C0..=0x00CF => Scd(n),
+                0x00D0..=0x00DF => Scu(n),
+                0x00FB => Scr,
+                0x00FC => Scl,
+                0x00FD => Exit,
+                0x00FE => Low,
+                0x00FF => High,
+                _ => Sys(nnn),
+            },
+            0x1000 => Jp(nnn),
+            0x2000 => Call(nnn),
+            0x3000 => SeByte(x, kk),
+            0x4000 => SneByte(x, kk),
+            0x5000 => match n {
+                0x0 => SeReg(x, y),
+                0x2 => Save(x, y),
+                0x3 => Load(x, y),
+                _ => return None,
+            },
+            0x6000 => LdByte(x, kk),
+            0x7000 => AddByte(x, kk),
+            0x8000 => match n {
+                0x0 => LdReg(x, y),
+                0x1 => Or(x, y),
+                0x2 => And(x, y),
+                0x3 => Xor(x, y),
+                0x4 => AddReg(x, y),
+                0x5 => Sub(x, y),
+                0x6 => Shr(x, y),
+                0x7 => Subn(x, y),
+                0xE => Shl(x, y),
+                _ => return None,
+            },
+            0x9000 => match n {
+                0x0 => SneReg(x, y),
+                _ => return None,
+            },
+            0xA000 => LdI(nnn),
+            0xB000 => JpV0(nnn),
+            0xC000 => Rnd(x, kk),
+            0xD000 => Drw(x, y, n),
+            0xE000 => match kk {
+                0x9E => Skp(x),
+                0xA1 => Sknp(x),
+                _ => return None,
+            },
+            _ => match kk {
+                0x00 => return None,
+                0x01 => Plane(x),
+                0x02 if x == 0 => Audio,
+                0x07 => LdVxDt(x),
+                0x0A => LdVxK(x),
+                0x15 => LdDtVx(x),
+                0x18 => LdStVx(x),
+                0x1E => AddI(x),
+                0x29 => LdF(x),
+                0x30 => LdHf(x),
+                0x33 => LdB(x),
+                0x3A => Pitch(x),
+                0x55 => LdIVx(x),
+                0x65 => LdVxI(x),
+                0x75 => LdRVx(x),
+                0x85 => LdVxR(x),
+                _ => return None,
+            },
+        })
+    }
+
+    /// Decode the instruction at the start of `bytes`.  Returns None for invalid opcodes and
+    /// truncated instructions.
+    pub fn decode_bytes(bytes: &[u8]) -> Option<Self> {
+        match bytes {
+            [0xF0, 0x00, hi, lo, ..] => Some(Instruction::LdILong((*hi as u16) << 8 | *lo as u16)),
+            [0xF0, 0x00, ..] => None,
+            [w0, w1, ..] => Self::decode((*w0 as u16) << 8 | *w1 as u16),
+            _ => None,
+        }
+    }
+
+    /// Opcode of the instruction.  For `LdILong` this is the `F000` prefix, followed in memory by
+    /// the address.
+    pub fn encode(&self) -> u16 {
+        use Instruction::*;
+        let xy =
+            |op: u16, x: u8, y: u8, n: u16| op | (x as u16 & 0xf) << 8 | (y as u16 & 0xf) << 4 | n;
+        let xkk = |op: u16, x: u8, kk: u8| op | (x as u16 & 0xf) << 8 | kk as u16;
+        let fx = |x: u8, kk: u16| 0xF000 | (x as u16 & 0xf) << 8 | kk;
+        match *self {
+            Sys(addr) => addr & 0x0fff,
+            Cls => 0x00E0,
+            Ret => 0x00EE,
+            Scd(n) => 0x00C0 | (n as u16 & 0xf),
+            Scu(n) => 0x00D0 | (n as u16 & 0xf),
+            Scr => 0x00FB,
+            Scl => 0x00FC,
+            Exit => 0x00FD,
+            Low => 0x00FE,
+            High => 0x00FF,
+            Jp(addr) => 0x1000 | addr & 0x0fff,
+            Call(addr) => 0x2000 | addr & 0x0fff,
+            SeByte(x, kk) => xkk(0x3000, x, kk),
+            SneByte(x, kk) => xkk(0x4000, x, kk),
+            SeReg(x, y) => xy(0x5000, x, y, 0x0),
+            Save(x, y) => xy(0x5000, x, y, 0x2),
+            Load(x, y) => xy(0x5000, x, y, 0x3),
+            LdByte(x, kk) => xkk(0x6000, x, kk),
+            AddByte(x, kk) => xkk(0x7000, x, kk),
+            LdReg(x, y) => xy(0x8000, x, y, 0x0),
+            Or(x, y) => xy(0x8000, x, y, 0x1),
+            And(x, y) => xy(0x8000, x, y, 0x2),
+            Xor(x, y) => xy(0x8000, x, y, 0x3),
+            AddReg(x, y) => xy(0x8000, x, y, 0x4),
+            Sub(x, y) => xy(0x8000, x, y, 0x5),
+            Shr(x, y) => xy(0x8000, x, y, 0x6),
+            Subn(x, y) => xy(0x8000, x, y, 0x7),
+            Shl(x, y) => xy(0x8000, x, y, 0xE),
+            SneReg(x, y) => xy(0x9000, x, y, 0x0),
+            LdI(addr) => 0xA000 | addr & 0x0fff,
+            JpV0(addr) => 0xB000 | addr & 0x0fff,
+            Rnd(x, kk) => xkk(0xC000, x, kk),
+            Drw(x, y, n) => xy(0xD000, x, y, n as u16 & 0xf),
+            Skp(x) => xkk(0xE000, x, 0x9E),
+            Sknp(x) => xkk(0xE000, x, 0xA1),
+            LdILong(_) => 0xF000,
+            Plane(n) => fx(n, 0x01),
+            Audio => 0xF002,
+            LdVxDt(x) => fx(x, 0x07),
+            LdVxK(x) => fx(x, 0x0A),
+            LdDtVx(x) => fx(x, 0x15),
+            LdStVx(x) => fx(x, 0x18),
+            AddI(x) => fx(x, 0x1E),
+            LdF(x) => fx(x, 0x29),
+            LdHf(x) => fx(x, 0x30),
+            LdB(x) => fx(x, 0x33),
+            Pitch(x) => fx(x, 0x3A),
+            LdIVx(x) => fx(x, 0x55),
+            LdVxI(x) => fx(x, 0x65),
+            LdRVx(x) => fx(x, 0x75),
+            LdVxR(x) => fx(x, 0x85),
+        }
+    }
+
+    /// Length of the instruction in bytes
+    pub fn size(&self) -> usize {
+        match self {
+            Instruction::LdILong(_) => 4,
+            _ => 2,
+        }
+    }
+
+    /// Write the instruction at the start of `buf`, which must be at least `size()` bytes long.
+    pub fn encode_bytes(&self, buf: &mut [u8]) {
+        buf[..2].copy_from_slice(&self.encode().to_be_bytes());
+        if let Instruction::LdILong(addr) = self {
+            buf[2..4].copy_from_slice(&addr.to_be_bytes());
+        }
+    }
+
+    /// Display the instruction with the mnemonics of `syntax`
+    pub fn display(&self, syntax: Syntax) -> Display<'_> {
+        Display { inst: self, syntax }
+    }
+
+    fn fmt_cowgod(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use Instruction::*;
+        match *self {
+            Sys(addr) => write!(f, "SYS 0x{:03X}", addr),
+            Cls => write!(f, "CLS"),
+            Ret => write!(f, "RET"),
+            Scd(n) => write!(f, "SCD {}", n),
+            Scu(n) => write!(f, "SCU {}", n),
+            Scr => write!(f, "SCR"),
+            Scl => write!(f, "SCL"),
+            Exit => write!(f, "EXIT"),
+            Low => write!(f, "LOW"),
+            High => write!(f, "HIGH"),
+            Jp(addr) => write!(f, "JP 0x{:03X}", addr),
+            Call(addr) => write!(f, "CALL 0x{:03X}", addr),
+            SeByte(x, kk) => write!(f, "SE V{:X}, 0x{:02X}", x, kk),
+            SneByte(x, kk) => write!(f, "SNE V{:X}, 0x{:02X}", x, kk),
+            SeReg(x, y) => write!(f, "SE V{:X}, V{:X}", x, y),
+            Save(x, y) => write!(f, "SAVE V{:X}, V{:X}", x, y),
+            Load(x, y) => write!(f, "LOAD V{:X}, V{:X}", x, y),
+            LdByte(x, kk) => write!(f, "LD V{:X}, 0x{:02X}", x, kk),
+            AddByte(x, kk) => write!(f, "ADD V{:X}, 0x{:02X}", x, kk),
+            LdReg(x, y) => write!(f, "LD V{:X}, V{:X}", x, y),
+            Or(x, y) => write!(f, "OR V{:X}, V{:X}", x, y),
+            And(x, y) => write!(f, "AND V{:X}, V{:X}", x, y),
+            Xor(x, y) => write!(f, "XOR V{:X}, V{:X}", x, y),
+            AddReg(x, y) => write!(f, "ADD V{:X}, V{:X}", x, y),
+            Sub(x, y) => write!(f, "SUB V{:X}, V{:X}", x, y),
+            Shr(x, y) => write!(f, "SHR V{:X}, V{:X}", x, y),
+            Subn(x, y) => write!(f, "SUBN V{:X}, V{:X}", x, y),
+            Shl(x, y) => write!(f, "SHL V{:X}, V{:X}", x, y),
+            SneReg(x, y) => write!(f, "SNE V{:X}, V{:X}", x, y),
+            LdI(addr) => write!(f, "LD I, 0x{:03X}", addr),
+            JpV0(addr) => write!(f, "JP V0, 0x{:03X}", addr),
+            Rnd(x, kk) => write!(f, "RND V{:X}, 0x{:02X}", x, kk),
+            Drw(x, y, n) => write!(f, "DRW V{:X}, V{:X}, {}", x, y, n),
+            Skp(x) => write!(f, "SKP V{:X}", x),
+            Sknp(x) => write!(f, "SKNP V{:X}", x),
+            LdILong(addr) => write!(f, "LD I, LONG 0x{:04X}", addr),
+            Plane(n) => write!(f, "PLANE {}", n),
+            Audio => write!(f, "AUDIO"),
+            LdVxDt(x) => write!(f, "LD V{:X}, DT", x),
+            LdVxK(x) => write!(f, "LD V{:X}, K", x),
+            LdDtVx(x) => write!(f, "LD DT, V{:X}", x),
+            LdStVx(x) => write!(f, "LD ST, V{:X}", x),
+            AddI(x) => write!(f, "ADD I, V{:X}", x),
+            LdF(x) => write!(f, "LD F, V{:X}", x),
+            LdHf(x) => write!(f, "LD HF, V{:X}", x),
+            LdB(x) => write!(f, "LD B, V{:X}", x),
+            Pitch(x) => write!(f, "PITCH V{:X}", x),
+            LdIVx(x) => write!(f, "LD [I], V{:X}", x),
+            LdVxI(x) => write!(f, "LD V{:X}, [I]", x),
+            LdRVx(x) => write!(f, "LD R, V{:X}", x),
+            LdVxR(x) => write!(f, "LD V{:X}, R", x),
+        }
+    }
+
+    fn fmt_octo(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use Instruction::*;
+        match *self {
+            Sys(addr) => write!(f, "0x{:02X} 0x{:02X}", addr >> 8, addr & 0xff),
+            Cls => write!(f, "clear"),
+            Ret => write!(f, "return"),
+            Scd(n) => write!(f, "scroll-down {}", n),
+            Scu(n) => write!(f, "scroll-up {}", n),
+            Scr => write!(f, "scroll-right"),
+            Scl => write!(f, "scroll-left"),
+            Exit => write!(f, "exit"),
+            Low => write!(f, "lores"),
+            High => write!(f, "hires"),
+            Jp(addr) => write!(f, "jump 0x{:03X}", addr),
+            Call(addr) => write!(f, ":call 0x{:03X}", addr),
+            SeByte(x, kk) => write!(f, "if v{:x} != 0x{:02X} then", x, kk),
+            SneByte(x, kk) => write!(f, "if v{:x} == 0x{:02X} then", x, kk),
+            SeReg(x, y) => write!(f, "if v{:x} != v{:x} then", x, y),
+            Save(x, y) => write!(f, "save v{:x} - v{:x}", x, y),
+            Load(x, y) => write!(f, "load v{:x} - v{:x}", x, y),
+            LdByte(x, kk) => write!(f, "v{:x} := 0x{:02X}", x, kk),
+            AddByte(x, kk) => write!(f, "v{:x} += 0x{:02X}", x, kk),
+            LdReg(x, y) => write!(f, "v{:x} := v{:x}", x, y),
+            Or(x, y) => write!(f, "v{:x} |= v{:x}", x, y),
+            And(x, y) => write!(f, "v{:x} &= v{:x}", x, y),
+            Xor(x, y) => write!(f, "v{:x} ^= v{:x}", x, y),
+            AddReg(x, y) => write!(f, "v{:x} += v{:x}", x, y),
+            Sub(x, y) => write!(f, "v{:x} -= v{:x}", x, y),
+            Shr(x, y) => write!(f, "v{:x} >>= v{:x}", x, y),
+            Subn(x, y) => write!(f, "v{:x} =- v{:x}", x, y),
+            Shl(x, y) => write!(f, "v{:x} <<= v{:x}", x, y),
+            SneReg(x, y) => write!(f, "if v{:x} == v{:x} then", x, y),
+            LdI(addr) => write!(f, "i := 0x{:03X}", addr),
+            JpV0(addr) => write!(f, "jump0 0x{:03X}", addr),
+            Rnd(x, kk) => write!(f, "v{:x} := random 0x{:02X}", x, kk),
+            Drw(x, y, n) => write!(f, "sprite v{:x} v{:x} {}", x, y, n),
+            Skp(x) => write!(f, "if v{:x} -key then", x),
+            Sknp(x) => write!(f, "if v{:x} key then", x),
+            LdILong(addr) => write!(f, "i := long 0x{:04X}", addr),
+            Plane(n) => write!(f, "plane {}", n),
+            Audio => write!(f, "audio"),
+            LdVxDt(x) => write!(f, "v{:x} := delay", x),
+            LdVxK(x) => write!(f, "v{:x} := key", x),
+            LdDtVx(x) => write!(f, "delay := v{:x}", x),
+            LdStVx(x) => write!(f, "buzzer := v{:x}", x),
+            AddI(x) => write!(f, "i += v{:x}", x),
+            LdF(x) => write!(f, "i := hex v{:x}", x),
+            LdHf(x) => write!(f, "i := bighex v{:x}", x),
+            LdB(x) => write!(f, "bcd v{:x}", x),
+            Pitch(x) => write!(f, "pitch := v{:x}", x),
+            LdIVx(x) => write!(f, "save v{:x}", x),
+            LdVxI(x) => write!(f, "load v{:x}", x),
+            LdRVx(x) => write!(f, "saveflags v{:x}", x),
+            LdVxR(x) => write!(f, "loadflags v{:x}", x),
+        }
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.fmt_cowgod(f)
+    }
+}
+
+/// Helper to display an instruction with the mnemonics of a `Syntax`
+pub struct Display<'a> {
+    inst: &'a Instruction,
+    syntax: Syntax,
+}
+
+impl<'a> fmt::Display for Display<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.syntax {
+            Syntax::Cowgod => self.inst.fmt_cowgod(f),
+            Syntax::Octo => self.inst.fmt_octo(f),
+        }
+    }
+}
+
+/// A disassembled instruction, or data that doesn't decode as an instruction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Line {
+    /// Address of the first byte
+    pub addr: u16,
+    /// Raw bytes, of which the first `len` are used
+    pub bytes: [u8; 4],
+    /// Number of bytes
+    pub len: usize,
+    /// Decoded instruction, if valid
+    pub instruction: Option<Instruction>,
+}
+
+impl Line {
+    /// Display the line with the mnemonics of `syntax`
+    pub fn display(&self, syntax: Syntax) -> DisplayLine<'_> {
+        DisplayLine { line: self, syntax }
+    }
+}
+
+impl fmt::Display for Line {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.display(Syntax::Cowgod).fmt(f)
+    }
+}
+
+/// Helper to display a disassembled line with the mnemonics of a `Syntax`
+pub struct DisplayLine<'a> {
+    line: &'a Line,
+    syntax: Syntax,
+}
+
+impl<'a> fmt::Display for DisplayLine<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let line = self.line;
+        write!(f, "{:04X}: ", line.addr)?;
+        for i in 0..4 {
+            if i < line.len {
+                write!(f, "{:02X}", line.bytes[i])?;
+            } else {
+                write!(f, "  ")?;
+            }
+        }
+        write!(f, "  ")?;
+        match (line.instruction, self.syntax) {
+            (Some(inst), syntax) => inst.display(syntax).fmt(f),
+            (None, Syntax::Cowgod) => {
+                write!(f, "DB")?;
+                for (i, b) in line.bytes[..line.len].iter().enumerate() {
+                    write!(f, "{} 0x{:02X}", if i == 0 { "" } else { "," }, b)?;
+                }
+                Ok(())
+            }
+            (None, Syntax::Octo) => {
+                for (i, b) in line.bytes[..line.len].iter().enumerate() {
+                    write!(f, "{}0x{:02X}", if i == 0 { "" } else { " " }, b)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Iterator over the disassembled lines of a rom
+pub struct Disassemble<'a> {
+    rom: &'a [u8],
+    pos: usize,
+    origin: u16,
+}
+
+impl<'a> Iterator for Disassemble<'a> {
+    type Item = Line;
+
+    fn next(&mut self) -> Option<Line> {
+        let rest = &self.rom[self.pos..];
+        if rest.is_empty() {
+            return None;
+        }
+        let instruction = Instruction::decode_bytes(rest);
+        let len = match instruction {
+            Some(inst) => inst.size(),
+            None => rest.len().min(2),
+        };
+        let mut bytes = [0; 4];
+        bytes[..len].copy_from_slice(&rest[..len]);
+        let line = Line {
+            addr: self.origin.wrapping_add(self.pos as u16),
+            bytes,
+            len,
+            instruction,
+        };
+        self.pos += len;
+        Some(line)
+    }
+}
+
+/// Disassemble `rom`, loaded at address `origin`, linearly from its first byte
+pub fn disassemble(rom: &[u8], origin: u16) -> Disassemble<'_> {
+    Disassemble {
+        rom,
+        pos: 0,
+        origin,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::string::ToString;
+    use std::vec::Vec;
+    use Instruction::*;
+
+    #[test]
+    fn round_trip() {
+        let mut valid = 0;
+        for op in 0..=0xffff {
+            if let Some(inst) = Instruction::decode(op) {
+                assert_eq!(inst.encode(), op, "{:04X} {:?}", op, inst);
+                let mut buf = [0; 4];
+                inst.encode_bytes(&mut buf);
+                assert_eq!(Instruction::decode_bytes(&buf), Some(inst));
+                valid += 1;
+            }
+        }
+        // Invalid: 5xyN with 13 values of N, 8xyN with 7, 9xyN with 15, ExKK with 254 values of
+        // KK and FxKK with 242, except for F002
+        let invalid = 0x100 * (13 + 7 + 15) + 0x10 * (254 + 242) - 1;
+        assert_eq!(valid, 0x10000 - invalid);
+
+        let inst = LdILong(0xabcd);
+        let mut buf = [0; 4];
+        inst.encode_bytes(&mut buf);
+        assert_eq!(buf, [0xf0, 0x00, 0xab, 0xcd]);
+        assert_eq!(Instruction::decode_bytes(&buf), Some(inst));
+        assert_eq!(Instruction::decode_bytes(&buf[..3]), None);
+        assert_eq!(Instruction::decode(0xf000), None);
+    }
+
+    #[test]
+    fn display() {
+        let cases = [
+            (Sys(0x123), "SYS 0x123", "0x01 0x23"),
+            (Cls, "CLS", "clear"),
+            (Ret, "RET", "return"),
+            (Scd(4), "SCD 4", "scroll-down 4"),
+            (Scu(3), "SCU 3", "scroll-up 3"),
+            (Scr, "SCR", "scroll-right"),
+            (Scl, "SCL", "scroll-left"),
+            (Exit, "EXIT", "exit"),
+            (Low, "LOW", "lores"),
+            (High, "HIGH", "hires"),
+            (Jp(0x345), "JP 0x345", "jump 0x345"),
+            (Call(0x456), "CALL 0x456", ":call 0x456"),
+            (SeByte(1, 0x12), "SE V1, 0x12", "if v1 != 0x12 then"),
+            (SneByte(3, 0xff), "SNE V3, 0xFF", "if v3 == 0xFF then"),
+            (SeReg(1, 2), "SE V1, V2", "if v1 != v2 then"),
+            (Save(2, 5), "SAVE V2, V5", "save v2 - v5"),
+            (Load(5, 2), "LOAD V5, V2", "load v5 - v2"),
+            (LdByte(6, 0xc8), "LD V6, 0xC8", "v6 := 0xC8"),
+            (AddByte(0xa, 3), "ADD VA, 0x03", "va += 0x03"),
+            (LdReg(5, 0xa), "LD V5, VA", "v5 := va"),
+            (Or(1, 2), "OR V1, V2", "v1 |= v2"),
+            (And(1, 2), "AND V1, V2", "v1 &= v2"),
+            (Xor(1, 2), "XOR V1, V2", "v1 ^= v2"),
+            (AddReg(1, 2), "ADD V1, V2", "v1 += v2"),
+            (Sub(1, 2), "SUB V1, V2", "v1 -= v2"),
+            (Shr(4, 5), "SHR V4, V5", "v4 >>= v5"),
+            (Subn(1, 2), "SUBN V1, V2", "v1 =- v2"),
+            (Shl(4, 5), "SHL V4, V5", "v4 <<= v5"),
+            (SneReg(3, 4), "SNE V3, V4", "if v3 == v4 then"),
+            (LdI(0x678), "LD I, 0x678", "i := 0x678"),
+            (JpV0(0x300), "JP V0, 0x300", "jump0 0x300"),
+            (Rnd(2, 0x0f), "RND V2, 0x0F", "v2 := random 0x0F"),
+            (Drw(1, 2, 5), "DRW V1, V2, 5", "sprite v1 v2 5"),
+            (Skp(3), "SKP V3", "if v3 -key then"),
+            (Sknp(3), "SKNP V3", "if v3 key then"),
+            (LdILong(0xabcd), "LD I, LONG 0xABCD", "i := long 0xABCD"),
+            (Plane(3), "PLANE 3", "plane 3"),
+            (Audio, "AUDIO", "audio"),
+            (LdVxDt(7), "LD V7, DT", "v7 := delay"),
+            (LdVxK(8), "LD V8, K", "v8 := key"),
+            (LdDtVx(9), "LD DT, V9", "delay := v9"),
+            (LdStVx(0xa), "LD ST, VA", "buzzer := va"),
+            (AddI(3), "ADD I, V3", "i += v3"),
+            (LdF(0xb), "LD F, VB", "i := hex vb"),
+            (LdHf(0xc), "LD HF, VC", "i := bighex vc"),
+            (LdB(0xd), "LD B, VD", "bcd vd"),
+            (Pitch(2), "PITCH V2", "pitch := v2"),
+            (LdIVx(0xe), "LD [I], VE", "save ve"),
+            (LdVxI(0xf), "LD VF, [I]", "load vf"),
+            (LdRVx(7), "LD R, V7", "saveflags v7"),
+            (LdVxR(7), "LD V7, R", "loadflags v7"),
+        ];
+        for (inst, cowgod, octo) in cases.iter() {
+            assert_eq!(inst.to_string(), *cowgod);
+            assert_eq!(inst.display(Syntax::Cowgod).to_string(), *cowgod);
+            assert_eq!(inst.display(Syntax::Octo).to_string(), *octo);
+        }
+    }
+
+    #[test]
+    fn disassembly() {
+        let rom = [0x00, 0xe0, 0xf0, 0x00, 0x12, 0x34, 0x51, 0x21, 0xaa];
+        let lines: Vec<_> = disassemble(&rom, 0x200).collect();
+        let cowgod: Vec<_> = lines.iter().map(|l| l.to_string()).collect();
+        assert_eq!(
+            cowgod,
+            [
+                "0200: 00E0      CLS",
+                "0202: F0001234  LD I, LONG 0x1234",
+                "0206: 5121      DB 0x51, 0x21",
+                "0208: AA        DB 0xAA",
+            ]
+        );
+        let octo: Vec<_> = lines
+            .iter()
+            .map(|l| l.display(Syntax::Octo).to_string())
+            .collect();
+        assert_eq!(
+            octo,
+            [
+                "0200: 00E0      clear",
+                "0202: F0001234  i := long 0x1234",
+                "0206: 5121      0x51 0x21",
+                "0208: AA        0xAA",
+            ]
+        );
+    }
+}
@@ -0,0 +1,197 @@
+//! Keypad events: key presses and releases applied at the instruction boundary when they happen.
+//!
+//! Events are timestamped in microseconds from the start of the next call to `Chip8::frame` or
+//! `Chip8::run_for`, and are applied before the first instruction that runs at or after their
+//! time.  Events that the call doesn't reach are applied when it ends.
+
+use super::*;
+
+/// Maximum number of key events queued between runs
+pub const MAX_KEY_EVENTS: usize = 32;
+
+#[derive(Clone, Copy)]
+pub(crate) struct KeyEvent {
+    key: u8,
+    down: bool,
+    time: u32, // Microseconds from the start of the run
+}
+
+pub(crate) struct KeyEvents {
+    events: [KeyEvent; MAX_KEY_EVENTS],
+    len: usize,
+}
+
+impl KeyEvents {
+    pub(crate) fn new() -> Self {
+        Self {
+            events: [KeyEvent {
+                key: 0,
+                down: false,
+                time: 0,
+            }; MAX_KEY_EVENTS],
+            len: 0,
+        }
+    }
+    /// Insert `event` after the events with the same or an earlier time.  Returns false if the
+    /// queue is full.
+    fn push(&mut self, event: KeyEvent) -> bool {
+        if self.len == MAX_KEY_EVENTS {
+            return false;
+        }
+        let pos = self.events[..self.len]
+            .iter()
+            .position(|e| e.time > event.time)
+            .unwrap_or(self.len);
+        self.events.copy_within(pos..self.len, pos + 1);
+        self.events[pos] = event;
+        self.len += 1;
+        true
+    }
+}
+
+impl<R: RngCore, T: Trap, M: Bus> Chip8<R, T, M> {
+    /// Queue the press of `key` at the start of the next run.  Returns false if the queue is full.
+    pub fn key_down(&mut self, key: u8) -> bool {
+        self.key_down_at(key, 0)
+    }
+    /// Queue the release of `key` at the start of the next run.  Returns false if the queue is
+    /// full.
+    pub fn key_up(&mut self, key: u8) -> bool {
+        self.key_up_at(key, 0)
+    }
+    /// Queue the press of `key` `time_us` microseconds into the next run.  Returns false if the
+    /// queue is full.
+    pub fn key_down_at(&mut self, key: u8, time_us: u32) -> bool {
+        self.key_events.push(KeyEvent {
+            key: key & 0x0f,
+            down: true,
+            time: time_us,
+        })
+    }
+    /// Queue the release of `key` `time_us` microseconds into the next run.  Returns false if the
+    /// queue is full.
+    pub fn key_up_at(&mut self, key: u8, time_us: u32) -> bool {
+        self.key_events.push(KeyEvent {
+            key: key & 0x0f,
+            down: false,
+            time: time_us,
+        })
+    }
+    /// Keys pressed, one bit per key
+    pub fn keypad(&self) -> u16 {
+        self.keypad
+    }
+    /// Apply the queued key events up to the position of the current instruction in the run, or
+    /// all of them if `all` is set.
+    pub(crate) fn apply_key_events(&mut self, all: bool) {
+        if self.key_events.len == 0 {
+            return;
+        }
+        let pos = self.run_pos();
+        let events = &mut self.key_events;
+        let n = events.events[..events.len]
+            .iter()
+            .take_while(|e| all || e.time as u64 * 60 <= pos)
+            .count();
+        for e in &events.events[..n] {
+            if e.down {
+                self.keypad |= 1 << e.key;
+            } else {
+                self.keypad &= !(1 << e.key);
+            }
+        }
+        events.events.copy_within(n..events.len, 0);
+        events.len -= n;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A frame, for `run_for` which unlike `frame` keeps the keys pressed
+    const FRAME_US: u32 = 16667;
+
+    /// Machine running 100 instructions per frame, one every 1/6000 s
+    fn machine(key_release: bool, words: &[u16]) -> Chip8 {
+        let quirks = Quirks {
+            timing: Timing::Tickrate(100),
+            key_release,
+            ..Quirks::default()
+        };
+        TestMachine::new().quirks(quirks).words(words).build()
+    }
+
+    #[test]
+    fn timestamps() {
+        // LD V0, 5; loop: SKNP V0; JP end; ADD V1, 1; JP loop; end: JP end
+        let words = [0x6005, 0xe0a1, 0x120a, 0x7101, 0x1202, 0x120a];
+        let mut chip8 = machine(false, &words);
+        // Seen by instruction 31, after 10 iterations
+        assert!(chip8.key_down_at(5, 5000));
+        chip8.run_for(FRAME_US).unwrap();
+        assert_eq!(chip8.v()[1], 10);
+        assert_eq!(chip8.pc(), 0x20a);
+        assert_eq!(chip8.keypad(), 1 << 5);
+
+        // Events past the end of the run are applied when it ends
+        let mut chip8 = machine(false, &words);
+        assert!(chip8.key_down_at(5, 20000));
+        chip8.run_for(FRAME_US).unwrap();
+        assert_eq!(chip8.keypad(), 1 << 5);
+        assert_ne!(chip8.pc(), 0x20a);
+
+        // Events at the same time apply in the order they were queued
+        let mut chip8 = machine(false, &words);
+        assert!(chip8.key_down_at(3, 100));
+        assert!(chip8.key_up_at(3, 100));
+        assert!(chip8.key_down_at(4, 50));
+        assert!(chip8.key_up(4));
+        chip8.run_for(FRAME_US).unwrap();
+        assert_eq!(chip8.keypad(), 1 << 4);
+    }
+
+    #[test]
+    fn full_queue() {
+        let mut chip8 = machine(false, &[0x1200]);
+        for i in 0..MAX_KEY_EVENTS {
+            assert!(chip8.key_down_at(i as u8, i as u32));
+        }
+        assert!(!chip8.key_up(0));
+        chip8.run_for(FRAME_US).unwrap();
+        assert_eq!(chip8.keypad(), 0xffff);
+        assert!(chip8.key_up(0));
+    }
+
+    #[test]
+    fn wait_for_release() {
+        // LD V0, K; loop: ADD V1, 1; JP loop
+        let words = [0xf00a, 0x7101, 0x1202];
+        let mut chip8 = machine(true, &words);
+        chip8.key_down(5);
+        chip8.key_up_at(5, 5000);
+        chip8.run_for(FRAME_US).unwrap();
+        // Released before instruction 30, then 35 of the remaining 70 are additions
+        assert_eq!(chip8.v()[0], 5);
+        assert_eq!(chip8.v()[1], 35);
+
+        // Still waiting while the key is held
+        let mut chip8 = machine(true, &words);
+        chip8.key_down(5);
+        chip8.run_for(FRAME_US).unwrap();
+        assert_eq!(chip8.pc(), 0x200);
+        chip8.run_for(FRAME_US).unwrap();
+        assert_eq!(chip8.pc(), 0x200);
+        chip8.key_up(5);
+        chip8.run_for(FRAME_US).unwrap();
+        assert_eq!(chip8.v()[0], 5);
+        assert_ne!(chip8.pc(), 0x200);
+
+        // Without the quirk the press is enough
+        let mut chip8 = machine(false, &words);
+        chip8.key_down(5);
+        chip8.run_for(FRAME_US).unwrap();
+        assert_eq!(chip8.v()[0], 5);
+        assert_eq!(chip8.v()[1], 50);
+    }
+}
@@ -3,9 +3,46 @@
 //! Op descriptions are taken from Cowgod's Chip-8 Technical Reference v1.0  compiled by Thomas P.
 //! Greene: http://devernay.free.fr/hacks/chip8/C8TECH10.HTM
 
+#[cfg(any(test, feature = "std"))]
+extern crate std;
+
+mod audio;
+mod bus;
+mod debug;
+mod display;
+mod error;
+mod instruction;
+mod keypad;
+mod movie;
+mod quirks;
+mod rewind;
+mod rng;
+mod state;
+mod timing;
+mod trap;
+
+pub use audio::{Audio, Waveform};
+pub use bus::{Bus, Memory};
+pub use debug::{Break, Watch, MAX_BREAKPOINTS, MAX_OPCODE_BREAKS, MAX_WATCHPOINTS};
+pub use display::{Dirty, Palette, Pixels, Rows, COLORS};
+pub use error::{Error, Fault};
+pub use instruction::{disassemble, Disassemble, Display, DisplayLine, Instruction, Line, Syntax};
+pub use keypad::MAX_KEY_EVENTS;
+pub use movie::{rom_hash, Header, Player, Recorder, MOVIE_HEADER_SIZE};
+pub use quirks::{MemoryPolicy, OpcodePolicy, Quirks};
+pub use rewind::Rewind;
+pub use rng::{Pcg32, RngState, RNG_STATE_SIZE};
+pub use state::STATE_SIZE;
+pub use timing::Timing;
+pub use trap::{Cpu, NoTrap, Trap};
+
 use core::ops::{Index, IndexMut};
 
-use rand::rngs::SmallRng;
+use audio::ToneLog;
+use debug::Debugger;
+use keypad::KeyEvents;
+use timing::TIMER_PERIOD;
+
 use rand::{RngCore, SeedableRng};
 
 const SPRITE_CHARS: [[u8; 5]; 0x10] = [
@@ -27,26 +64,88 @@ const SPRITE_CHARS: [[u8; 5]; 0x10] = [
     [0xF0, 0x80, 0xF0, 0x80, 0x80], // F
 ];
 const SPRITE_CHARS_ADDR: u16 = 0x0000;
+const BIG_SPRITE_CHARS: [[u8; 10]; 0x10] = [
+    [0xFF, 0xFF, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF], // 0
+    [0x18, 0x78, 0x78, 0x18, 0x18, 0x18, 0x18, 0x18, 0xFF, 0xFF], // 1
+    [0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF], // 2
+    [0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF], // 3
+    [0xC3, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0x03, 0x03, 0x03, 0x03], // 4
+    [0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF], // 5
+    [0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF], // 6
+    [0xFF, 0xFF, 0x03, 0x03, 0x06, 0x0C, 0x18, 0x18, 0x18, 0x18], // 7
+    [0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF], // 8
+    [0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF], // 9
+    [0x7E, 0xFF, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xC3], // A
+    [0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC], // B
+    [0x3C, 0xFF, 0xC3, 0xC0, 0xC0, 0xC0, 0xC0, 0xC3, 0xFF, 0x3C], // C
+    [0xFC, 0xFE, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFE, 0xFC], // D
+    [0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF], // E
+    [0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xC0, 0xC0], // F
+];
+const BIG_SPRITE_CHARS_ADDR: u16 = 0x0050;
 pub const SCREEN_WIDTH: usize = 64;
 pub const SCREEN_HEIGTH: usize = 32;
-const MEM_SIZE: usize = 0x1000;
-const ROM_ADDR: usize = 0x200;
-
-#[derive(Debug)]
-pub enum Error {
-    InvalidOp(u8, u8),
-    RomTooBig(usize),
-    PcOutOfBounds(u16),
-    Debug,
-}
+pub const HIRES_SCREEN_WIDTH: usize = 128;
+pub const HIRES_SCREEN_HEIGTH: usize = 64;
+const FB_SIZE: usize = SCREEN_WIDTH * SCREEN_HEIGTH / 8;
+const HIRES_FB_SIZE: usize = HIRES_SCREEN_WIDTH * HIRES_SCREEN_HEIGTH / 8;
+#[cfg(not(feature = "xo-chip"))]
+pub const MEM_SIZE: usize = 0x1000;
+#[cfg(feature = "xo-chip")]
+pub const MEM_SIZE: usize = 0x10000;
+/// Number of bit planes in the framebuffer
+#[cfg(not(feature = "xo-chip"))]
+pub const PLANES: usize = 1;
+/// Number of bit planes in the framebuffer
+#[cfg(feature = "xo-chip")]
+pub const PLANES: usize = 2;
+/// Size of the XO-CHIP audio pattern buffer in bytes
+pub const AUDIO_PATTERN_SIZE: usize = 16;
+/// Maximum depth of the call stack, see `Quirks::stack_depth`
+pub const MAX_STACK_DEPTH: usize = 32;
+/// Address where the ROM is loaded and execution starts
+pub const ROM_ADDR: usize = 0x200;
 
-/// Returns low nibble from byte
-fn lo_nib(b: u8) -> u8 {
-    b & 0x0f
+/// Instruction set implemented by the machine.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Original COSMAC VIP instruction set.
+    Chip8,
+    /// SUPER-CHIP 1.1: adds the 128x64 hi-res display, scrolling, 16x16 sprites, big digits and
+    /// RPL user flags.
+    SuperChip,
+    /// XO-CHIP: SUPER-CHIP plus 64 KiB of memory, bit planes and audio patterns.
+    #[cfg(feature = "xo-chip")]
+    XoChip,
 }
-/// Returns high nibble from byte
-fn hi_nib(b: u8) -> u8 {
-    (b & 0xf0) >> 4
+
+/// XOR a byte of sprite data into the framebuffer of `width` pixels at (x, y), wrapping around
+/// horizontally unless `clip` is set.  Returns the bits that were erased, and the byte column
+/// following the one of x if the sprite spilled into it.
+fn xor_byte(
+    fb: &mut [u8],
+    width: usize,
+    x: usize,
+    y: usize,
+    byte: u8,
+    clip: bool,
+) -> (u8, Option<usize>) {
+    let cols = width / 8;
+    let shift = x % 8;
+    let col_a = x / 8;
+    let col_b = (col_a + 1) % cols;
+    let a = byte >> shift;
+    let fb_a = &mut fb[y * cols + col_a];
+    let mut collision = *fb_a & a;
+    *fb_a ^= a;
+    if shift == 0 || (clip && col_b == 0) {
+        return (collision, None);
+    }
+    let b = byte << (8 - shift);
+    let fb_b = &mut fb[y * cols + col_b];
+    collision |= *fb_b & b;
+    *fb_b ^= b;
+    (collision, Some(col_b))
 }
 
 #[derive(Clone, Copy)]
@@ -76,26 +175,40 @@ impl Regs {
 
 const FRAME_TIME: isize = 16666; // In microseconds
 
-pub struct Chip8<R: RngCore> {
-    mem: [u8; MEM_SIZE],
-    v: Regs, // Register Set
-    i: u16,  // Index Register
-    pc: u16, // Program Counter
-    stack: [u16; 0x10],
-    sp: u8,                                     // Stack Pointer
-    dt: u8,                                     // Delay Timer
-    st: u8,                                     // Sound Timer
-    keypad: u16,                                // Keypad
-    fb: [u8; SCREEN_WIDTH * SCREEN_HEIGTH / 8], // Framebuffer
-    tone: bool,                                 // Tone output enable
-    time: isize,                                // Overtime in microseconds
-    rng: R,                                     // Instance of a random number generator
-}
-
-macro_rules! nnn {
-    ($w0:expr, $w1:expr) => {
-        (($w0 & 0x0f) as u16) << 8 | $w1 as u16
-    };
+pub struct Chip8<R: RngCore = Pcg32, T: Trap = NoTrap, M: Bus = Memory> {
+    mem: M,                                  // Memory bus
+    v: Regs,                                 // Register Set
+    i: u16,                                  // Index Register
+    pc: u16,                                 // Program Counter
+    stack: [u16; MAX_STACK_DEPTH],           // Call Stack
+    sp: u8,                                  // Stack Pointer
+    dt: u8,                                  // Delay Timer
+    st: u8,                                  // Sound Timer
+    keypad: u16,                             // Keypad
+    key_events: KeyEvents,                   // Queued key presses and releases
+    key_wait: Option<u8>,                    // Key pressed while waiting in Fx0A
+    fb: [[u8; FB_SIZE]; PLANES],             // Framebuffer
+    fb_hires: [[u8; HIRES_FB_SIZE]; PLANES], // Hi-res Framebuffer (SUPER-CHIP)
+    hires: bool,                             // Hi-res display enabled
+    planes: u8,                              // Selected bit planes (XO-CHIP)
+    rpl: [u8; 0x10],                         // RPL user flags (SUPER-CHIP)
+    pattern: [u8; AUDIO_PATTERN_SIZE],       // Audio pattern buffer (XO-CHIP)
+    pitch: u8,                               // Audio pattern pitch (XO-CHIP)
+    mode: Mode,                              // Instruction set
+    exited: bool,                            // Interpreter exited (SUPER-CHIP)
+    tone: bool,                              // Tone output enable
+    time: isize,                             // Overtime in microseconds
+    timer_phase: u32,                        // Time since the last timer tick, in 1/60 us
+    budget_frac: u32,                        // Fraction of execution time carried, in 1/60 us
+    run_budget: isize,                       // Execution time added by the current run
+    tick_next: u64,                          // Position in the run of the next timer tick
+    ticks: u16,                              // Timer ticks left in the run
+    tone_log: ToneLog,                       // Tone changes in the run
+    quirks: Quirks,                          // Compatibility quirks
+    rng: R,                                  // Instance of a random number generator
+    debug: Debugger,                         // Breakpoints and watchpoints
+    dirty: Dirty,                            // Region of the framebuffer modified
+    trap: T,                                 // Handler of unknown opcodes
 }
 
 pub struct Output {
@@ -103,91 +216,447 @@ pub struct Output {
     pub overtime: usize,
 }
 
-impl Chip8<SmallRng> {
+impl Chip8<Pcg32> {
     pub fn new(seed: u64) -> Self {
-        let mut mem = [0; MEM_SIZE];
+        Self::with_quirks(seed, Quirks::default())
+    }
+    pub fn with_quirks(seed: u64, quirks: Quirks) -> Self {
+        Chip8::with_trap(seed, quirks, NoTrap)
+    }
+}
+
+impl<T: Trap> Chip8<Pcg32, T> {
+    /// Create a machine that hands unknown opcodes to `trap` with `OpcodePolicy::Trap`.
+    pub fn with_trap(seed: u64, quirks: Quirks, trap: T) -> Self {
+        Chip8::with_rng_trap(Pcg32::seed_from_u64(seed), quirks, trap)
+    }
+}
+
+impl<R: RngCore> Chip8<R> {
+    /// Create a machine that draws its random numbers from `rng`.
+    pub fn with_rng(rng: R, quirks: Quirks) -> Self {
+        Chip8::with_rng_trap(rng, quirks, NoTrap)
+    }
+}
+
+impl<R: RngCore, T: Trap> Chip8<R, T> {
+    /// Create a machine that draws its random numbers from `rng` and hands unknown opcodes to
+    /// `trap` with `OpcodePolicy::Trap`.
+    pub fn with_rng_trap(rng: R, quirks: Quirks, trap: T) -> Self {
+        Chip8::with_bus(Memory::new(), rng, quirks, trap)
+    }
+}
+
+impl<R: RngCore, T: Trap, M: Bus> Chip8<R, T, M> {
+    /// Create a machine whose memory is served by `mem`, drawing its random numbers from `rng`
+    /// and handing unknown opcodes to `trap` with `OpcodePolicy::Trap`.  The fonts are stored
+    /// into `mem`.
+    pub fn with_bus(mut mem: M, rng: R, quirks: Quirks, trap: T) -> Self {
         for (i, sprite) in SPRITE_CHARS.iter().enumerate() {
             let p = SPRITE_CHARS_ADDR as usize + i * sprite.len();
-            mem[p..p + sprite.len()].copy_from_slice(sprite)
+            for (j, b) in sprite.iter().enumerate() {
+                mem.poke(p + j, *b);
+            }
+        }
+        for (i, sprite) in BIG_SPRITE_CHARS.iter().enumerate() {
+            let p = BIG_SPRITE_CHARS_ADDR as usize + i * sprite.len();
+            for (j, b) in sprite.iter().enumerate() {
+                mem.poke(p + j, *b);
+            }
         }
         Self {
             mem,
             v: Regs::new(),
             i: 0,
             pc: ROM_ADDR as u16,
-            stack: [0; 0x10],
+            stack: [0; MAX_STACK_DEPTH],
             sp: 0,
             dt: 0,
             st: 0,
             keypad: 0,
-            fb: [0; SCREEN_WIDTH * SCREEN_HEIGTH / 8],
+            key_events: KeyEvents::new(),
+            key_wait: None,
+            fb: [[0; FB_SIZE]; PLANES],
+            fb_hires: [[0; HIRES_FB_SIZE]; PLANES],
+            hires: false,
+            planes: 0b01,
+            rpl: [0; 0x10],
+            pattern: [0; AUDIO_PATTERN_SIZE],
+            pitch: 64,
+            mode: Mode::Chip8,
+            exited: false,
             tone: false,
             time: 0,
-            rng: rand::rngs::SmallRng::seed_from_u64(seed),
+            timer_phase: 0,
+            budget_frac: 0,
+            run_budget: 0,
+            tick_next: 0,
+            ticks: 0,
+            tone_log: ToneLog::new(),
+            quirks,
+            rng,
+            debug: Debugger::new(),
+            dirty: Dirty::default(),
+            trap,
         }
     }
 }
 
-impl<R: RngCore> Chip8<R> {
+impl<R: RngCore, T: Trap, M: Bus> Chip8<R, T, M> {
     /// Load a rom into Chip8 memory
     pub fn load_rom(&mut self, rom: &[u8]) -> Result<(), Error> {
         if rom.len() > MEM_SIZE - ROM_ADDR {
             return Err(Error::RomTooBig(rom.len()));
         }
-        self.mem[ROM_ADDR..ROM_ADDR + rom.len()].copy_from_slice(rom);
+        for (i, b) in rom.iter().enumerate() {
+            self.mem.poke(ROM_ADDR + i, *b);
+        }
         Ok(())
     }
-    /// Whether a tone must be played
+    /// Whether a tone must be played, which is while the sound timer is active
     pub fn tone(&self) -> bool {
         self.tone
     }
-    /// Framebuffer view
-    pub fn fb(&self) -> [u8; SCREEN_WIDTH * SCREEN_HEIGTH / 8] {
-        self.fb
+    /// Select the instruction set to emulate
+    pub fn set_mode(&mut self, mode: Mode) {
+        self.mode = mode;
+    }
+    /// Instruction set being emulated
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+    /// Compatibility quirks in use
+    pub fn quirks(&self) -> Quirks {
+        self.quirks
+    }
+    /// Select how the execution of a frame is budgeted.  Can be changed between frames.
+    pub fn set_timing(&mut self, timing: Timing) {
+        if timing != self.quirks.timing {
+            // The overtime is measured in the units of the previous timing
+            self.time = 0;
+            self.budget_frac = 0;
+        }
+        self.quirks.timing = timing;
+    }
+    /// Whether the hi-res (128x64) display is enabled
+    pub fn hires(&self) -> bool {
+        self.hires
+    }
+    /// Current display resolution as (width, height)
+    pub fn resolution(&self) -> (usize, usize) {
+        if self.hires {
+            (HIRES_SCREEN_WIDTH, HIRES_SCREEN_HEIGTH)
+        } else {
+            (SCREEN_WIDTH, SCREEN_HEIGTH)
+        }
+    }
+    /// Whether the program has exited the interpreter (SUPER-CHIP 00FD)
+    pub fn exited(&self) -> bool {
+        self.exited
+    }
+    /// Audio pattern buffer, played one bit per sample while the tone is enabled (XO-CHIP)
+    pub fn audio_pattern(&self) -> &[u8; AUDIO_PATTERN_SIZE] {
+        &self.pattern
+    }
+    /// Audio pattern pitch register.  The pattern playback rate in Hz is
+    /// `4000 * 2 ^ ((pitch - 64) / 48)` (XO-CHIP)
+    pub fn pitch(&self) -> u8 {
+        self.pitch
+    }
+    /// Copy of the lo-res framebuffer.  Prefer `fb_ref` to avoid the copy.
+    pub fn fb(&self) -> [u8; FB_SIZE] {
+        self.fb[0]
+    }
+    /// Hi-res framebuffer view
+    pub fn fb_hires(&self) -> &[u8; HIRES_FB_SIZE] {
+        &self.fb_hires[0]
+    }
+    /// View of the bit plane `p` of the framebuffer at the current resolution
+    pub fn plane(&self, p: usize) -> &[u8] {
+        if self.hires {
+            &self.fb_hires[p]
+        } else {
+            &self.fb[p]
+        }
+    }
+    /// Bit plane `p` of the framebuffer at the current resolution with its width in pixels
+    fn plane_mut(&mut self, p: usize) -> (&mut [u8], usize) {
+        if self.hires {
+            (&mut self.fb_hires[p][..], HIRES_SCREEN_WIDTH)
+        } else {
+            (&mut self.fb[p][..], SCREEN_WIDTH)
+        }
+    }
+    /// Whether the SUPER-CHIP instructions are available
+    fn schip(&self) -> bool {
+        self.mode != Mode::Chip8
+    }
+    /// Whether the XO-CHIP instructions are available
+    #[cfg(feature = "xo-chip")]
+    fn xo(&self) -> bool {
+        self.mode == Mode::XoChip
+    }
+    /// Whether the XO-CHIP instructions are available
+    #[cfg(not(feature = "xo-chip"))]
+    fn xo(&self) -> bool {
+        false
+    }
+    /// Advance the PC over the next instruction, which is 4 bytes long for the XO-CHIP long I
+    /// load.
+    fn skip(&mut self) {
+        let next = self.pc as usize + 2;
+        let long = self.xo()
+            && next + 1 < MEM_SIZE
+            && self.mem.peek(next) == 0xF0
+            && self.mem.peek(next + 1) == 0x00;
+        self.pc = self.pc.wrapping_add(if long { 6 } else { 4 });
     }
     /// Emulates the execution of instructions continuously until the emulated instructions total
     /// elapsed time reaches the equivalent of a frame.
     pub fn frame(&mut self, keypad: u16) -> Result<(), Error> {
         self.keypad = keypad;
-        if self.dt != 0 {
-            self.dt -= 1;
+        self.advance(TIMER_PERIOD as u64);
+        self.resume()
+    }
+    /// Emulates `duration_us` microseconds of real time: the timers are updated at 60 Hz and the
+    /// instructions run for the equivalent time, carrying the fractions between calls.  Allows
+    /// driving the machine at any rate instead of calling `frame` at 60 Hz.
+    pub fn run_for(&mut self, duration_us: u32) -> Result<(), Error> {
+        self.advance(duration_us as u64 * 60);
+        self.resume()
+    }
+    /// Set the keys pressed, one bit per key, for the following calls to `run_for`.
+    pub fn set_keypad(&mut self, keypad: u16) {
+        self.keypad = keypad;
+    }
+    /// Continues the execution of a frame that returned early with `Error::Debug`.
+    pub fn resume(&mut self) -> Result<(), Error> {
+        while self.time > 0 && !self.exited {
+            self.exec_next()?;
         }
-        self.tone = if self.st != 0 {
-            self.st -= 1;
-            true
+        self.tick_timers(true);
+        self.apply_key_events(true);
+        Ok(())
+    }
+    /// Executes exactly one instruction, ignoring any breakpoint at the current PC.  Stepping goes
+    /// on with an interrupted frame: the instruction takes its time from the frame, and ticks the
+    /// timers it reaches as `resume` would.  Outside of a frame the timers don't tick, and the time
+    /// is taken from the next frame.
+    pub fn step(&mut self) -> Result<(), Error> {
+        self.debug.skip = true;
+        self.exec_next()
+    }
+    /// Executes the instruction at PC, stopping before or after it when a break condition is met.
+    fn exec_next(&mut self) -> Result<(), Error> {
+        self.tick_timers(false);
+        self.apply_key_events(false);
+        let pc = self.pc;
+        if pc as usize > MEM_SIZE - 1 {
+            return Err(Error::PcOutOfBounds(self.fault()));
+        }
+        let pc_addr = pc as usize;
+        let w0 = self.mem.peek(pc_addr);
+        let w1 = if pc_addr + 1 < MEM_SIZE {
+            self.mem.peek(pc_addr + 1)
         } else {
-            false
+            0
         };
-        self.time += FRAME_TIME;
-
-        while self.time > 0 {
-            if self.pc as usize > MEM_SIZE - 1 {
-                return Err(Error::PcOutOfBounds(self.pc));
+        if !self.debug.skip {
+            if let Some(b) = self.debug.check_exec(pc, (w0 as u16) << 8 | w1 as u16) {
+                self.debug.skip = true;
+                return Err(Error::Debug(b));
+            }
+        }
+        self.debug.skip = false;
+        let regs = self.v.0;
+        // Fetch the opcode through the bus, and the second word only for the XO-CHIP long I load
+        let len = (MEM_SIZE - pc_addr).min(if (w0, w1) == (0xF0, 0x00) { 4 } else { 2 });
+        let mut bytes = [0; 4];
+        for (i, b) in bytes[..len].iter_mut().enumerate() {
+            *b = self.mem.read(pc_addr + i);
+        }
+        let inst = Instruction::decode_bytes(&bytes[..len]);
+        let adv = self.exec_timed(inst, (w0 as u16) << 8 | w1 as u16)?;
+        self.time -= adv as isize;
+        if let Some(b) = self.debug.hit.take() {
+            return Err(Error::Debug(b));
+        }
+        let watched = self.debug.watching_regs();
+        if watched != 0 {
+            for x in (0..0x10).filter(|x| watched & 1 << x != 0) {
+                let (old, new) = (regs[x], self.v.0[x]);
+                if old != new {
+                    let x = x as u8;
+                    return Err(Error::Debug(Break::Reg { pc, x, old, new }));
+                }
             }
-            let w0 = self.mem[self.pc as usize];
-            let w1 = self.mem[self.pc as usize + 1];
-            let adv = self.exec(w0, w1)?;
-            self.time -= adv as isize;
         }
         Ok(())
     }
+    /// Map an address computed by the current instruction into memory, following the memory
+    /// policy when it's out of bounds.
+    fn addr(&self, addr: usize) -> Result<usize, Error> {
+        if addr < MEM_SIZE {
+            return Ok(addr);
+        }
+        match self.quirks.memory_policy {
+            MemoryPolicy::Wrap => Ok(addr % MEM_SIZE),
+            MemoryPolicy::Clamp => Ok(MEM_SIZE - 1),
+            MemoryPolicy::Error => Err(Error::MemoryOutOfBounds {
+                addr,
+                fault: self.fault(),
+            }),
+        }
+    }
+    /// Read the memory at addr on behalf of the current instruction.
+    fn read(&mut self, addr: usize) -> Result<u8, Error> {
+        let addr = self.addr(addr)?;
+        if self.debug.watching_mem() {
+            self.debug.check_access(self.pc, addr as u16, Watch::Read);
+        }
+        Ok(self.mem.read(addr))
+    }
+    /// Write the memory at addr on behalf of the current instruction.
+    fn write(&mut self, addr: usize, v: u8) -> Result<(), Error> {
+        let addr = self.addr(addr)?;
+        if self.debug.watching_mem() {
+            self.debug.check_access(self.pc, addr as u16, Watch::Write);
+        }
+        self.mem.write(addr, v);
+        Ok(())
+    }
 
     /// Op: Clear the display.
     fn op_cls(&mut self) -> usize {
-        for b in self.fb.iter_mut() {
-            *b = 0;
+        self.touch_all();
+        let planes = self.planes;
+        for p in (0..PLANES).filter(|p| planes & 1 << p != 0) {
+            let (fb, _) = self.plane_mut(p);
+            for b in fb.iter_mut() {
+                *b = 0;
+            }
         }
-        self.pc += 2;
+        self.pc = self.pc.wrapping_add(2);
         109
     }
+    /// Op: Scroll display n lines down.
+    fn op_scd(&mut self, n: u8) -> usize {
+        self.touch_all();
+        let planes = self.planes;
+        for p in (0..PLANES).filter(|p| planes & 1 << p != 0) {
+            let (fb, width) = self.plane_mut(p);
+            let n = n as usize * width / 8;
+            let len = fb.len();
+            fb.copy_within(0..len - n, n);
+            for b in fb[..n].iter_mut() {
+                *b = 0;
+            }
+        }
+        self.pc = self.pc.wrapping_add(2);
+        3000
+    }
+    /// Op: Scroll display n lines up.
+    fn op_scu(&mut self, n: u8) -> usize {
+        self.touch_all();
+        let planes = self.planes;
+        for p in (0..PLANES).filter(|p| planes & 1 << p != 0) {
+            let (fb, width) = self.plane_mut(p);
+            let n = n as usize * width / 8;
+            let len = fb.len();
+            fb.copy_within(n..len, 0);
+            for b in fb[len - n..].iter_mut() {
+                *b = 0;
+            }
+        }
+        self.pc = self.pc.wrapping_add(2);
+        3000
+    }
+    /// Op: Scroll display 4 pixels right.
+    fn op_scr(&mut self) -> usize {
+        self.touch_all();
+        let planes = self.planes;
+        for p in (0..PLANES).filter(|p| planes & 1 << p != 0) {
+            let (fb, width) = self.plane_mut(p);
+            for row in fb.chunks_mut(width / 8) {
+                for x in (0..row.len()).rev() {
+                    let carry = if x == 0 { 0 } else { row[x - 1] << 4 };
+                    row[x] = row[x] >> 4 | carry;
+                }
+            }
+        }
+        self.pc = self.pc.wrapping_add(2);
+        3000
+    }
+    /// Op: Scroll display 4 pixels left.
+    fn op_scl(&mut self) -> usize {
+        self.touch_all();
+        let planes = self.planes;
+        for p in (0..PLANES).filter(|p| planes & 1 << p != 0) {
+            let (fb, width) = self.plane_mut(p);
+            for row in fb.chunks_mut(width / 8) {
+                let len = row.len();
+                for x in 0..len {
+                    let carry = if x == len - 1 { 0 } else { row[x + 1] >> 4 };
+                    row[x] = row[x] << 4 | carry;
+                }
+            }
+        }
+        self.pc = self.pc.wrapping_add(2);
+        3000
+    }
+    /// Op: Exit the interpreter.
+    fn op_exit(&mut self) -> usize {
+        self.exited = true;
+        100
+    }
+    /// Op: Enable or disable the hi-res display, clearing it.
+    fn op_hires(&mut self, hires: bool) -> usize {
+        self.hires = hires;
+        self.touch_all();
+        for p in 0..PLANES {
+            let (fb, _) = self.plane_mut(p);
+            for b in fb.iter_mut() {
+                *b = 0;
+            }
+        }
+        self.pc = self.pc.wrapping_add(2);
+        109
+    }
+    /// Op: Select the bit planes affected by drawing, clearing and scrolling.
+    fn op_plane(&mut self, n: u8) -> usize {
+        self.planes = n & ((1 << PLANES) - 1) as u8;
+        self.pc = self.pc.wrapping_add(2);
+        27
+    }
+    /// Op: Load 16 bytes starting at I into the audio pattern buffer.
+    fn op_audio(&mut self) -> Result<usize, Error> {
+        let i = self.i as usize;
+        for n in 0..AUDIO_PATTERN_SIZE {
+            self.pattern[n] = self.read(i + n)?;
+        }
+        self.pc = self.pc.wrapping_add(2);
+        Ok(605)
+    }
+    /// Op: Set the audio pattern pitch = v.
+    fn op_pitch(&mut self, v: u8) -> usize {
+        self.pitch = v;
+        self.pc = self.pc.wrapping_add(2);
+        45
+    }
     fn op_call_rca_1802(&mut self, _addr: u16) -> usize {
         100
     }
     /// Op: Return from a subroutine.
-    fn op_ret(&mut self) -> usize {
+    fn op_ret(&mut self) -> Result<usize, Error> {
+        if self.sp == 0 {
+            return Err(Error::StackUnderflow {
+                pc: self.pc,
+                fault: self.fault(),
+            });
+        }
         self.sp -= 1;
         self.pc = self.stack[self.sp as usize];
-        105
+        Ok(105)
     }
     /// Op: Jump to addr.
     fn op_jp(&mut self, addr: u16) -> usize {
@@ -195,93 +664,173 @@ impl<R: RngCore> Chip8<R> {
         105
     }
     /// Op: Call subroutine at addr.
-    fn op_call(&mut self, addr: u16) -> usize {
-        self.stack[self.sp as usize] = self.pc + 2;
+    fn op_call(&mut self, addr: u16) -> Result<usize, Error> {
+        if self.sp as usize >= self.quirks.stack_depth.min(MAX_STACK_DEPTH) {
+            return Err(Error::StackOverflow {
+                pc: self.pc,
+                fault: self.fault(),
+            });
+        }
+        self.stack[self.sp as usize] = self.pc.wrapping_add(2);
         self.sp += 1;
         self.pc = addr;
-        105
+        Ok(105)
     }
     /// Op: Skip next instruction if a == b.
     fn op_se(&mut self, a: u8, b: u8) -> usize {
         if a == b {
-            self.pc += 4;
+            self.skip();
         } else {
-            self.pc += 2;
+            self.pc = self.pc.wrapping_add(2);
         }
         61
     }
     /// Op: Skip next instruction if a != b.
     fn op_sne(&mut self, a: u8, b: u8) -> usize {
         if a != b {
-            self.pc += 4;
+            self.skip();
         } else {
-            self.pc += 2;
+            self.pc = self.pc.wrapping_add(2);
         }
         61
     }
     /// Op: Set Vx = v.
     fn op_ld(&mut self, x: Reg, v: u8) -> usize {
         self.v[x] = v;
-        self.pc += 2;
+        self.pc = self.pc.wrapping_add(2);
         27
     }
     /// Op: Wait for a key press, store the value of the key in Vx.
     fn op_ld_vx_k(&mut self, x: Reg) -> usize {
-        for i in 0..0x10 {
-            if 1 << i & self.keypad != 0 {
-                self.v[x] = i as u8;
-                self.pc += 2;
-                break;
+        if !self.quirks.key_release {
+            for i in 0..0x10 {
+                if 1 << i & self.keypad != 0 {
+                    self.v[x] = i as u8;
+                    self.pc = self.pc.wrapping_add(2);
+                    break;
+                }
             }
+            return 200;
+        }
+        match self.key_wait {
+            // The key was pressed and released
+            Some(k) if 1 << k & self.keypad == 0 => {
+                self.v[x] = k;
+                self.key_wait = None;
+                self.pc = self.pc.wrapping_add(2);
+            }
+            Some(_) => {}
+            None => self.key_wait = (0..0x10).find(|k| 1 << k & self.keypad != 0),
         }
         200
     }
     /// Op: Set delay timer = Vx.
     fn op_ld_dt(&mut self, v: u8) -> usize {
         self.dt = v;
-        self.pc += 2;
+        self.pc = self.pc.wrapping_add(2);
         45
     }
     /// Op: Set sound timer = Vx.
     fn op_ld_st(&mut self, v: u8) -> usize {
         self.st = v;
-        self.pc += 2;
+        self.set_tone(v != 0, self.run_pos());
+        self.pc = self.pc.wrapping_add(2);
         45
     }
     /// Op: Set I = location of sprite for digit v.
     fn op_ld_f(&mut self, v: u8) -> usize {
         self.i = SPRITE_CHARS_ADDR + v as u16 * 5;
-        self.pc += 2;
+        self.pc = self.pc.wrapping_add(2);
+        91
+    }
+    /// Op: Set I = location of big sprite for digit v.
+    fn op_ld_hf(&mut self, v: u8) -> usize {
+        self.i = BIG_SPRITE_CHARS_ADDR + (v & 0x0f) as u16 * 10;
+        self.pc = self.pc.wrapping_add(2);
         91
     }
     /// Op: Store BCD representation of v in memory locations I, I+1, and I+2.
     #[allow(clippy::identity_op)]
-    fn op_ld_b(&mut self, v: u8) -> usize {
+    fn op_ld_b(&mut self, v: u8) -> Result<usize, Error> {
         let d2 = v / 100;
         let v = v - d2 * 100;
         let d1 = v / 10;
         let v = v - d1 * 10;
         let d0 = v / 1;
-        self.mem[self.i as usize + 0] = d2;
-        self.mem[self.i as usize + 1] = d1;
-        self.mem[self.i as usize + 2] = d0;
-        self.pc += 2;
-        927
+        self.write(self.i as usize + 0, d2)?;
+        self.write(self.i as usize + 1, d1)?;
+        self.write(self.i as usize + 2, d0)?;
+        self.pc = self.pc.wrapping_add(2);
+        Ok(927)
     }
     /// Op: Store registers V0 through Vx in memory starting at location I.
-    fn op_ld_i_vx(&mut self, x: u8) -> usize {
+    fn op_ld_i_vx(&mut self, x: u8) -> Result<usize, Error> {
         for i in 0..x + 1 {
-            self.mem[self.i as usize + i as usize] = self.v[Reg(i)];
+            self.write(self.i as usize + i as usize, self.v[Reg(i)])?;
         }
-        self.pc += 2;
-        605
+        if self.quirks.load_store_inc_i {
+            self.i = self.i.wrapping_add(x as u16 + 1);
+        }
+        self.pc = self.pc.wrapping_add(2);
+        Ok(605)
     }
     /// Op: Read registers V0 through Vx from memory starting at location I.
-    fn op_ld_vx_i(&mut self, x: u8) -> usize {
+    fn op_ld_vx_i(&mut self, x: u8) -> Result<usize, Error> {
+        for i in 0..x + 1 {
+            self.v[Reg(i)] = self.read(self.i as usize + i as usize)?;
+        }
+        if self.quirks.load_store_inc_i {
+            self.i = self.i.wrapping_add(x as u16 + 1);
+        }
+        self.pc = self.pc.wrapping_add(2);
+        Ok(605)
+    }
+    /// Op: Store registers Vx through Vy in memory starting at location I, in either order.
+    fn op_ld_i_vxy(&mut self, x: u8, y: u8) -> Result<usize, Error> {
+        let i = self.i as usize;
+        if x <= y {
+            for (n, r) in (x..=y).enumerate() {
+                self.write(i + n, self.v[Reg(r)])?;
+            }
+        } else {
+            for (n, r) in (y..=x).rev().enumerate() {
+                self.write(i + n, self.v[Reg(r)])?;
+            }
+        }
+        self.pc = self.pc.wrapping_add(2);
+        Ok(605)
+    }
+    /// Op: Read registers Vx through Vy from memory starting at location I, in either order.
+    fn op_ld_vxy_i(&mut self, x: u8, y: u8) -> Result<usize, Error> {
+        let i = self.i as usize;
+        if x <= y {
+            for (n, r) in (x..=y).enumerate() {
+                self.v[Reg(r)] = self.read(i + n)?;
+            }
+        } else {
+            for (n, r) in (y..=x).rev().enumerate() {
+                self.v[Reg(r)] = self.read(i + n)?;
+            }
+        }
+        self.pc = self.pc.wrapping_add(2);
+        Ok(605)
+    }
+    /// Op: Store registers V0 through Vx in RPL user flags (x <= 7, or x <= F in XO-CHIP).
+    fn op_ld_r_vx(&mut self, x: u8) -> usize {
+        let x = if self.xo() { x } else { x & 0x07 };
         for i in 0..x + 1 {
-            self.v[Reg(i)] = self.mem[self.i as usize + i as usize];
+            self.rpl[i as usize] = self.v[Reg(i)];
         }
-        self.pc += 2;
+        self.pc = self.pc.wrapping_add(2);
+        605
+    }
+    /// Op: Read registers V0 through Vx from RPL user flags (x <= 7, or x <= F in XO-CHIP).
+    fn op_ld_vx_r(&mut self, x: u8) -> usize {
+        let x = if self.xo() { x } else { x & 0x07 };
+        for i in 0..x + 1 {
+            self.v[Reg(i)] = self.rpl[i as usize];
+        }
+        self.pc = self.pc.wrapping_add(2);
         605
     }
     /// Op: Set Vx = Vx + b.
@@ -291,31 +840,40 @@ impl<R: RngCore> Chip8<R> {
         if set_overflow {
             self.v[Reg(0xf)] = if overflow { 1 } else { 0 };
         }
-        self.pc += 2;
+        self.pc = self.pc.wrapping_add(2);
         45
     }
     /// Op: Set I = I + b.
     fn op_add16(&mut self, b: u8) -> usize {
-        self.i += b as u16;
-        self.pc += 2;
+        self.i = self.i.wrapping_add(b as u16);
+        self.pc = self.pc.wrapping_add(2);
         86
     }
     /// Op: Set Vx = Vx OR b.
     fn op_or(&mut self, x: Reg, b: u8) -> usize {
         self.v[x] |= b;
-        self.pc += 2;
+        if self.quirks.vf_reset {
+            self.v[Reg(0xf)] = 0;
+        }
+        self.pc = self.pc.wrapping_add(2);
         200
     }
     /// Op: Set Vx = Vx AND b.
     fn op_and(&mut self, x: Reg, b: u8) -> usize {
         self.v[x] &= b;
-        self.pc += 2;
+        if self.quirks.vf_reset {
+            self.v[Reg(0xf)] = 0;
+        }
+        self.pc = self.pc.wrapping_add(2);
         200
     }
     /// Op: Set Vx = Vx XOR b.
     fn op_xor(&mut self, x: Reg, b: u8) -> usize {
         self.v[x] ^= b;
-        self.pc += 2;
+        if self.quirks.vf_reset {
+            self.v[Reg(0xf)] = 0;
+        }
+        self.pc = self.pc.wrapping_add(2);
         200
     }
     /// Op: Set Vx = Vx - b.
@@ -323,7 +881,7 @@ impl<R: RngCore> Chip8<R> {
         let (res, overflow) = self.v[x].overflowing_sub(b);
         self.v[x] = res;
         self.v[Reg(0xf)] = if overflow { 0 } else { 1 };
-        self.pc += 2;
+        self.pc = self.pc.wrapping_add(2);
         200
     }
     /// Op: Set Vx = b - Vx, set Vf = NOT borrow.
@@ -331,140 +889,564 @@ impl<R: RngCore> Chip8<R> {
         let (res, overflow) = b.overflowing_sub(self.v[x]);
         self.v[x] = res;
         self.v[Reg(0xf)] = if overflow { 0 } else { 1 };
-        self.pc += 2;
+        self.pc = self.pc.wrapping_add(2);
         200
     }
-    /// Op: Set Vx = Vx >> 1.
-    fn op_shr(&mut self, x: Reg) -> usize {
-        self.v[Reg(0xf)] = self.v[x] & 0b00000001;
-        let (res, _) = self.v[x].overflowing_shr(1);
+    /// Op: Set Vx = b >> 1, where b is Vx or Vy depending on the shift quirk.
+    fn op_shr(&mut self, x: Reg, b: u8) -> usize {
+        let b = if self.quirks.shift_vy { b } else { self.v[x] };
+        let (res, _) = b.overflowing_shr(1);
         self.v[x] = res;
-        self.pc += 2;
+        self.v[Reg(0xf)] = b & 0b00000001;
+        self.pc = self.pc.wrapping_add(2);
         200
     }
-    /// Op: Set Vx = Vx << 1.
-    fn op_shl(&mut self, x: Reg) -> usize {
-        self.v[Reg(0xf)] = (self.v[x] & 0b10000000) >> 7;
-        let (res, _) = self.v[x].overflowing_shl(1);
+    /// Op: Set Vx = b << 1, where b is Vx or Vy depending on the shift quirk.
+    fn op_shl(&mut self, x: Reg, b: u8) -> usize {
+        let b = if self.quirks.shift_vy { b } else { self.v[x] };
+        let (res, _) = b.overflowing_shl(1);
         self.v[x] = res;
-        self.pc += 2;
+        self.v[Reg(0xf)] = (b & 0b10000000) >> 7;
+        self.pc = self.pc.wrapping_add(2);
         200
     }
     /// Op: Set I = addr
     fn op_ld_i(&mut self, addr: u16) -> usize {
         self.i = addr;
-        self.pc += 2;
+        self.pc = self.pc.wrapping_add(2);
         55
     }
+    /// Op: Set I = the 16 bit addr following this instruction.
+    fn op_ld_i_long(&mut self, addr: u16) -> usize {
+        self.i = addr;
+        self.pc = self.pc.wrapping_add(4);
+        110
+    }
     /// Op: Set Vx = random byte AND v
     fn op_rnd(&mut self, x: Reg, v: u8) -> usize {
         self.v[x] = (self.rng.next_u32() as u8) & v;
-        self.pc += 2;
+        self.pc = self.pc.wrapping_add(2);
         164
     }
     /// Op: Display n-byte sprite starting at memory location I at (Vx, Vy), set VF = collision.
-    fn op_drw(&mut self, pos_x: u8, pos_y: u8, n: u8) -> usize {
-        let pos_x = pos_x % 64;
-        let pos_y = pos_y % 32;
-        let fb = &mut self.fb;
-        let shift = pos_x % 8;
-        let col_a = pos_x as usize / 8;
-        let col_b = (col_a + 1) % (SCREEN_WIDTH / 8);
+    /// In SUPER-CHIP mode, n = 0 displays a 16x16 sprite of 32 bytes.  In XO-CHIP mode, a sprite
+    /// is drawn in each selected bit plane, with the data for each plane following the previous
+    /// one.
+    fn op_drw(&mut self, pos_x: u8, pos_y: u8, n: u8) -> Result<usize, Error> {
+        let (width, height) = self.resolution();
+        let pos_x = pos_x as usize % width;
+        let pos_y = pos_y as usize % height;
+        let (rows, cols) = if n == 0 && self.schip() {
+            (16, 2)
+        } else {
+            (n as usize, 1)
+        };
+        let mut addr = self.i as usize;
         let mut collision = 0;
-        for i in 0..(n as usize) {
-            let byte = self.mem[self.i as usize + i];
-            let y = (pos_y as usize + i) % SCREEN_HEIGTH;
-            let a = byte >> shift;
-            let fb_a = &mut fb[y * SCREEN_WIDTH / 8 + col_a];
-            collision |= *fb_a & a;
-            *fb_a ^= a;
-            if shift != 0 {
-                let b = byte << (8 - shift);
-                let fb_b = &mut fb[y * SCREEN_WIDTH / 8 + col_b];
-                collision |= *fb_b & b;
-                *fb_b ^= b;
+        let planes = self.planes;
+        let clip = self.quirks.clip_sprites;
+        for p in (0..PLANES).filter(|p| planes & 1 << p != 0) {
+            for i in 0..rows {
+                if clip && pos_y + i >= height {
+                    break;
+                }
+                let y = (pos_y + i) % height;
+                for j in 0..cols {
+                    if clip && pos_x + j * 8 >= width {
+                        break;
+                    }
+                    let byte = self.read(addr + i * cols + j)?;
+                    let x = (pos_x + j * 8) % width;
+                    let (fb, _) = self.plane_mut(p);
+                    let (erased, spill) = xor_byte(fb, width, x, y, byte, clip);
+                    collision |= erased;
+                    self.touch(x / 8, y);
+                    if let Some(col) = spill {
+                        self.touch(col, y);
+                    }
+                }
             }
+            addr += rows * cols;
         }
         self.v[Reg(0xf)] = if collision != 0 { 1 } else { 0 };
-        self.pc += 2;
-        22734
+        self.pc = self.pc.wrapping_add(2);
+        Ok(if self.quirks.display_wait {
+            // Wait for the next frame
+            self.vblank_wait()
+        } else {
+            22734
+        })
     }
     /// Op: Skip next instruction if key with the value of v is pressed.
     fn op_skp(&mut self, v: u8) -> usize {
-        if 1 << v & self.keypad != 0 {
-            self.pc += 4;
+        if 1 << (v & 0x0f) & self.keypad != 0 {
+            self.skip();
         } else {
-            self.pc += 2;
+            self.pc = self.pc.wrapping_add(2);
         }
         73
     }
     /// Op: Skip next instruction if key with the value of v is not pressed.
     fn op_sknp(&mut self, v: u8) -> usize {
-        if 1 << v & self.keypad == 0 {
-            self.pc += 4;
+        if 1 << (v & 0x0f) & self.keypad == 0 {
+            self.skip();
         } else {
-            self.pc += 2;
+            self.pc = self.pc.wrapping_add(2);
         }
         73
     }
 
-    /// Execute the instruction defined by (w0, w1).  Returns the number of microseconds elapsed.
-    fn exec(&mut self, w0: u8, w1: u8) -> Result<usize, Error> {
-        Ok(match w0 & 0xf0 {
-            0x00 => match w1 {
-                0xe0 => self.op_cls(),
-                0xee => self.op_ret(),
-                _ => self.op_call_rca_1802(nnn!(w0, w1)),
-            },
-            0x10 => self.op_jp(nnn!(w0, w1)),
-            0x20 => self.op_call(nnn!(w0, w1)),
-            0x30 => self.op_se(self.v[Reg(lo_nib(w0))], w1),
-            0x40 => self.op_sne(self.v[Reg(lo_nib(w0))], w1),
-            0x50 => self.op_se(self.v[Reg(lo_nib(w0))], self.v[Reg(hi_nib(w1))]),
-            0x60 => self.op_ld(Reg(w0 & 0x0f), w1),
-            0x70 => self.op_add(Reg(w0 & 0x0f), w1, false),
-            0x80 => {
-                let a = Reg(lo_nib(w0));
-                let b = self.v[Reg(hi_nib(w1))];
-                match w1 & 0x0f {
-                    0x00 => self.op_ld(a, b),
-                    0x01 => self.op_or(a, b),
-                    0x02 => self.op_and(a, b),
-                    0x03 => self.op_xor(a, b),
-                    0x04 => self.op_add(a, b, true),
-                    0x05 => self.op_sub(a, b),
-                    0x06 => self.op_shr(a),
-                    0x07 => self.op_subn(a, b),
-                    0x0E => self.op_shl(a),
-                    _ => return Err(Error::InvalidOp(w0, w1)),
-                }
+    /// Execute the instruction.  Returns the number of microseconds elapsed.
+    fn exec(&mut self, inst: Instruction) -> Result<usize, Error> {
+        use Instruction::*;
+        let v = |x: u8| self.v[Reg(x)];
+        Ok(match inst {
+            Cls => self.op_cls(),
+            Ret => self.op_ret()?,
+            Scd(n) if self.schip() => self.op_scd(n),
+            Scu(n) if self.xo() => self.op_scu(n),
+            Scr if self.schip() => self.op_scr(),
+            Scl if self.schip() => self.op_scl(),
+            Exit if self.schip() => self.op_exit(),
+            Low if self.schip() => self.op_hires(false),
+            High if self.schip() => self.op_hires(true),
+            Jp(addr) => self.op_jp(addr),
+            Call(addr) => self.op_call(addr)?,
+            SeByte(x, kk) => self.op_se(v(x), kk),
+            SneByte(x, kk) => self.op_sne(v(x), kk),
+            SeReg(x, y) => self.op_se(v(x), v(y)),
+            Save(x, y) if self.xo() => self.op_ld_i_vxy(x, y)?,
+            Load(x, y) if self.xo() => self.op_ld_vxy_i(x, y)?,
+            LdByte(x, kk) => self.op_ld(Reg(x), kk),
+            AddByte(x, kk) => self.op_add(Reg(x), kk, false),
+            LdReg(x, y) => self.op_ld(Reg(x), v(y)),
+            Or(x, y) => self.op_or(Reg(x), v(y)),
+            And(x, y) => self.op_and(Reg(x), v(y)),
+            Xor(x, y) => self.op_xor(Reg(x), v(y)),
+            AddReg(x, y) => self.op_add(Reg(x), v(y), true),
+            Sub(x, y) => self.op_sub(Reg(x), v(y)),
+            Shr(x, y) => self.op_shr(Reg(x), v(y)),
+            Subn(x, y) => self.op_subn(Reg(x), v(y)),
+            Shl(x, y) => self.op_shl(Reg(x), v(y)),
+            SneReg(x, y) => self.op_sne(v(x), v(y)),
+            LdI(addr) => self.op_ld_i(addr),
+            JpV0(addr) if self.quirks.jump_vx => self.op_jp(v((addr >> 8) as u8) as u16 + addr),
+            JpV0(addr) => self.op_jp(v(0) as u16 + addr),
+            Rnd(x, kk) => self.op_rnd(Reg(x), kk),
+            Drw(x, y, n) => self.op_drw(v(x), v(y), n)?,
+            Skp(x) => self.op_skp(v(x)),
+            Sknp(x) => self.op_sknp(v(x)),
+            LdILong(addr) if self.xo() => self.op_ld_i_long(addr),
+            Plane(n) if self.xo() => self.op_plane(n),
+            Audio if self.xo() => self.op_audio()?,
+            LdVxDt(x) => self.op_ld(Reg(x), self.dt),
+            LdVxK(x) => self.op_ld_vx_k(Reg(x)),
+            LdDtVx(x) => self.op_ld_dt(v(x)),
+            LdStVx(x) => self.op_ld_st(v(x)),
+            AddI(x) => self.op_add16(v(x)),
+            LdF(x) => self.op_ld_f(v(x)),
+            LdHf(x) if self.schip() => self.op_ld_hf(v(x)),
+            LdB(x) => self.op_ld_b(v(x))?,
+            Pitch(x) if self.xo() => self.op_pitch(v(x)),
+            LdIVx(x) => self.op_ld_i_vx(x)?,
+            LdVxI(x) => self.op_ld_vx_i(x)?,
+            LdRVx(x) if self.schip() => self.op_ld_r_vx(x),
+            LdVxR(x) if self.schip() => self.op_ld_vx_r(x),
+            // Calls to machine code routines can't be emulated, and are ignored unless the opcode
+            // policy skips or traps them
+            Sys(_) | Scd(_) | Scu(_) | Scr | Scl | Exit | Low | High
+                if self.quirks.unknown_opcode == OpcodePolicy::Error =>
+            {
+                self.op_call_rca_1802(inst.encode())
+            }
+            // The extensions of other modes
+            Sys(_) | Scd(_) | Scu(_) | Scr | Scl | Exit | Low | High | Save(..) | Load(..)
+            | LdILong(_) | Plane(_) | Audio | LdHf(_) | Pitch(_) | LdRVx(_) | LdVxR(_) => {
+                self.op_unknown(inst.encode())?
             }
-            0x90 => match w1 & 0x0f {
-                0x00 => self.op_sne(self.v[Reg(lo_nib(w0))], self.v[Reg(hi_nib(w1))]),
-                _ => return Err(Error::InvalidOp(w0, w1)),
-            },
-            0xA0 => self.op_ld_i(nnn!(w0, w1)),
-            0xB0 => self.op_jp(self.v[Reg(0)] as u16 + nnn!(w0, w1)),
-            0xC0 => self.op_rnd(Reg(lo_nib(w0)), w1),
-            0xD0 => self.op_drw(self.v[Reg(lo_nib(w0))], self.v[Reg(hi_nib(w1))], lo_nib(w1)),
-            0xE0 => match w1 {
-                0x9E => self.op_skp(self.v[Reg(lo_nib(w0))]),
-                0xA1 => self.op_sknp(self.v[Reg(lo_nib(w0))]),
-                _ => return Err(Error::InvalidOp(w0, w1)),
-            },
-            0xF0 => match w1 {
-                0x07 => self.op_ld(Reg(lo_nib(w0)), self.dt),
-                0x0A => self.op_ld_vx_k(Reg(lo_nib(w0))),
-                0x15 => self.op_ld_dt(self.v[Reg(lo_nib(w0))]),
-                0x18 => self.op_ld_st(self.v[Reg(lo_nib(w0))]),
-                0x1E => self.op_add16(self.v[Reg(lo_nib(w0))]),
-                0x29 => self.op_ld_f(self.v[Reg(lo_nib(w0))]),
-                0x33 => self.op_ld_b(self.v[Reg(lo_nib(w0))]),
-                0x55 => self.op_ld_i_vx(lo_nib(w0)),
-                0x65 => self.op_ld_vx_i(lo_nib(w0)),
-                _ => return Err(Error::InvalidOp(w0, w1)),
-            },
-            _ => return Err(Error::InvalidOp(w0, w1)),
         })
     }
 }
+
+/// Builder of the machines of the unit tests, by default with seed 0, the default quirks, in
+/// CHIP-8 mode and without a program.
+#[cfg(test)]
+pub(crate) struct TestMachine {
+    seed: u64,
+    quirks: Quirks,
+    mode: Mode,
+    rom: std::vec::Vec<u8>,
+}
+
+#[cfg(test)]
+impl TestMachine {
+    pub(crate) fn new() -> Self {
+        Self {
+            seed: 0,
+            quirks: Quirks::default(),
+            mode: Mode::Chip8,
+            rom: std::vec::Vec::new(),
+        }
+    }
+    pub(crate) fn seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+    pub(crate) fn quirks(mut self, quirks: Quirks) -> Self {
+        self.quirks = quirks;
+        self
+    }
+    pub(crate) fn mode(mut self, mode: Mode) -> Self {
+        self.mode = mode;
+        self
+    }
+    /// Append `bytes` to the program
+    pub(crate) fn rom(mut self, bytes: &[u8]) -> Self {
+        self.rom.extend_from_slice(bytes);
+        self
+    }
+    /// Append the big endian `words` to the program
+    pub(crate) fn words(mut self, words: &[u16]) -> Self {
+        for w in words {
+            self.rom.extend_from_slice(&w.to_be_bytes());
+        }
+        self
+    }
+    pub(crate) fn build(self) -> Chip8 {
+        self.build_with_trap(NoTrap)
+    }
+    pub(crate) fn build_with_trap<T: Trap>(self, trap: T) -> Chip8<Pcg32, T> {
+        let chip8 = Chip8::with_trap(self.seed, self.quirks, trap);
+        self.load(chip8)
+    }
+    pub(crate) fn build_with_bus<M: Bus>(self, bus: M) -> Chip8<Pcg32, NoTrap, M> {
+        let rng = Pcg32::seed_from_u64(self.seed);
+        let chip8 = Chip8::with_bus(bus, rng, self.quirks, NoTrap);
+        self.load(chip8)
+    }
+    fn load<T: Trap, M: Bus>(self, mut chip8: Chip8<Pcg32, T, M>) -> Chip8<Pcg32, T, M> {
+        chip8.set_mode(self.mode);
+        chip8.load_rom(&self.rom).unwrap();
+        chip8
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn steps(chip8: &mut Chip8, n: usize) {
+        for _ in 0..n {
+            chip8.step().unwrap();
+        }
+    }
+
+    /// Coordinates of the lit pixels at the current resolution
+    fn lit(chip8: &Chip8) -> std::vec::Vec<(usize, usize)> {
+        let (width, _) = chip8.resolution();
+        chip8
+            .pixels()
+            .enumerate()
+            .filter(|(_, color)| *color != 0)
+            .map(|(pos, _)| (pos % width, pos / width))
+            .collect()
+    }
+
+    #[test]
+    fn resolution() {
+        let mut chip8 = TestMachine::new()
+            .mode(Mode::SuperChip)
+            .words(&[0x6000, 0x6100, 0xf029, 0xd015, 0x00ff, 0x00fe])
+            .build();
+        assert_eq!(chip8.resolution(), (SCREEN_WIDTH, SCREEN_HEIGTH));
+        steps(&mut chip8, 4);
+        assert!(!lit(&chip8).is_empty());
+        // Switching the resolution clears the screen
+        chip8.step().unwrap();
+        assert!(chip8.hires());
+        assert_eq!(
+            chip8.resolution(),
+            (HIRES_SCREEN_WIDTH, HIRES_SCREEN_HEIGTH)
+        );
+        assert!(lit(&chip8).is_empty());
+        chip8.step().unwrap();
+        assert!(!chip8.hires());
+        assert_eq!(chip8.resolution(), (SCREEN_WIDTH, SCREEN_HEIGTH));
+        assert!(lit(&chip8).is_empty());
+    }
+
+    #[test]
+    fn big_sprites() {
+        let program = [
+            0x00ff, // HIGH
+            0xa220, // LD I, sprite
+            0x6078, // LD V0, 120
+            0x613c, // LD V1, 60
+            0xd010, // DRW V0, V1, 0: 16x16 in the bottom right corner, wrapping around
+            0x6000, // LD V0, 0
+            0x6108, // LD V1, 8
+            0xd010, // DRW V0, V1, 0: overlaps the wrapped part
+            0x0000, // padding up to the sprite at 0x220
+            0x0000, 0x0000, 0x0000, 0x0000, 0x0000, 0x0000, 0x0000,
+        ];
+        let mut words = program.to_vec();
+        words.extend(&[0xffff; 16]);
+        let mut chip8 = TestMachine::new()
+            .mode(Mode::SuperChip)
+            .words(&words)
+            .build();
+        steps(&mut chip8, 5);
+        assert_eq!(chip8.v()[0xf], 0);
+        let pixels = lit(&chip8);
+        assert_eq!(pixels.len(), 16 * 16);
+        // Wraps to the left and top edges
+        assert!(pixels.contains(&(120, 60)) && pixels.contains(&(127, 63)));
+        assert!(pixels.contains(&(0, 0)) && pixels.contains(&(7, 11)));
+        assert!(!pixels.contains(&(8, 0)) && !pixels.contains(&(7, 12)));
+        steps(&mut chip8, 3);
+        assert_eq!(chip8.v()[0xf], 1);
+        // The overlapping pixels are erased
+        assert!(!chip8.pixel(0, 8) && !chip8.pixel(7, 11));
+        assert!(chip8.pixel(8, 8) && chip8.pixel(0, 12));
+
+        // Dxy0 draws nothing in CHIP-8 mode
+        let mut chip8 = TestMachine::new()
+            .mode(Mode::Chip8)
+            .words(&[0xa000, 0xd010])
+            .build();
+        steps(&mut chip8, 2);
+        assert!(lit(&chip8).is_empty());
+    }
+
+    #[test]
+    fn scrolling() {
+        for &(hires, width) in [(false, SCREEN_WIDTH), (true, HIRES_SCREEN_WIDTH)].iter() {
+            let resolution = if hires { 0x00ff } else { 0x00fe };
+            let program = [
+                resolution, 0x6008, // LD V0, 8
+                0x6102, // LD V1, 2
+                0xa000, // LD I, 0: the font, "0" is F0 90 90 90 F0
+                0xd011, // DRW V0, V1, 1: pixels 8-11 of row 2
+                0x00c3, // SCD 3
+                0x00fb, // SCR
+                0x00fc, // SCL
+                0x00fc, // SCL
+                0x00fc, // SCL
+                0x00fc, // SCL
+            ];
+            let mut chip8 = TestMachine::new()
+                .mode(Mode::SuperChip)
+                .words(&program)
+                .build();
+            steps(&mut chip8, 5);
+            assert_eq!(lit(&chip8), [(8, 2), (9, 2), (10, 2), (11, 2)]);
+            chip8.step().unwrap();
+            assert_eq!(lit(&chip8), [(8, 5), (9, 5), (10, 5), (11, 5)]);
+            chip8.step().unwrap();
+            assert_eq!(lit(&chip8), [(12, 5), (13, 5), (14, 5), (15, 5)]);
+            steps(&mut chip8, 3);
+            assert_eq!(lit(&chip8), [(0, 5), (1, 5), (2, 5), (3, 5)]);
+            // Pixels scrolled out of the screen are lost
+            chip8.step().unwrap();
+            assert!(lit(&chip8).is_empty(), "width {}", width);
+        }
+    }
+
+    #[test]
+    fn big_digits() {
+        let mut chip8 = TestMachine::new()
+            .mode(Mode::SuperChip)
+            .words(&[0x6007, 0xf030])
+            .build();
+        steps(&mut chip8, 2);
+        assert_eq!(chip8.i(), BIG_SPRITE_CHARS_ADDR + 7 * 10);
+        for (j, b) in BIG_SPRITE_CHARS[7].iter().enumerate() {
+            assert_eq!(chip8.peek(chip8.i() + j as u16), *b);
+        }
+    }
+
+    #[test]
+    fn rpl_flags() {
+        let program = [
+            0x6011, // LD V0, 0x11
+            0x6122, // LD V1, 0x22
+            0x6233, // LD V2, 0x33
+            0xf175, // LD R, V1
+            0x6000, // LD V0, 0
+            0x6100, // LD V1, 0
+            0x6200, // LD V2, 0
+            0xf285, // LD V2, R
+        ];
+        let mut chip8 = TestMachine::new()
+            .mode(Mode::SuperChip)
+            .words(&program)
+            .build();
+        steps(&mut chip8, 8);
+        // V2 wasn't saved, and reads back as 0
+        assert_eq!(chip8.v()[..3], [0x11, 0x22, 0]);
+
+        // Only V0 to V7 are stored
+        let mut chip8 = TestMachine::new()
+            .mode(Mode::SuperChip)
+            .words(&[0x6f01, 0x6701, 0xff75, 0x6f00, 0x6700, 0xff85])
+            .build();
+        steps(&mut chip8, 6);
+        assert_eq!((chip8.v()[7], chip8.v()[0xf]), (1, 0));
+    }
+
+    #[test]
+    fn exit() {
+        let mut chip8 = TestMachine::new()
+            .mode(Mode::SuperChip)
+            .words(&[0x00fd])
+            .build();
+        chip8.frame(0).unwrap();
+        assert!(chip8.exited());
+        assert_eq!(chip8.pc(), 0x200);
+    }
+
+    #[cfg(feature = "xo-chip")]
+    #[test]
+    fn bit_planes() {
+        let program = [
+            0xa000, // LD I, 0: the font, "0" and "1" follow each other
+            0xf201, // PLANE 2
+            0xd005, // DRW V0, V0, 5: "0" in the second plane
+            0xf301, // PLANE 3
+            0x6008, // LD V0, 8
+            0xd005, // DRW V0, V0, 5: "0" in the first plane and "1" in the second one
+            0xf101, // PLANE 1
+            0x00e0, // CLS: only the first plane
+            0x00d2, // SCU 2: only the first plane
+        ];
+        let mut chip8 = TestMachine::new()
+            .mode(Mode::XoChip)
+            .words(&program)
+            .build();
+        steps(&mut chip8, 3);
+        assert_eq!((chip8.color(0, 0), chip8.color(3, 0)), (2, 2));
+        steps(&mut chip8, 3);
+        // The top rows are F0 for "0" and 20 for "1"
+        assert_eq!((chip8.color(8, 8), chip8.color(10, 8)), (1, 3));
+        assert_eq!(chip8.color(9, 12), 3);
+        assert_eq!(chip8.v()[0xf], 0);
+        steps(&mut chip8, 2);
+        assert_eq!((chip8.color(0, 0), chip8.color(10, 8)), (2, 2));
+        steps(&mut chip8, 1);
+        assert_eq!(chip8.color(0, 0), 2);
+        assert!(chip8.plane(0).iter().all(|b| *b == 0));
+    }
+
+    #[cfg(feature = "xo-chip")]
+    #[test]
+    fn register_ranges() {
+        let program = [
+            0x6211, 0x6322, 0x6433, // LD V2..V4
+            0xa300, // LD I, 0x300
+            0x5242, // SAVE V2, V4
+            0x5423, // LOAD V4, V2: reversed
+            0xf000, 0x1234, // LD I, LONG 0x1234
+            0x3000, // SE V0, 0: skips the long load
+            0xf000, 0x5678, // LD I, LONG 0x5678
+        ];
+        let mut chip8 = TestMachine::new()
+            .mode(Mode::XoChip)
+            .words(&program)
+            .build();
+        steps(&mut chip8, 5);
+        assert_eq!(chip8.i(), 0x300);
+        assert_eq!(
+            [chip8.peek(0x300), chip8.peek(0x301), chip8.peek(0x302)],
+            [0x11, 0x22, 0x33]
+        );
+        chip8.step().unwrap();
+        assert_eq!(chip8.v()[2..5], [0x33, 0x22, 0x11]);
+        chip8.step().unwrap();
+        assert_eq!((chip8.i(), chip8.pc()), (0x1234, 0x210));
+        chip8.step().unwrap();
+        assert_eq!(chip8.pc(), 0x216);
+    }
+
+    #[cfg(feature = "xo-chip")]
+    #[test]
+    fn audio_registers() {
+        let mut chip8 = TestMachine::new()
+            .mode(Mode::XoChip)
+            .words(&[0xa000, 0xf002, 0x6078, 0xf03a])
+            .build();
+        assert_eq!(chip8.pitch(), 64);
+        steps(&mut chip8, 4);
+        for (j, b) in chip8.audio_pattern().iter().enumerate() {
+            assert_eq!(*b, chip8.peek(j as u16));
+        }
+        assert_eq!(chip8.pitch(), 0x78);
+
+        // The XO-CHIP instructions are unknown in the other modes
+        for opcode in [0xf002u16, 0xf03a, 0xf201, 0x5122].iter() {
+            let mut chip8 = TestMachine::new()
+                .mode(Mode::SuperChip)
+                .words(&[*opcode])
+                .build();
+            assert!(
+                matches!(chip8.step(), Err(Error::InvalidOp(_))),
+                "{:04x}",
+                opcode
+            );
+        }
+    }
+
+    /// Machine with `policy` running `words` with I at the last byte of memory
+    fn at_end(policy: MemoryPolicy, words: &[u16]) -> Chip8 {
+        let mut chip8 = TestMachine::new().mode(Mode::Chip8).words(words).build();
+        chip8.quirks = Quirks {
+            memory_policy: policy,
+            ..Quirks::default()
+        };
+        chip8.i = (MEM_SIZE - 1) as u16;
+        chip8
+    }
+
+    #[test]
+    fn memory_policy() {
+        // LD V0, 1; LD V1, 2; LD [I], V1
+        let store = [0x6001, 0x6102, 0xf155];
+        let mut chip8 = at_end(MemoryPolicy::Wrap, &store);
+        steps(&mut chip8, 3);
+        assert_eq!(chip8.peek((MEM_SIZE - 1) as u16), 1);
+        assert_eq!(chip8.peek(0), 2);
+
+        let mut chip8 = at_end(MemoryPolicy::Clamp, &store);
+        steps(&mut chip8, 3);
+        assert_eq!(chip8.peek((MEM_SIZE - 1) as u16), 2);
+        assert_eq!(chip8.peek(0), 0xf0);
+
+        let mut chip8 = at_end(MemoryPolicy::Error, &store);
+        steps(&mut chip8, 2);
+        match chip8.step() {
+            Err(Error::MemoryOutOfBounds { addr, fault }) => {
+                assert_eq!(addr, MEM_SIZE);
+                assert_eq!(fault.pc, 0x204);
+            }
+            r => panic!("{:?}", r),
+        }
+        assert_eq!(chip8.peek(0), 0xf0);
+
+        // LD V1, [I]
+        let mut chip8 = at_end(MemoryPolicy::Wrap, &[0xf165]);
+        steps(&mut chip8, 1);
+        assert_eq!(chip8.v()[1], 0xf0);
+        let mut chip8 = at_end(MemoryPolicy::Clamp, &[0xf165]);
+        steps(&mut chip8, 1);
+        assert_eq!(chip8.v()[1], chip8.v()[0]);
+        let mut chip8 = at_end(MemoryPolicy::Error, &[0xf165]);
+        assert!(chip8.step().is_err());
+
+        // A sprite read past the end
+        let mut chip8 = at_end(MemoryPolicy::Error, &[0xd002]);
+        assert!(chip8.step().is_err());
+        let mut chip8 = at_end(MemoryPolicy::Wrap, &[0xd002]);
+        steps(&mut chip8, 1);
+        // Second row is the top of the "0" glyph
+        assert_eq!(lit(&chip8)[..4], [(0, 1), (1, 1), (2, 1), (3, 1)]);
+    }
+}
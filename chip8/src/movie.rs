@@ -0,0 +1,456 @@
+//! Input movies: recordings of the keypad of every frame, replayed deterministically.
+//!
+//! A movie starts from a fresh machine built from its header, so that running the same frames
+//! with the same keypad reproduces the same machine on every platform.  Checksums of the machine
+//! are recorded periodically to detect when a replay diverges from the recording.
+//!
+//! The format is little endian:
+//!
+//! | Field              | Size |
+//! |--------------------|------|
+//! | Magic `C8MV`       | 4    |
+//! | Version            | 1    |
+//! | Rom length         | 4    |
+//! | Rom hash (FNV-1a)  | 8    |
+//! | Seed               | 8    |
+//! | Mode               | 1    |
+//! | Quirks             | 7    |
+//! | Checksum interval  | 2    |
+//! | Frames             | ...  |
+//!
+//! Each frame is the keypad passed to `Chip8::frame`, followed by the checksum of the machine
+//! after the frame on every frame that is a multiple of the checksum interval.
+
+use super::state::{mode_from_u8, mode_to_u8, timing_from_u8, timing_to_u8, Reader, Sink, Writer};
+use super::*;
+
+const MOVIE_MAGIC: [u8; 4] = *b"C8MV";
+const MOVIE_VERSION: u8 = 1;
+
+/// Size in bytes of the header of a movie
+pub const MOVIE_HEADER_SIZE: usize = 4 + 1 + 4 + 8 + 8 + 1 + 7 + 2;
+
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0100_0000_01b3;
+
+/// Hash the bytes with 64 bit FNV-1a, starting from `hash`.
+fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+    bytes
+        .iter()
+        .fold(hash, |h, b| (h ^ *b as u64).wrapping_mul(FNV_PRIME))
+}
+
+/// Hash identifying a rom in a movie
+pub fn rom_hash(rom: &[u8]) -> u64 {
+    fnv1a(FNV_OFFSET, rom)
+}
+
+/// Settings of the machine that a movie was recorded on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub rom_len: u32,
+    pub rom_hash: u64,
+    /// Seed of the random number generator
+    pub seed: u64,
+    pub mode: Mode,
+    pub quirks: Quirks,
+    /// Frames between checksums of the machine, or 0 for none
+    pub checksum_interval: u16,
+}
+
+impl Header {
+    /// Header for a movie of `rom` on a machine created with `Chip8::with_quirks(seed, quirks)` in
+    /// `mode`.
+    pub fn new(rom: &[u8], seed: u64, mode: Mode, quirks: Quirks, checksum_interval: u16) -> Self {
+        Self {
+            rom_len: rom.len() as u32,
+            rom_hash: rom_hash(rom),
+            seed,
+            mode,
+            quirks,
+            checksum_interval,
+        }
+    }
+    /// Create the machine that the movie starts from, with `rom` loaded.  Fails with
+    /// `Error::MovieRomMismatch` if `rom` isn't the one the movie was recorded with.
+    pub fn machine(&self, rom: &[u8]) -> Result<Chip8, Error> {
+        if rom.len() as u32 != self.rom_len || rom_hash(rom) != self.rom_hash {
+            return Err(Error::MovieRomMismatch);
+        }
+        let mut chip8 = Chip8::with_quirks(self.seed, self.quirks);
+        chip8.set_mode(self.mode);
+        chip8.load_rom(rom)?;
+        Ok(chip8)
+    }
+    fn write(&self, w: &mut Writer) {
+        w.bytes(&MOVIE_MAGIC);
+        w.u8(MOVIE_VERSION);
+        w.u32(self.rom_len);
+        w.u64(self.rom_hash);
+        w.u64(self.seed);
+        w.u8(mode_to_u8(self.mode));
+        let q = &self.quirks;
+        let flags = [
+            q.shift_vy,
+            q.load_store_inc_i,
+            q.vf_reset,
+            q.jump_vx,
+            q.clip_sprites,
+            q.display_wait,
+            q.key_release,
+        ];
+        w.u8(flags
+            .iter()
+            .enumerate()
+            .fold(0, |b, (i, f)| b | (*f as u8) << i));
+        w.u8(q.stack_depth as u8);
+        w.u8(match q.memory_policy {
+            MemoryPolicy::Wrap => 0,
+            MemoryPolicy::Clamp => 1,
+            MemoryPolicy::Error => 2,
+        });
+        w.u8(match q.unknown_opcode {
+            OpcodePolicy::Error => 0,
+            OpcodePolicy::Nop => 1,
+            OpcodePolicy::Trap => 2,
+        });
+        let (timing, tickrate) = timing_to_u8(q.timing);
+        w.u8(timing);
+        w.u16(tickrate);
+        w.u16(self.checksum_interval);
+    }
+    fn read(r: &mut Reader) -> Result<Self, Error> {
+        if r.bytes(4) != MOVIE_MAGIC || r.u8() != MOVIE_VERSION {
+            return Err(Error::InvalidMovie);
+        }
+        let rom_len = r.u32();
+        let rom_hash = r.u64();
+        let seed = r.u64();
+        let mode = mode_from_u8(r.u8()).map_err(|_| Error::InvalidMovie)?;
+        let flags = r.u8();
+        let flag = |i: u8| flags & 1 << i != 0;
+        let stack_depth = r.u8() as usize;
+        if stack_depth > MAX_STACK_DEPTH {
+            return Err(Error::InvalidMovie);
+        }
+        let memory_policy = match r.u8() {
+            0 => MemoryPolicy::Wrap,
+            1 => MemoryPolicy::Clamp,
+            2 => MemoryPolicy::Error,
+            _ => return Err(Error::InvalidMovie),
+        };
+        let unknown_opcode = match r.u8() {
+            0 => OpcodePolicy::Error,
+            1 => OpcodePolicy::Nop,
+            2 => OpcodePolicy::Trap,
+            _ => return Err(Error::InvalidMovie),
+        };
+        let (timing, tickrate) = (r.u8(), r.u16());
+        let timing = timing_from_u8(timing, tickrate).map_err(|_| Error::InvalidMovie)?;
+        Ok(Self {
+            rom_len,
+            rom_hash,
+            seed,
+            mode,
+            quirks: Quirks {
+                shift_vy: flag(0),
+                load_store_inc_i: flag(1),
+                vf_reset: flag(2),
+                jump_vx: flag(3),
+                clip_sprites: flag(4),
+                display_wait: flag(5),
+                key_release: flag(6),
+                stack_depth,
+                memory_policy,
+                unknown_opcode,
+                timing,
+            },
+            checksum_interval: r.u16(),
+        })
+    }
+    /// Whether frame `frame`, counting from 1, is followed by a checksum
+    fn has_checksum(&self, frame: u32) -> bool {
+        self.checksum_interval != 0 && frame % self.checksum_interval as u32 == 0
+    }
+}
+
+/// Records the frames of a movie into a caller provided buffer.
+pub struct Recorder<'a> {
+    buf: &'a mut [u8],
+    len: usize, // Bytes of the movie written
+    header: Header,
+    frames: u32, // Frames recorded
+}
+
+impl<'a> Recorder<'a> {
+    /// Start a movie in `buf` for a machine created as described by `header`.
+    pub fn new(buf: &'a mut [u8], header: Header) -> Result<Self, Error> {
+        if buf.len() < MOVIE_HEADER_SIZE {
+            return Err(Error::MovieBufferFull);
+        }
+        let mut w = Writer { buf, pos: 0 };
+        header.write(&mut w);
+        let len = w.pos;
+        Ok(Self {
+            buf,
+            len,
+            header,
+            frames: 0,
+        })
+    }
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+    /// Number of frames recorded
+    pub fn frames(&self) -> u32 {
+        self.frames
+    }
+    /// The movie recorded so far
+    pub fn data(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+    /// Run a frame of `chip8` with `keypad` and record it.  Fails with `Error::MovieBufferFull`
+    /// without running the frame if the buffer can't hold it.
+    pub fn frame<R: RngCore, T: Trap, M: Bus>(
+        &mut self,
+        chip8: &mut Chip8<R, T, M>,
+        keypad: u16,
+    ) -> Result<(), Error> {
+        let frame = self.frames + 1;
+        let size = if self.header.has_checksum(frame) {
+            6
+        } else {
+            2
+        };
+        if self.len + size > self.buf.len() {
+            return Err(Error::MovieBufferFull);
+        }
+        let mut w = Writer {
+            buf: self.buf,
+            pos: self.len,
+        };
+        w.u16(keypad);
+        // A failing frame is kept with its checksum, so that the replay reproduces the failure
+        // and stays in step with the movie if it goes on
+        let result = chip8.frame(keypad);
+        if self.header.has_checksum(frame) {
+            w.u32(chip8.checksum());
+        }
+        self.len = w.pos;
+        self.frames = frame;
+        result
+    }
+}
+
+/// Replays the frames of a movie.
+pub struct Player<'a> {
+    r: Reader<'a>,
+    header: Header,
+    frames: u32, // Frames replayed
+}
+
+impl<'a> Player<'a> {
+    /// Open the movie in `buf`.
+    pub fn new(buf: &'a [u8]) -> Result<Self, Error> {
+        if buf.len() < MOVIE_HEADER_SIZE {
+            return Err(Error::InvalidMovie);
+        }
+        let mut r = Reader { buf, pos: 0 };
+        let header = Header::read(&mut r)?;
+        Ok(Self {
+            r,
+            header,
+            frames: 0,
+        })
+    }
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+    /// Number of frames replayed
+    pub fn frames(&self) -> u32 {
+        self.frames
+    }
+    /// Whether all the frames have been replayed
+    pub fn finished(&self) -> bool {
+        self.r.remaining() < 2
+    }
+    /// Run the next frame of the movie on `chip8`.  Returns false without running anything at the
+    /// end of the movie, and fails with `Error::MovieDesync` if the machine doesn't match the
+    /// recorded checksum.
+    pub fn frame<R: RngCore, T: Trap, M: Bus>(
+        &mut self,
+        chip8: &mut Chip8<R, T, M>,
+    ) -> Result<bool, Error> {
+        if self.finished() {
+            return Ok(false);
+        }
+        let keypad = self.r.u16();
+        self.frames += 1;
+        let result = chip8.frame(keypad);
+        // The checksum is missing if the recording stopped at this frame.  It's consumed even if
+        // the frame fails, as the recorder writes it, to stay in step with the movie.
+        let recorded = if self.header.has_checksum(self.frames) && self.r.remaining() >= 4 {
+            Some(self.r.u32())
+        } else {
+            None
+        };
+        result?;
+        if matches!(recorded, Some(checksum) if checksum != chip8.checksum()) {
+            return Err(Error::MovieDesync(self.frames));
+        }
+        Ok(true)
+    }
+}
+
+impl<R: RngCore, T: Trap, M: Bus> Chip8<R, T, M> {
+    /// Checksum of the state visible to the program: memory, registers, timers, stack and
+    /// framebuffers.
+    pub fn checksum(&self) -> u32 {
+        let mut h = FNV_OFFSET;
+        for addr in 0..MEM_SIZE {
+            h = fnv1a(h, &[self.mem.peek(addr)]);
+        }
+        h = fnv1a(h, &self.v.0);
+        h = fnv1a(h, &self.i.to_le_bytes());
+        h = fnv1a(h, &self.pc.to_le_bytes());
+        for addr in self.stack.iter() {
+            h = fnv1a(h, &addr.to_le_bytes());
+        }
+        h = fnv1a(
+            h,
+            &[self.sp, self.dt, self.st, self.hires as u8, self.planes],
+        );
+        for plane in self.fb.iter() {
+            h = fnv1a(h, plane);
+        }
+        for plane in self.fb_hires.iter() {
+            h = fnv1a(h, plane);
+        }
+        (h ^ h >> 32) as u32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec;
+
+    /// Adds 1 to V2 on the frames key 5 is held, and keeps drawing random numbers into V0
+    const ROM: [u8; 10] = [
+        0xc0, 0xff, // RND V0, 0xff
+        0x61, 0x05, // LD V1, 5
+        0xe1, 0xa1, // SKNP V1
+        0x72, 0x01, // ADD V2, 1
+        0x12, 0x00, // JP 0x200
+    ];
+
+    fn keypad(frame: u32) -> u16 {
+        if frame % 7 < 3 {
+            1 << 5
+        } else {
+            0
+        }
+    }
+
+    fn record(rom: &[u8], frames: u32, interval: u16) -> (vec::Vec<u8>, u32) {
+        let header = Header::new(rom, 42, Mode::Chip8, Quirks::default(), interval);
+        let mut chip8 = header.machine(rom).unwrap();
+        let mut buf = vec![0; 0x1000];
+        let mut recorder = Recorder::new(&mut buf, header).unwrap();
+        for frame in 0..frames {
+            recorder.frame(&mut chip8, keypad(frame)).unwrap();
+        }
+        let len = recorder.data().len();
+        buf.truncate(len);
+        (buf, chip8.checksum())
+    }
+
+    #[test]
+    fn replay() {
+        let (movie, checksum) = record(&ROM, 30, 10);
+        // 30 keypads and 3 checksums
+        assert_eq!(movie.len(), MOVIE_HEADER_SIZE + 30 * 2 + 3 * 4);
+        let mut player = Player::new(&movie).unwrap();
+        assert_eq!(player.header().seed, 42);
+        let mut chip8 = player.header().machine(&ROM).unwrap();
+        while player.frame(&mut chip8).unwrap() {}
+        assert!(player.finished());
+        assert_eq!(player.frames(), 30);
+        assert_eq!(chip8.checksum(), checksum);
+    }
+
+    #[test]
+    fn desync() {
+        let (mut movie, _) = record(&ROM, 30, 10);
+        // Release key 5 on the 15th frame, after the first checksum
+        let at = MOVIE_HEADER_SIZE + 10 * 2 + 4 + 4 * 2;
+        assert_eq!(movie[at], 0x20);
+        movie[at] = 0;
+        let mut player = Player::new(&movie).unwrap();
+        let mut chip8 = player.header().machine(&ROM).unwrap();
+        for _ in 0..19 {
+            assert!(player.frame(&mut chip8).unwrap());
+        }
+        assert!(matches!(
+            player.frame(&mut chip8),
+            Err(Error::MovieDesync(20))
+        ));
+    }
+
+    #[test]
+    fn rom_mismatch() {
+        let (movie, _) = record(&ROM, 1, 0);
+        let player = Player::new(&movie).unwrap();
+        let mut rom = ROM;
+        rom[1] = 0x0f;
+        assert!(matches!(
+            player.header().machine(&rom),
+            Err(Error::MovieRomMismatch)
+        ));
+        assert!(matches!(Player::new(&movie[1..]), Err(Error::InvalidMovie)));
+    }
+
+    #[test]
+    fn failing_frames() {
+        // The first instruction is invalid, so every frame fails
+        let rom = [0x51, 0x21];
+        let header = Header::new(&rom, 0, Mode::Chip8, Quirks::default(), 1);
+        let mut chip8 = header.machine(&rom).unwrap();
+        let mut buf = [0; 0x100];
+        let mut recorder = Recorder::new(&mut buf, header).unwrap();
+        for frame in 0..3 {
+            assert!(matches!(
+                recorder.frame(&mut chip8, keypad(frame)),
+                Err(Error::InvalidOp(_))
+            ));
+        }
+        assert_eq!(recorder.frames(), 3);
+        let len = recorder.data().len();
+        assert_eq!(len, MOVIE_HEADER_SIZE + 3 * (2 + 4));
+        // The replay reproduces the failures without reading keypads as checksums
+        let mut player = Player::new(&buf[..len]).unwrap();
+        let mut chip8 = player.header().machine(&rom).unwrap();
+        for _ in 0..3 {
+            assert!(matches!(player.frame(&mut chip8), Err(Error::InvalidOp(_))));
+        }
+        assert!(player.finished());
+        assert_eq!(player.frames(), 3);
+    }
+
+    #[test]
+    fn buffer_full() {
+        let header = Header::new(&ROM, 0, Mode::Chip8, Quirks::default(), 2);
+        let mut chip8 = header.machine(&ROM).unwrap();
+        let mut buf = [0; MOVIE_HEADER_SIZE + 2 + 5];
+        let mut recorder = Recorder::new(&mut buf, header).unwrap();
+        recorder.frame(&mut chip8, 0).unwrap();
+        let pc = chip8.pc;
+        // The second frame needs room for its checksum too, and isn't run
+        assert!(matches!(
+            recorder.frame(&mut chip8, 0),
+            Err(Error::MovieBufferFull)
+        ));
+        assert_eq!(recorder.frames(), 1);
+        assert_eq!(chip8.pc, pc);
+    }
+}
@@ -0,0 +1,263 @@
+//! Compatibility quirks: behaviours on which the different CHIP-8 interpreters disagree.
+//!
+//! See https://github.com/Timendus/chip8-test-suite#quirks-test for a description of each one.
+
+use super::{Timing, MAX_STACK_DEPTH};
+
+/// Handling of instructions that access memory past its end through I.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryPolicy {
+    /// Wrap around to the start of memory, like the 12 bit addresses of the COSMAC VIP.
+    Wrap,
+    /// Access the last byte of memory instead.
+    Clamp,
+    /// Stop with `Error::MemoryOutOfBounds`.
+    Error,
+}
+
+/// Handling of opcodes that aren't valid in the current mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpcodePolicy {
+    /// Stop with `Error::InvalidOp`.  Calls to machine code routines (0NNN) are ignored without
+    /// moving the PC instead, as before the policy was configurable.
+    Error,
+    /// Skip the opcode, like interpreters that ignore junk in roms.  Calls to machine code
+    /// routines are skipped too.
+    Nop,
+    /// Hand the opcode, or the call to machine code, to the `Trap` of the machine.
+    Trap,
+}
+
+/// Configuration of the behaviours that differ between interpreters.
+///
+/// The default matches the behaviour of this crate before the quirks were configurable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quirks {
+    /// 8XY6/8XYE shift Vy and store the result in Vx, instead of shifting Vx in place.
+    pub shift_vy: bool,
+    /// Fx55/Fx65 leave I incremented by x + 1.
+    pub load_store_inc_i: bool,
+    /// 8XY1/8XY2/8XY3 reset VF to 0.
+    pub vf_reset: bool,
+    /// BNNN jumps to XNN + Vx instead of NNN + V0.
+    pub jump_vx: bool,
+    /// Sprites are clipped at the edges of the screen instead of wrapping around.
+    pub clip_sprites: bool,
+    /// DXYN waits for the vertical blank interrupt, so that at most one sprite is drawn per frame.
+    pub display_wait: bool,
+    /// FX0A waits for a key to be pressed and released, instead of taking the first key held.
+    pub key_release: bool,
+    /// Number of nested subroutine calls before `Error::StackOverflow`, up to `MAX_STACK_DEPTH`.
+    pub stack_depth: usize,
+    /// What to do when I points past the end of memory.
+    pub memory_policy: MemoryPolicy,
+    /// What to do with opcodes that aren't valid in the current mode.
+    pub unknown_opcode: OpcodePolicy,
+    /// How long each instruction takes.
+    pub timing: Timing,
+}
+
+impl Default for Quirks {
+    fn default() -> Self {
+        Self {
+            shift_vy: false,
+            load_store_inc_i: false,
+            vf_reset: false,
+            jump_vx: false,
+            clip_sprites: false,
+            display_wait: false,
+            key_release: false,
+            stack_depth: 16,
+            memory_policy: MemoryPolicy::Wrap,
+            unknown_opcode: OpcodePolicy::Error,
+            timing: Timing::Estimated,
+        }
+    }
+}
+
+impl Quirks {
+    /// Original CHIP-8 interpreter on the COSMAC VIP.
+    pub fn cosmac_vip() -> Self {
+        Self {
+            shift_vy: true,
+            load_store_inc_i: true,
+            vf_reset: true,
+            jump_vx: false,
+            clip_sprites: true,
+            display_wait: true,
+            key_release: true,
+            stack_depth: 12,
+            memory_policy: MemoryPolicy::Wrap,
+            unknown_opcode: OpcodePolicy::Error,
+            timing: Timing::CosmacVip,
+        }
+    }
+    /// CHIP-48 on the HP-48 calculators.
+    pub fn chip48() -> Self {
+        Self {
+            shift_vy: false,
+            load_store_inc_i: true,
+            vf_reset: false,
+            jump_vx: true,
+            clip_sprites: true,
+            display_wait: false,
+            key_release: false,
+            stack_depth: 16,
+            memory_policy: MemoryPolicy::Wrap,
+            unknown_opcode: OpcodePolicy::Error,
+            timing: Timing::Estimated,
+        }
+    }
+    /// SUPER-CHIP 1.1 on the HP-48 calculators.
+    pub fn super_chip() -> Self {
+        Self {
+            shift_vy: false,
+            load_store_inc_i: false,
+            vf_reset: false,
+            jump_vx: true,
+            clip_sprites: true,
+            display_wait: false,
+            key_release: false,
+            stack_depth: 16,
+            memory_policy: MemoryPolicy::Wrap,
+            unknown_opcode: OpcodePolicy::Error,
+            timing: Timing::Estimated,
+        }
+    }
+    /// Modern interpreters such as Octo, also used for XO-CHIP.
+    pub fn modern() -> Self {
+        Self {
+            shift_vy: true,
+            load_store_inc_i: true,
+            vf_reset: false,
+            jump_vx: false,
+            clip_sprites: false,
+            display_wait: false,
+            key_release: true,
+            stack_depth: MAX_STACK_DEPTH,
+            memory_policy: MemoryPolicy::Wrap,
+            unknown_opcode: OpcodePolicy::Error,
+            timing: Timing::Estimated,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Chip8, Error, TestMachine};
+
+    fn run(quirks: Quirks, words: &[u16], steps: usize) -> Chip8 {
+        let mut chip8 = TestMachine::new().quirks(quirks).words(words).build();
+        for _ in 0..steps {
+            chip8.step().unwrap();
+        }
+        chip8
+    }
+
+    /// Quirks measured by running programs that depend on them
+    fn observe(quirks: Quirks) -> Quirks {
+        // SHR V1, V2 with V1 = 1 and V2 = 4
+        let shift_vy = run(quirks, &[0x6101, 0x6204, 0x8126], 3).v()[1] == 2;
+        // LD [I], V1 at 0x300
+        let load_store_inc_i = run(quirks, &[0xa300, 0xf155], 2).i() == 0x302;
+        // OR V0, V1 with VF = 5
+        let vf_reset = run(quirks, &[0x6f05, 0x8011], 2).v()[0xf] == 0;
+        // JP V0, 0x104 with V0 = 0 and V1 = 4
+        let jump_vx = run(quirks, &[0x6104, 0xb104], 2).pc() == 0x108;
+        // "0" drawn at the right edge
+        let clip_sprites = !run(quirks, &[0x603e, 0xa000, 0xd015], 3).pixel(0, 0);
+        // Count the sprites drawn in a frame of 20 instructions
+        let tickrate = Quirks {
+            timing: Timing::Tickrate(20),
+            ..quirks
+        };
+        let mut chip8 = TestMachine::new()
+            .quirks(tickrate)
+            .words(&[0xa000, 0xd005, 0x7101, 0x1202])
+            .build();
+        chip8.frame(0).unwrap();
+        let display_wait = chip8.v()[1] <= 1;
+        // LD V0, K while key 5 is held
+        let mut chip8 = TestMachine::new()
+            .quirks(quirks)
+            .words(&[0xf00a, 0x1202])
+            .build();
+        chip8.frame(1 << 5).unwrap();
+        let key_release = chip8.pc() == 0x200;
+        // Recursive call
+        let mut chip8 = TestMachine::new().quirks(quirks).words(&[0x2200]).build();
+        let stack_depth = match chip8.frame(0) {
+            Err(Error::StackOverflow { fault, .. }) => fault.stack().len(),
+            _ => 0,
+        };
+        Quirks {
+            shift_vy,
+            load_store_inc_i,
+            vf_reset,
+            jump_vx,
+            clip_sprites,
+            display_wait,
+            key_release,
+            stack_depth,
+            ..quirks
+        }
+    }
+
+    #[test]
+    fn presets() {
+        let presets = [
+            ("default", Quirks::default()),
+            ("cosmac_vip", Quirks::cosmac_vip()),
+            ("chip48", Quirks::chip48()),
+            ("super_chip", Quirks::super_chip()),
+            ("modern", Quirks::modern()),
+        ];
+        for (name, quirks) in presets.iter() {
+            assert_eq!(observe(*quirks), *quirks, "{}", name);
+        }
+    }
+
+    #[test]
+    fn each_quirk() {
+        // Every quirk can be observed on its own against the default
+        let base = Quirks::default();
+        let cases = [
+            Quirks {
+                shift_vy: true,
+                ..base
+            },
+            Quirks {
+                load_store_inc_i: true,
+                ..base
+            },
+            Quirks {
+                vf_reset: true,
+                ..base
+            },
+            Quirks {
+                jump_vx: true,
+                ..base
+            },
+            Quirks {
+                clip_sprites: true,
+                ..base
+            },
+            Quirks {
+                display_wait: true,
+                ..base
+            },
+            Quirks {
+                key_release: true,
+                ..base
+            },
+            Quirks {
+                stack_depth: 3,
+                ..base
+            },
+        ];
+        for quirks in cases.iter() {
+            assert_eq!(observe(*quirks), *quirks);
+        }
+    }
+}
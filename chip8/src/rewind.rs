@@ -0,0 +1,364 @@
+//! Rewind buffer.
+//!
+//! Save states are recorded every frame into a caller provided storage.  Only the latest state is
+//! kept in full: the history is a ring buffer of deltas against the following state, so that the
+//! bytes that didn't change between two frames take almost no space.  When the storage is full,
+//! the oldest deltas are dropped.
+//!
+//! A delta is the XOR of two consecutive states, run length encoded as a sequence of
+//! `(zeros, literals)` pairs of LEB128 lengths, each followed by `literals` bytes.  The new state
+//! is encoded as it is serialized, against the latest one, so runs of literals are split every
+//! `MAX_LITERALS` bytes.  An entry in the ring buffer is the encoded delta surrounded by its
+//! length as a little endian `u32`, so that entries can be dropped from the oldest end and popped
+//! from the newest end.
+
+use super::state::Sink;
+use super::*;
+
+/// Bytes used by the length before and after each entry in the ring buffer
+const ENTRY_OVERHEAD: usize = 8;
+
+/// Longest run of literals in a delta
+const MAX_LITERALS: usize = 32;
+
+/// Bounded history of machine states, one per frame, to step the machine backwards in time.
+pub struct Rewind<'a> {
+    latest: &'a mut [u8], // Latest recorded state
+    ring: &'a mut [u8],   // Ring buffer of deltas
+    start: usize,         // Position of the oldest entry
+    used: usize,          // Bytes used in the ring buffer
+    entries: usize,       // Number of entries in the ring buffer
+    recorded: bool,       // Whether latest holds a state
+}
+
+/// Encoder of the delta between `base` and the state written to it, which calls `f` with every
+/// byte of the encoded delta.
+struct DeltaEncoder<'b, F: FnMut(u8)> {
+    base: &'b [u8],
+    pos: usize,
+    zeros: usize,
+    literals: [u8; MAX_LITERALS],
+    n: usize,
+    len: usize,
+    f: F,
+}
+
+impl<'b, F: FnMut(u8)> DeltaEncoder<'b, F> {
+    fn new(base: &'b [u8], f: F) -> Self {
+        Self {
+            base,
+            pos: 0,
+            zeros: 0,
+            literals: [0; MAX_LITERALS],
+            n: 0,
+            len: 0,
+            f,
+        }
+    }
+
+    fn varint(&mut self, mut v: usize) {
+        loop {
+            let byte = (v & 0x7f) as u8;
+            v >>= 7;
+            self.len += 1;
+            if v == 0 {
+                (self.f)(byte);
+                break;
+            }
+            (self.f)(byte | 0x80);
+        }
+    }
+
+    fn flush(&mut self) {
+        self.varint(self.zeros);
+        self.varint(self.n);
+        for i in 0..self.n {
+            (self.f)(self.literals[i]);
+        }
+        self.len += self.n;
+        self.zeros = 0;
+        self.n = 0;
+    }
+
+    /// Flush the pending literals and return the encoded length.  Trailing zeros are implied.
+    fn finish(mut self) -> usize {
+        if self.n != 0 {
+            self.flush();
+        }
+        self.len
+    }
+}
+
+impl<'b, F: FnMut(u8)> Sink for DeltaEncoder<'b, F> {
+    fn bytes(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            let x = self.base[self.pos] ^ byte;
+            self.pos += 1;
+            if x == 0 {
+                if self.n != 0 {
+                    self.flush();
+                }
+                self.zeros += 1;
+            } else {
+                if self.n == MAX_LITERALS {
+                    self.flush();
+                }
+                self.literals[self.n] = x;
+                self.n += 1;
+            }
+        }
+    }
+}
+
+impl<'a> Rewind<'a> {
+    /// Create a rewind buffer using `storage`, which must fit a save state plus the history.
+    pub fn new(storage: &'a mut [u8]) -> Result<Self, Error> {
+        if storage.len() < STATE_SIZE + ENTRY_OVERHEAD {
+            return Err(Error::StateBufferTooSmall(storage.len()));
+        }
+        let (latest, ring) = storage.split_at_mut(STATE_SIZE);
+        Ok(Self {
+            latest,
+            ring,
+            start: 0,
+            used: 0,
+            entries: 0,
+            recorded: false,
+        })
+    }
+
+    /// Number of frames that can be rewound
+    pub fn len(&self) -> usize {
+        self.entries
+    }
+
+    /// Whether there are no frames to rewind
+    pub fn is_empty(&self) -> bool {
+        self.entries == 0
+    }
+
+    /// Drop the whole history
+    pub fn clear(&mut self) {
+        self.start = 0;
+        self.used = 0;
+        self.entries = 0;
+        self.recorded = false;
+    }
+
+    fn ring_write(&mut self, pos: usize, byte: u8) {
+        let len = self.ring.len();
+        self.ring[pos % len] = byte;
+    }
+
+    fn ring_read(&self, pos: usize) -> u8 {
+        self.ring[pos % self.ring.len()]
+    }
+
+    fn ring_read_u32(&self, pos: usize) -> usize {
+        let mut b = [0; 4];
+        for (i, byte) in b.iter_mut().enumerate() {
+            *byte = self.ring_read(pos + i);
+        }
+        u32::from_le_bytes(b) as usize
+    }
+
+    fn ring_write_u32(&mut self, pos: usize, v: usize) {
+        for (i, byte) in (v as u32).to_le_bytes().iter().enumerate() {
+            self.ring_write(pos + i, *byte);
+        }
+    }
+
+    /// Drop the oldest entry
+    fn drop_oldest(&mut self) {
+        let len = self.ring_read_u32(self.start);
+        self.start = (self.start + len + ENTRY_OVERHEAD) % self.ring.len();
+        self.used -= len + ENTRY_OVERHEAD;
+        self.entries -= 1;
+    }
+
+    /// Record the current state of the machine.  Call once per frame.
+    pub fn push<R: RngState, T: Trap, M: Bus>(
+        &mut self,
+        chip8: &Chip8<R, T, M>,
+    ) -> Result<(), Error> {
+        if !self.recorded {
+            chip8.save_state(self.latest)?;
+            self.recorded = true;
+            return Ok(());
+        }
+        let mut encoder = DeltaEncoder::new(self.latest, |_| {});
+        chip8.write_state(&mut encoder);
+        let len = encoder.finish();
+        if len + ENTRY_OVERHEAD > self.ring.len() {
+            // The delta doesn't fit even in an empty buffer: restart the history from here
+            self.clear();
+            return self.push(chip8);
+        }
+        while self.ring.len() - self.used < len + ENTRY_OVERHEAD {
+            self.drop_oldest();
+        }
+        let entry = (self.start + self.used) % self.ring.len();
+        self.ring_write_u32(entry, len);
+        let mut pos = entry + 4;
+        let ring = &mut *self.ring;
+        let ring_len = ring.len();
+        let mut encoder = DeltaEncoder::new(self.latest, |byte| {
+            ring[pos % ring_len] = byte;
+            pos += 1;
+        });
+        chip8.write_state(&mut encoder);
+        encoder.finish();
+        self.ring_write_u32(entry + 4 + len, len);
+        self.used += len + ENTRY_OVERHEAD;
+        self.entries += 1;
+        // The delta turns the previous state into the new one as well
+        self.apply_delta(entry + 4, len);
+        Ok(())
+    }
+
+    /// XOR the delta of `len` bytes at `pos` in the ring buffer into the latest state.
+    fn apply_delta(&mut self, mut pos: usize, len: usize) {
+        let payload_end = pos + len;
+        let ring = &*self.ring;
+        let ring_len = ring.len();
+        let read_varint = |pos: &mut usize| {
+            let mut v = 0;
+            let mut shift = 0;
+            loop {
+                let byte = ring[*pos % ring_len];
+                *pos += 1;
+                v |= ((byte & 0x7f) as usize) << shift;
+                shift += 7;
+                if byte & 0x80 == 0 {
+                    return v;
+                }
+            }
+        };
+        let mut offset = 0;
+        while pos < payload_end {
+            offset += read_varint(&mut pos);
+            let literals = read_varint(&mut pos);
+            for b in self.latest[offset..offset + literals].iter_mut() {
+                *b ^= ring[pos % ring_len];
+                pos += 1;
+            }
+            offset += literals;
+        }
+    }
+
+    /// Restore the machine to the state recorded before the latest one, removing the latest one
+    /// from the history.  Returns false if there is no history left.
+    pub fn pop<R: RngState, T: Trap, M: Bus>(
+        &mut self,
+        chip8: &mut Chip8<R, T, M>,
+    ) -> Result<bool, Error> {
+        if self.entries == 0 {
+            return Ok(false);
+        }
+        let end = self.start + self.used;
+        let len = self.ring_read_u32(end - 4);
+        self.apply_delta(end - 4 - len, len);
+        self.used -= len + ENTRY_OVERHEAD;
+        self.entries -= 1;
+        chip8.load_state(self.latest)?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec;
+    use std::vec::Vec;
+
+    const MAZE: &[u8] = include_bytes!("../../games/MAZE");
+
+    fn machine() -> Chip8 {
+        TestMachine::new().seed(3).rom(MAZE).build()
+    }
+
+    /// Run `frames` frames, pushing the state after each, and return the checksums of the states
+    fn record(chip8: &mut Chip8, rewind: &mut Rewind, frames: usize) -> Vec<u32> {
+        let mut checksums = Vec::new();
+        for _ in 0..frames {
+            chip8.frame(0).unwrap();
+            rewind.push(chip8).unwrap();
+            checksums.push(chip8.checksum());
+        }
+        checksums
+    }
+
+    #[test]
+    fn push_pop() {
+        let mut storage = vec![0; STATE_SIZE + 0x10000];
+        let mut rewind = Rewind::new(&mut storage).unwrap();
+        let mut chip8 = machine();
+        assert!(!rewind.pop(&mut chip8).unwrap());
+        let checksums = record(&mut chip8, &mut rewind, 20);
+        // The first state is the base of the history
+        assert_eq!(rewind.len(), 19);
+        for expected in checksums.iter().rev().skip(1) {
+            assert!(rewind.pop(&mut chip8).unwrap());
+            assert_eq!(chip8.checksum(), *expected);
+        }
+        assert!(rewind.is_empty());
+        assert!(!rewind.pop(&mut chip8).unwrap());
+        assert_eq!(chip8.checksum(), checksums[0]);
+
+        // Recording goes on from the restored state
+        let more = record(&mut chip8, &mut rewind, 5);
+        assert_eq!(rewind.len(), 5);
+        assert!(rewind.pop(&mut chip8).unwrap());
+        assert_eq!(chip8.checksum(), more[3]);
+    }
+
+    #[test]
+    fn ring_wrap() {
+        // Room for a few deltas only, so that the entries wrap around the end of the ring
+        let mut storage = vec![0; STATE_SIZE + 300];
+        let mut rewind = Rewind::new(&mut storage).unwrap();
+        let mut chip8 = machine();
+        let checksums = record(&mut chip8, &mut rewind, 200);
+        let kept = rewind.len();
+        assert!(kept > 1 && kept < 199, "{} entries", kept);
+        // The newest states are kept, the oldest ones were dropped
+        for expected in checksums.iter().rev().skip(1).take(kept) {
+            assert!(rewind.pop(&mut chip8).unwrap());
+            assert_eq!(chip8.checksum(), *expected);
+        }
+        assert!(!rewind.pop(&mut chip8).unwrap());
+    }
+
+    #[test]
+    fn long_runs() {
+        // Loading another program changes runs of bytes much longer than MAX_LITERALS
+        let mut storage = vec![0; STATE_SIZE + 0x10000];
+        let mut rewind = Rewind::new(&mut storage).unwrap();
+        let mut chip8 = machine();
+        let checksums = record(&mut chip8, &mut rewind, 2);
+        chip8
+            .load_rom(include_bytes!("../../games/BLINKY"))
+            .unwrap();
+        assert_ne!(chip8.checksum(), checksums[1]);
+        rewind.push(&chip8).unwrap();
+        assert!(rewind.pop(&mut chip8).unwrap());
+        assert_eq!(chip8.checksum(), checksums[1]);
+        // The new state was applied to the latest one too
+        assert!(rewind.pop(&mut chip8).unwrap());
+        assert_eq!(chip8.checksum(), checksums[0]);
+    }
+
+    #[test]
+    fn delta_too_big() {
+        let mut storage = vec![0; STATE_SIZE + ENTRY_OVERHEAD + 4];
+        let mut rewind = Rewind::new(&mut storage).unwrap();
+        let mut chip8 = machine();
+        record(&mut chip8, &mut rewind, 10);
+        // The history restarts from every state as no delta fits
+        assert!(rewind.is_empty());
+        assert!(matches!(
+            Rewind::new(&mut storage[..STATE_SIZE]),
+            Err(Error::StateBufferTooSmall(_))
+        ));
+    }
+}
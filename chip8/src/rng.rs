@@ -0,0 +1,176 @@
+//! Random number generators for the `RND` instruction.
+//!
+//! The machine accepts any `RngCore`.  Save states also store the state of the generator, so it
+//! must implement `RngState`.  The built-in `Pcg32` produces the same sequence on every platform
+//! for a given seed, which makes runs reproducible from the seed and the inputs alone.
+
+use super::*;
+
+/// Size in bytes of the state of a random number generator in a save state
+pub const RNG_STATE_SIZE: usize = 16;
+
+/// Random number generator whose state can be saved and restored with the machine.
+///
+/// Generators without a reproducible state, like hardware ones, can return any state and ignore
+/// the one restored.
+pub trait RngState: RngCore {
+    /// Current state of the generator
+    fn state(&self) -> [u8; RNG_STATE_SIZE];
+    /// Restore a state returned by `state`.
+    fn set_state(&mut self, state: &[u8; RNG_STATE_SIZE]);
+}
+
+/// Multiplier of the linear congruential generator
+const PCG_MULTIPLIER: u64 = 6_364_136_223_846_793_005;
+
+/// PCG32 (XSH RR variant) random number generator: 64 bits of state, 32 bits of output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pcg32 {
+    state: u64,
+    increment: u64, // Selects the stream, always odd
+}
+
+impl Pcg32 {
+    /// Create a generator at position `state` of the stream `stream`.
+    pub fn new(state: u64, stream: u64) -> Self {
+        let increment = (stream << 1) | 1;
+        let mut pcg = Self {
+            state: state.wrapping_add(increment),
+            increment,
+        };
+        pcg.step();
+        pcg
+    }
+    fn step(&mut self) {
+        self.state = self
+            .state
+            .wrapping_mul(PCG_MULTIPLIER)
+            .wrapping_add(self.increment);
+    }
+}
+
+impl RngCore for Pcg32 {
+    fn next_u32(&mut self) -> u32 {
+        let state = self.state;
+        self.step();
+        let xorshifted = (((state >> 18) ^ state) >> 27) as u32;
+        xorshifted.rotate_right((state >> 59) as u32)
+    }
+    fn next_u64(&mut self) -> u64 {
+        let low = self.next_u32() as u64;
+        (self.next_u32() as u64) << 32 | low
+    }
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        for chunk in dest.chunks_mut(4) {
+            chunk.copy_from_slice(&self.next_u32().to_le_bytes()[..chunk.len()]);
+        }
+    }
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+impl SeedableRng for Pcg32 {
+    /// The state followed by the stream, little endian
+    type Seed = [u8; 16];
+
+    fn from_seed(seed: Self::Seed) -> Self {
+        let mut state = [0; 8];
+        let mut stream = [0; 8];
+        state.copy_from_slice(&seed[..8]);
+        stream.copy_from_slice(&seed[8..]);
+        Self::new(u64::from_le_bytes(state), u64::from_le_bytes(stream))
+    }
+}
+
+impl RngState for Pcg32 {
+    fn state(&self) -> [u8; RNG_STATE_SIZE] {
+        let mut state = [0; RNG_STATE_SIZE];
+        state[..8].copy_from_slice(&self.state.to_le_bytes());
+        state[8..].copy_from_slice(&self.increment.to_le_bytes());
+        state
+    }
+    fn set_state(&mut self, state: &[u8; RNG_STATE_SIZE]) {
+        let mut word = [0; 8];
+        word.copy_from_slice(&state[..8]);
+        self.state = u64::from_le_bytes(word);
+        word.copy_from_slice(&state[8..]);
+        self.increment = u64::from_le_bytes(word) | 1;
+    }
+}
+
+impl<R: RngCore, T: Trap, M: Bus> Chip8<R, T, M> {
+    /// Random number generator of the machine
+    pub fn rng(&self) -> &R {
+        &self.rng
+    }
+    /// Mutable random number generator of the machine
+    pub fn rng_mut(&mut self) -> &mut R {
+        &mut self.rng
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known_answers() {
+        // Output of the reference implementation (pcg32-global-demo) for the seed 42, 54
+        let mut pcg = Pcg32::new(42, 54);
+        let expected = [
+            0xa15c02b7, 0x7b47f409, 0xba1d3330, 0x83d2f293, 0xbfa4784b, 0xcbed606e,
+        ];
+        for &x in expected.iter() {
+            assert_eq!(pcg.next_u32(), x);
+        }
+
+        let mut seed = [0; 16];
+        seed[..8].copy_from_slice(&42u64.to_le_bytes());
+        seed[8..].copy_from_slice(&54u64.to_le_bytes());
+        let mut pcg = Pcg32::from_seed(seed);
+        assert_eq!(pcg.next_u64(), 0x7b47f409_a15c02b7);
+        let mut bytes = [0; 6];
+        pcg.fill_bytes(&mut bytes);
+        assert_eq!(bytes, [0x30, 0x33, 0x1d, 0xba, 0x93, 0xf2]);
+    }
+
+    #[test]
+    fn state_round_trip() {
+        let mut pcg = Pcg32::new(42, 54);
+        pcg.next_u32();
+        let state = pcg.state();
+        let mut restored = Pcg32::new(0, 0);
+        assert_ne!(restored, pcg);
+        restored.set_state(&state);
+        assert_eq!(restored, pcg);
+        assert_eq!(restored.next_u32(), 0x7b47f409);
+        // The increment stays odd
+        restored.set_state(&[0; RNG_STATE_SIZE]);
+        assert_eq!(restored.state()[8], 1);
+    }
+
+    #[test]
+    fn save_states() {
+        // RND V0, 0xff; JP 0x200
+        let machine = |seed| {
+            TestMachine::new()
+                .seed(seed)
+                .words(&[0xc0ff, 0x1200])
+                .build()
+        };
+        let mut chip8 = machine(7);
+        chip8.step().unwrap();
+        let mut buf = [0; STATE_SIZE];
+        chip8.save_state(&mut buf).unwrap();
+        let mut restored = machine(8);
+        restored.load_state(&buf).unwrap();
+        assert_eq!(restored.rng(), chip8.rng());
+        for _ in 0..10 {
+            chip8.step().unwrap();
+            restored.step().unwrap();
+            assert_eq!(restored.v()[0], chip8.v()[0]);
+        }
+    }
+}
@@ -0,0 +1,380 @@
+//! Binary save states.
+//!
+//! A save state is a little endian dump of the whole machine, prefixed by a header that
+//! identifies the format version and the memory layout of the build that produced it:
+//!
+//! | Field          | Size                          |
+//! |----------------|-------------------------------|
+//! | Magic `C8ST`   | 4                             |
+//! | Version        | 1                             |
+//! | Memory size    | 4                             |
+//! | Bit planes     | 1                             |
+//! | Machine state  | `STATE_SIZE - 10`             |
+
+use super::*;
+
+const STATE_MAGIC: [u8; 4] = *b"C8ST";
+const STATE_VERSION: u8 = 6;
+const STATE_HEADER: usize = 4 + 1 + 4 + 1;
+
+/// Size in bytes of a save state
+pub const STATE_SIZE: usize = STATE_HEADER
+    + 1 // mode
+    + 1 // hires
+    + 1 // exited
+    + 1 // tone
+    + 1 // sp
+    + 1 // planes
+    + 3 // timing
+    + MEM_SIZE // mem
+    + 0x10 // v
+    + 2 // i
+    + 2 // pc
+    + MAX_STACK_DEPTH * 2 // stack
+    + 1 // dt
+    + 1 // st
+    + 2 // keypad
+    + 1 // key_wait
+    + PLANES * FB_SIZE // fb
+    + PLANES * HIRES_FB_SIZE // fb_hires
+    + 0x10 // rpl
+    + AUDIO_PATTERN_SIZE // pattern
+    + 1 // pitch
+    + 8 // time
+    + 4 // timer_phase
+    + 4 // budget_frac
+    + RNG_STATE_SIZE; // rng
+
+/// Destination of the little endian values of a save state
+pub(crate) trait Sink {
+    fn bytes(&mut self, bytes: &[u8]);
+    fn u8(&mut self, v: u8) {
+        self.bytes(&[v]);
+    }
+    fn u16(&mut self, v: u16) {
+        self.bytes(&v.to_le_bytes());
+    }
+    fn u32(&mut self, v: u32) {
+        self.bytes(&v.to_le_bytes());
+    }
+    fn u64(&mut self, v: u64) {
+        self.bytes(&v.to_le_bytes());
+    }
+}
+
+/// Sequential writer of little endian values into a byte buffer
+pub(crate) struct Writer<'a> {
+    pub(crate) buf: &'a mut [u8],
+    pub(crate) pos: usize,
+}
+
+impl<'a> Sink for Writer<'a> {
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.buf[self.pos..self.pos + bytes.len()].copy_from_slice(bytes);
+        self.pos += bytes.len();
+    }
+}
+
+/// Sequential reader of little endian values from a byte buffer
+pub(crate) struct Reader<'a> {
+    pub(crate) buf: &'a [u8],
+    pub(crate) pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    /// Bytes left to read
+    pub(crate) fn remaining(&self) -> usize {
+        self.buf.len() - self.pos
+    }
+    pub(crate) fn bytes(&mut self, len: usize) -> &'a [u8] {
+        let bytes = &self.buf[self.pos..self.pos + len];
+        self.pos += len;
+        bytes
+    }
+    pub(crate) fn u8(&mut self) -> u8 {
+        self.bytes(1)[0]
+    }
+    pub(crate) fn bool(&mut self) -> Result<bool, Error> {
+        match self.u8() {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(Error::InvalidState),
+        }
+    }
+    pub(crate) fn u16(&mut self) -> u16 {
+        let mut b = [0; 2];
+        b.copy_from_slice(self.bytes(2));
+        u16::from_le_bytes(b)
+    }
+    pub(crate) fn u32(&mut self) -> u32 {
+        let mut b = [0; 4];
+        b.copy_from_slice(self.bytes(4));
+        u32::from_le_bytes(b)
+    }
+    pub(crate) fn u64(&mut self) -> u64 {
+        let mut b = [0; 8];
+        b.copy_from_slice(self.bytes(8));
+        u64::from_le_bytes(b)
+    }
+}
+
+pub(crate) fn mode_to_u8(mode: Mode) -> u8 {
+    match mode {
+        Mode::Chip8 => 0,
+        Mode::SuperChip => 1,
+        #[cfg(feature = "xo-chip")]
+        Mode::XoChip => 2,
+    }
+}
+
+pub(crate) fn mode_from_u8(mode: u8) -> Result<Mode, Error> {
+    match mode {
+        0 => Ok(Mode::Chip8),
+        1 => Ok(Mode::SuperChip),
+        #[cfg(feature = "xo-chip")]
+        2 => Ok(Mode::XoChip),
+        _ => Err(Error::InvalidState),
+    }
+}
+
+/// Kind and tickrate of a timing
+pub(crate) fn timing_to_u8(timing: Timing) -> (u8, u16) {
+    match timing {
+        Timing::Estimated => (0, 0),
+        Timing::CosmacVip => (1, 0),
+        Timing::Tickrate(n) => (2, n),
+    }
+}
+
+pub(crate) fn timing_from_u8(timing: u8, tickrate: u16) -> Result<Timing, Error> {
+    match timing {
+        0 => Ok(Timing::Estimated),
+        1 => Ok(Timing::CosmacVip),
+        2 => Ok(Timing::Tickrate(tickrate)),
+        _ => Err(Error::InvalidState),
+    }
+}
+
+impl<R: RngState, T: Trap, M: Bus> Chip8<R, T, M> {
+    /// Store the machine state in `buf`, which must be at least `STATE_SIZE` bytes long.  Returns
+    /// the number of bytes written.
+    pub fn save_state(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        if buf.len() < STATE_SIZE {
+            return Err(Error::StateBufferTooSmall(buf.len()));
+        }
+        let mut w = Writer { buf, pos: 0 };
+        self.write_state(&mut w);
+        Ok(w.pos)
+    }
+
+    /// Write the machine state, `STATE_SIZE` bytes, to `w`.
+    pub(crate) fn write_state<S: Sink>(&self, w: &mut S) {
+        w.bytes(&STATE_MAGIC);
+        w.u8(STATE_VERSION);
+        w.u32(MEM_SIZE as u32);
+        w.u8(PLANES as u8);
+
+        w.u8(mode_to_u8(self.mode));
+        w.u8(self.hires as u8);
+        w.u8(self.exited as u8);
+        w.u8(self.tone as u8);
+        w.u8(self.sp);
+        w.u8(self.planes);
+        let (timing, tickrate) = timing_to_u8(self.quirks.timing);
+        w.u8(timing);
+        w.u16(tickrate);
+        for addr in 0..MEM_SIZE {
+            w.u8(self.mem.peek(addr));
+        }
+        w.bytes(&self.v.0);
+        w.u16(self.i);
+        w.u16(self.pc);
+        for addr in self.stack.iter() {
+            w.u16(*addr);
+        }
+        w.u8(self.dt);
+        w.u8(self.st);
+        w.u16(self.keypad);
+        w.u8(self.key_wait.unwrap_or(0xff));
+        for plane in self.fb.iter() {
+            w.bytes(plane);
+        }
+        for plane in self.fb_hires.iter() {
+            w.bytes(plane);
+        }
+        w.bytes(&self.rpl);
+        w.bytes(&self.pattern);
+        w.u8(self.pitch);
+        w.u64(self.time as i64 as u64);
+        w.u32(self.timer_phase);
+        w.u32(self.budget_frac);
+        w.bytes(&self.rng.state());
+    }
+
+    /// Restore the machine state from a save state produced by `save_state`.  The machine is left
+    /// untouched if the state is invalid, or if it was saved with another `Timing`, as the time
+    /// carried over between frames is measured in its units.
+    pub fn load_state(&mut self, buf: &[u8]) -> Result<(), Error> {
+        if buf.len() < STATE_SIZE {
+            return Err(Error::InvalidState);
+        }
+        let mut r = Reader { buf, pos: 0 };
+        if r.bytes(4) != STATE_MAGIC
+            || r.u8() != STATE_VERSION
+            || r.u32() != MEM_SIZE as u32
+            || r.u8() != PLANES as u8
+        {
+            return Err(Error::InvalidState);
+        }
+        // Validate the fields that can hold invalid values before modifying the machine
+        let mode = mode_from_u8(r.u8())?;
+        let hires = r.bool()?;
+        let exited = r.bool()?;
+        let tone = r.bool()?;
+        let sp = r.u8();
+        if sp as usize > self.stack.len() {
+            return Err(Error::InvalidState);
+        }
+        let planes = r.u8();
+        if planes >> PLANES != 0 {
+            return Err(Error::InvalidState);
+        }
+        let (timing, tickrate) = (r.u8(), r.u16());
+        if timing_from_u8(timing, tickrate)? != self.quirks.timing {
+            return Err(Error::InvalidState);
+        }
+
+        self.mode = mode;
+        self.hires = hires;
+        self.touch_all();
+        self.exited = exited;
+        self.tone = tone;
+        self.sp = sp;
+        self.planes = planes;
+        for (addr, b) in r.bytes(MEM_SIZE).iter().enumerate() {
+            self.mem.poke(addr, *b);
+        }
+        self.v.0.copy_from_slice(r.bytes(0x10));
+        self.i = r.u16();
+        self.pc = r.u16();
+        for addr in self.stack.iter_mut() {
+            *addr = r.u16();
+        }
+        self.dt = r.u8();
+        self.st = r.u8();
+        self.keypad = r.u16();
+        self.key_wait = Some(r.u8()).filter(|k| *k < 0x10);
+        for plane in self.fb.iter_mut() {
+            plane.copy_from_slice(r.bytes(FB_SIZE));
+        }
+        for plane in self.fb_hires.iter_mut() {
+            plane.copy_from_slice(r.bytes(HIRES_FB_SIZE));
+        }
+        self.rpl.copy_from_slice(r.bytes(0x10));
+        self.pattern.copy_from_slice(r.bytes(AUDIO_PATTERN_SIZE));
+        self.pitch = r.u8();
+        self.time = r.u64() as i64 as isize;
+        self.timer_phase = r.u32() % TIMER_PERIOD;
+        self.budget_frac = r.u32() % TIMER_PERIOD;
+        let mut rng = [0; RNG_STATE_SIZE];
+        rng.copy_from_slice(r.bytes(RNG_STATE_SIZE));
+        self.rng.set_state(&rng);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec;
+
+    const MAZE: &[u8] = include_bytes!("../../games/MAZE");
+
+    fn machine(quirks: Quirks) -> Chip8 {
+        TestMachine::new().seed(7).quirks(quirks).rom(MAZE).build()
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut chip8 = machine(Quirks::default());
+        for _ in 0..10 {
+            chip8.frame(0).unwrap();
+        }
+        let mut state = vec![0; STATE_SIZE];
+        assert_eq!(chip8.save_state(&mut state).unwrap(), STATE_SIZE);
+        let checksum = chip8.checksum();
+        chip8.frame(0).unwrap();
+        let next = chip8.checksum();
+
+        for _ in 0..10 {
+            chip8.frame(0).unwrap();
+        }
+        assert_ne!(chip8.checksum(), checksum);
+        chip8.load_state(&state).unwrap();
+        assert_eq!(chip8.checksum(), checksum);
+        // The random number generator is restored too
+        chip8.frame(0).unwrap();
+        assert_eq!(chip8.checksum(), next);
+
+        // Into a fresh machine
+        let mut fresh = TestMachine::new().build();
+        fresh.load_state(&state).unwrap();
+        assert_eq!(fresh.checksum(), checksum);
+    }
+
+    #[test]
+    fn invalid_states() {
+        let chip8 = machine(Quirks::default());
+        let mut state = vec![0; STATE_SIZE];
+        chip8.save_state(&mut state).unwrap();
+        let mut other = machine(Quirks::default());
+        other.frame(0).unwrap();
+        let checksum = other.checksum();
+
+        let corrupt = |offset: usize, v: u8| {
+            let mut state = state.clone();
+            state[offset] = v;
+            state
+        };
+        let cases = [
+            ("magic", corrupt(0, b'X')),
+            ("version", corrupt(4, STATE_VERSION - 1)),
+            ("memory size", corrupt(5, 0xff)),
+            ("bit planes", corrupt(9, PLANES as u8 + 1)),
+            ("mode", corrupt(STATE_HEADER, 3)),
+            ("hires", corrupt(STATE_HEADER + 1, 2)),
+            ("sp", corrupt(STATE_HEADER + 4, MAX_STACK_DEPTH as u8 + 1)),
+            ("planes", corrupt(STATE_HEADER + 5, 1 << PLANES)),
+            ("timing", corrupt(STATE_HEADER + 6, 3)),
+            ("truncated", state[..STATE_SIZE - 1].to_vec()),
+        ];
+        for (name, state) in cases.iter() {
+            assert!(
+                matches!(other.load_state(state), Err(Error::InvalidState)),
+                "{}",
+                name
+            );
+            assert_eq!(other.checksum(), checksum, "{}", name);
+        }
+        let mut small = [0; 16];
+        assert!(matches!(
+            chip8.save_state(&mut small),
+            Err(Error::StateBufferTooSmall(16))
+        ));
+    }
+
+    #[test]
+    fn timing_mismatch() {
+        let chip8 = machine(Quirks::default());
+        let mut state = vec![0; STATE_SIZE];
+        chip8.save_state(&mut state).unwrap();
+        let mut other = machine(Quirks {
+            timing: Timing::Tickrate(15),
+            ..Quirks::default()
+        });
+        assert!(matches!(other.load_state(&state), Err(Error::InvalidState)));
+        other.set_timing(Timing::Estimated);
+        other.load_state(&state).unwrap();
+        assert_eq!(other.checksum(), chip8.checksum());
+    }
+}
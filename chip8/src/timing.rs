@@ -0,0 +1,363 @@
+//! Timing models: how much of a frame each instruction takes.
+//!
+//! With `Timing::Tickrate`, a frame runs a fixed number of instructions and the overtime counts
+//! instructions instead of microseconds.
+//!
+//! The VIP runs its CDP1802 at 1.76064 MHz, and each machine cycle takes 8 clocks.  During the 128
+//! scanlines of the picture, the display interrupt routine keeps the CPU busy feeding the CDP1861,
+//! so the interpreter only gets the rest of the frame.  The cost of each instruction follows the
+//! path taken through its routine in the interpreter, which depends on the operands.
+
+use super::*;
+
+/// Duration of a machine cycle in nanoseconds
+const CYCLE_NS: usize = 4544;
+/// Machine cycles spent by the display interrupt routine every frame: 128 scanlines of 14 cycles
+/// plus the timer updates
+const INTERRUPT_CYCLES: usize = 128 * 14 + 40;
+/// Machine cycles to fetch an instruction and jump to its routine
+const FETCH_CYCLES: usize = 40;
+
+/// Period of the 60 Hz timers, in 1/60 microseconds
+pub(crate) const TIMER_PERIOD: u32 = 1_000_000;
+
+/// Model of the time taken by each instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Timing {
+    /// A fixed estimate per instruction, independent of its operands.
+    Estimated,
+    /// The machine cycles of the COSMAC VIP interpreter, including the time stolen by the display
+    /// interrupt.
+    CosmacVip,
+    /// A fixed number of instructions per frame, like the tickrate of Octo.  With
+    /// `Quirks::display_wait`, drawing a sprite ends the frame.
+    Tickrate(u16),
+}
+
+/// Convert machine cycles of the VIP into microseconds.
+fn micros(cycles: usize) -> usize {
+    cycles * CYCLE_NS / 1000
+}
+
+/// Microseconds available to the interpreter in a frame of the VIP.
+const VIP_FRAME_TIME: isize = FRAME_TIME - (INTERRUPT_CYCLES * CYCLE_NS / 1000) as isize;
+
+impl<R: RngCore, T: Trap, M: Bus> Chip8<R, T, M> {
+    /// Execution time added by every frame, in the units of the timing in use
+    pub(crate) fn frame_budget(&self) -> isize {
+        match self.quirks.timing {
+            Timing::Estimated => FRAME_TIME,
+            Timing::CosmacVip => VIP_FRAME_TIME,
+            Timing::Tickrate(n) => n as isize,
+        }
+    }
+    /// Start a run of `elapsed` 1/60 microseconds of real time: schedule the timer ticks at every
+    /// period boundary within the run and add the corresponding execution time.
+    pub(crate) fn advance(&mut self, elapsed: u64) {
+        let period = TIMER_PERIOD as u64;
+        // A boundary at the start of the run ticks in this run, one at the end in the next
+        let first = (period - self.timer_phase as u64) % period;
+        self.tick_next = first;
+        // Past 0x100 ticks the timers are stopped anyway
+        self.ticks = if elapsed > first {
+            ((elapsed - first - 1) / period + 1).min(0x100) as u16
+        } else {
+            0
+        };
+        self.timer_phase = ((self.timer_phase as u64 + elapsed) % period) as u32;
+        let budget = self.budget_frac as u64 + elapsed * self.frame_budget().max(0) as u64;
+        self.budget_frac = (budget % period) as u32;
+        self.run_budget = (budget / period) as isize;
+        self.time += self.run_budget;
+        self.tone_log.start(self.tone, elapsed);
+    }
+    /// Position of the current instruction in the run, in 1/60 microseconds of real time
+    pub(crate) fn run_pos(&self) -> u64 {
+        let budget = self.frame_budget().max(0) as u64;
+        if budget == 0 {
+            return 0;
+        }
+        (self.run_budget - self.time).max(0) as u64 * TIMER_PERIOD as u64 / budget
+    }
+    /// Tick the timers scheduled up to the current instruction, or all of them if `all` is set.
+    pub(crate) fn tick_timers(&mut self, all: bool) {
+        let pos = self.run_pos();
+        while self.ticks != 0 && (all || self.tick_next <= pos) {
+            if self.dt != 0 {
+                self.dt -= 1;
+            }
+            if self.st != 0 {
+                self.st -= 1;
+                if self.st == 0 {
+                    self.set_tone(false, self.tick_next);
+                }
+            }
+            self.tick_next += TIMER_PERIOD as u64;
+            self.ticks -= 1;
+        }
+    }
+    /// Execution time from the current instruction until the next timer tick, when the display
+    /// interrupt happens.
+    pub(crate) fn vblank_wait(&self) -> usize {
+        let period = TIMER_PERIOD as u64;
+        let budget = self.frame_budget().max(0) as u64;
+        if budget == 0 {
+            return self.time.max(0) as usize;
+        }
+        let pos = self.run_pos();
+        let mut next = self.tick_next;
+        if next <= pos {
+            next += (pos - next) / period * period + period;
+        }
+        (((next - pos) * budget + period - 1) / period) as usize
+    }
+    /// Execute `inst`, or the unknown `opcode` if it's None.  Returns the time elapsed in the
+    /// units of the timing in use.
+    pub(crate) fn exec_timed(
+        &mut self,
+        inst: Option<Instruction>,
+        opcode: u16,
+    ) -> Result<usize, Error> {
+        let inst = match inst {
+            Some(inst) => inst,
+            None => {
+                let adv = self.op_unknown(opcode)?;
+                return Ok(match self.quirks.timing {
+                    Timing::Tickrate(_) => 1,
+                    _ => adv,
+                });
+            }
+        };
+        // A sprite waiting for the display interrupt takes the rest of the frame
+        let wait = matches!(inst, Instruction::Drw(..)) && self.quirks.display_wait;
+        Ok(match self.quirks.timing {
+            Timing::Estimated => self.exec(inst)?,
+            Timing::CosmacVip => match self.vip_time(inst) {
+                Some(time) => {
+                    let adv = self.exec(inst)?;
+                    // On the VIP the sprite is drawn after the interrupt
+                    if wait {
+                        adv + time
+                    } else {
+                        time
+                    }
+                }
+                None => self.exec(inst)?,
+            },
+            Timing::Tickrate(_) => {
+                let adv = self.exec(inst)?;
+                if wait {
+                    adv
+                } else {
+                    1
+                }
+            }
+        })
+    }
+    /// Duration in microseconds of `inst` on the COSMAC VIP, before it's executed.  Returns None
+    /// for instructions that the VIP doesn't have.
+    pub(crate) fn vip_time(&self, inst: Instruction) -> Option<usize> {
+        use Instruction::*;
+        let v = |x: u8| self.v[Reg(x)];
+        // Taking a skip costs an extra branch
+        let skip = |taken: bool| if taken { 4 } else { 0 };
+        let key = |x: u8| 1 << (v(x) & 0x0f) & self.keypad != 0;
+        let cycles = match inst {
+            Cls => 24 + 256 * 6,
+            Ret => 10,
+            Sys(_) => 26,
+            Jp(_) => 12,
+            Call(_) => 26,
+            SeByte(x, kk) => 10 + skip(v(x) == kk),
+            SneByte(x, kk) => 10 + skip(v(x) != kk),
+            SeReg(x, y) => 14 + skip(v(x) == v(y)),
+            SneReg(x, y) => 14 + skip(v(x) != v(y)),
+            LdByte(..) => 6,
+            AddByte(..) => 10,
+            // The arithmetic ops run a copy of the 1802 instruction patched into memory
+            LdReg(..) | Or(..) | And(..) | Xor(..) | AddReg(..) | Sub(..) | Shr(..) | Subn(..)
+            | Shl(..) => 44,
+            LdI(_) => 12,
+            JpV0(_) => 22,
+            Rnd(..) => 36,
+            Drw(x, y, n) => self.vip_drw_cycles(v(x), v(y), n),
+            Skp(x) => 14 + skip(key(x)),
+            Sknp(x) => 14 + skip(!key(x)),
+            LdVxDt(_) | LdDtVx(_) | LdStVx(_) => 10,
+            // Cost of each poll of the keypad while waiting
+            LdVxK(_) => 40,
+            AddI(_) | LdF(_) => 16,
+            // Each digit is found by repeated subtraction
+            LdB(x) => {
+                let v = v(x);
+                80 + 16 * (v / 100 + v / 10 % 10 + v % 10) as usize
+            }
+            LdIVx(x) | LdVxI(x) => 14 + 14 * (x as usize + 1),
+            _ => return None,
+        };
+        Some(micros(FETCH_CYCLES + cycles))
+    }
+    /// Machine cycles to draw a sprite of n rows at (x, y) on the VIP.  Every row is shifted into
+    /// place one bit at a time and spills into a second byte when it crosses a byte boundary.
+    fn vip_drw_cycles(&self, x: u8, y: u8, n: u8) -> usize {
+        let (width, height) = (SCREEN_WIDTH, SCREEN_HEIGTH);
+        let (x, y) = (x as usize % width, y as usize % height);
+        let clip = self.quirks.clip_sprites;
+        let rows = if clip {
+            (n as usize).min(height - y)
+        } else {
+            n as usize
+        };
+        let shift = x % 8;
+        let spill = shift != 0 && !(clip && x / 8 == width / 8 - 1);
+        let row = 24 + 6 * shift + if spill { 20 } else { 0 };
+        26 + rows * row
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vip(v: &[u8]) -> Chip8 {
+        let mut chip8 = TestMachine::new().quirks(Quirks::cosmac_vip()).build();
+        chip8.v.0[..v.len()].copy_from_slice(v);
+        chip8
+    }
+
+    #[test]
+    fn vip_cycles() {
+        use Instruction::*;
+        let chip8 = vip(&[123, 123, 7]);
+        let cycles = |inst| chip8.vip_time(inst).map(|t| t * 1000 / CYCLE_NS);
+        // The conversion to microseconds truncates
+        let fetch = |c: usize| Some(micros(FETCH_CYCLES + c) * 1000 / CYCLE_NS);
+        assert_eq!(cycles(LdByte(0, 1)), fetch(6));
+        assert_eq!(cycles(Jp(0x200)), fetch(12));
+        assert_eq!(cycles(Xor(0, 1)), fetch(44));
+        assert_eq!(cycles(Cls), fetch(24 + 256 * 6));
+        // Skips taken cost an extra branch
+        assert_eq!(cycles(SeByte(0, 123)), fetch(14));
+        assert_eq!(cycles(SeByte(0, 5)), fetch(10));
+        assert_eq!(cycles(SneReg(0, 1)), fetch(14));
+        assert_eq!(cycles(SeReg(0, 1)), fetch(18));
+        // Key 7 held
+        let mut chip8 = vip(&[7]);
+        chip8.keypad = 1 << 7;
+        assert_eq!(chip8.vip_time(Skp(0)), Some(micros(FETCH_CYCLES + 18)));
+        assert_eq!(chip8.vip_time(Sknp(0)), Some(micros(FETCH_CYCLES + 14)));
+        // Depends on the digits and the number of registers
+        assert_eq!(cycles(LdB(0)), fetch(80 + 16 * 6));
+        assert_eq!(cycles(LdB(2)), fetch(80 + 16 * 7));
+        assert_eq!(cycles(LdIVx(3)), fetch(14 + 14 * 4));
+        // Not on the VIP
+        assert_eq!(cycles(High), None);
+        assert_eq!(cycles(Scd(1)), None);
+    }
+
+    #[test]
+    fn vip_drw_cycles() {
+        let chip8 = vip(&[]);
+        // Byte aligned
+        assert_eq!(chip8.vip_drw_cycles(0, 0, 5), 26 + 5 * 24);
+        // Shifted rows spill into the next byte
+        assert_eq!(chip8.vip_drw_cycles(3, 0, 5), 26 + 5 * (24 + 18 + 20));
+        assert_eq!(chip8.vip_drw_cycles(67, 32, 5), 26 + 5 * (24 + 18 + 20));
+        // Clipped at the right edge and at the bottom
+        assert_eq!(chip8.vip_drw_cycles(60, 0, 1), 26 + 24 + 24);
+        assert_eq!(chip8.vip_drw_cycles(0, 30, 5), 26 + 2 * 24);
+        let quirks = Quirks {
+            clip_sprites: false,
+            ..Quirks::cosmac_vip()
+        };
+        let chip8 = TestMachine::new().quirks(quirks).build();
+        assert_eq!(chip8.vip_drw_cycles(60, 0, 1), 26 + 24 + 24 + 20);
+        assert_eq!(chip8.vip_drw_cycles(0, 30, 5), 26 + 5 * 24);
+    }
+
+    #[test]
+    fn vip_frames() {
+        // ADD V1, 1; JP 0x200
+        let mut chip8 = TestMachine::new()
+            .quirks(Quirks::cosmac_vip())
+            .words(&[0x7101, 0x1200])
+            .build();
+        for _ in 0..10 {
+            chip8.frame(0).unwrap();
+        }
+        // The interpreter only gets the time left by the display interrupt
+        let iteration = micros(FETCH_CYCLES + 10) + micros(FETCH_CYCLES + 12);
+        let expected = 10 * VIP_FRAME_TIME as usize / iteration;
+        let count = chip8.v()[1] as usize;
+        assert!((expected..=expected + 1).contains(&count), "{}", count);
+    }
+
+    fn tickrate(n: u16, display_wait: bool, rom: &[u8]) -> Chip8 {
+        let quirks = Quirks {
+            timing: Timing::Tickrate(n),
+            display_wait,
+            ..Quirks::default()
+        };
+        TestMachine::new().quirks(quirks).rom(rom).build()
+    }
+
+    #[test]
+    fn tickrate_frames() {
+        // ADD V1, 1; JP 0x200
+        let mut chip8 = tickrate(20, false, &[0x71, 0x01, 0x12, 0x00]);
+        chip8.frame(0).unwrap();
+        assert_eq!(chip8.v()[1], 10);
+        chip8.frame(0).unwrap();
+        assert_eq!(chip8.v()[1], 20);
+        // Instructions are counted independently of their cost: CLS; ADD V1, 1; JP 0x200
+        let mut chip8 = tickrate(9, false, &[0x00, 0xe0, 0x71, 0x01, 0x12, 0x00]);
+        chip8.frame(0).unwrap();
+        assert_eq!(chip8.v()[1], 3);
+        assert_eq!(chip8.pc(), 0x200);
+    }
+
+    #[test]
+    fn tickrate_display_wait() {
+        // ADD V1, 1; DRW V0, V0, 1; JP 0x200
+        let rom = [0x71, 0x01, 0xd0, 0x01, 0x12, 0x00];
+        let mut chip8 = tickrate(30, false, &rom);
+        chip8.frame(0).unwrap();
+        assert_eq!(chip8.v()[1], 10);
+        // The sprite ends the frame
+        let mut chip8 = tickrate(30, true, &rom);
+        chip8.frame(0).unwrap();
+        assert_eq!((chip8.v()[1], chip8.pc()), (1, 0x204));
+        chip8.frame(0).unwrap();
+        assert_eq!((chip8.v()[1], chip8.pc()), (2, 0x204));
+    }
+
+    #[test]
+    fn run_for() {
+        // LD V0, 100; LD DT, V0; ADD V1, 1; JP 0x204
+        let rom = [0x60, 0x64, 0xf0, 0x15, 0x71, 0x01, 0x12, 0x04];
+        let mut frames = tickrate(10, false, &rom);
+        let mut split = tickrate(10, false, &rom);
+        for _ in 0..60 {
+            frames.frame(0).unwrap();
+        }
+        // A second in slices that don't line up with the frames
+        for _ in 0..1000 {
+            split.run_for(1000).unwrap();
+        }
+        assert_eq!(split.v(), frames.v());
+        assert_eq!(split.pc(), frames.pc());
+        assert_eq!(split.dt(), frames.dt());
+        assert_eq!(split.checksum(), frames.checksum());
+        // 60 ticks, the first one before DT is set
+        assert_eq!(split.dt(), 100 - 59);
+
+        // A single long run ticks the timers at the same rate
+        let mut long = tickrate(10, false, &rom);
+        long.run_for(1_000_000).unwrap();
+        assert_eq!(long.dt(), split.dt());
+        assert_eq!(long.v(), split.v());
+        // Nothing happens in an empty run
+        long.run_for(0).unwrap();
+        assert_eq!(long.v(), split.v());
+    }
+}
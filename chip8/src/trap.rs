@@ -0,0 +1,172 @@
+//! Trap handlers: user code that executes the opcodes the interpreter doesn't recognise.
+//!
+//! With `OpcodePolicy::Trap`, every opcode that isn't valid in the current mode, including the
+//! calls to machine code routines (0NNN), is handed to the `Trap` of the machine, which can
+//! implement custom instructions on top of the machine state.
+
+use super::*;
+
+/// Machine state that a trap handler can inspect and modify.
+pub struct Cpu<'a> {
+    /// Register Set V0 through VF
+    pub v: &'a mut [u8; 0x10],
+    /// Index Register
+    pub i: &'a mut u16,
+    /// Program Counter, pointing to the trapped opcode
+    pub pc: &'a mut u16,
+    /// Delay Timer
+    pub dt: &'a mut u8,
+    /// Sound Timer.  Changes start or stop the tone like `LD ST, Vx`.
+    pub st: &'a mut u8,
+    /// Memory bus.  Accesses through it bypass the memory policy and don't trigger watchpoints.
+    pub mem: &'a mut dyn Bus,
+    /// Pressed keys, one bit per key
+    pub keypad: u16,
+}
+
+/// Handler for unrecognised opcodes, used with `OpcodePolicy::Trap`.
+pub trait Trap {
+    /// Execute `opcode`, found at `cpu.pc`.  The handler is responsible for moving the PC to the
+    /// next instruction.  Returns the duration of the instruction in microseconds, or None to stop
+    /// with `Error::InvalidOp`.
+    fn trap(&mut self, cpu: Cpu, opcode: u16) -> Option<usize>;
+}
+
+/// Trap handler that doesn't implement any opcode.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoTrap;
+
+impl Trap for NoTrap {
+    fn trap(&mut self, _cpu: Cpu, _opcode: u16) -> Option<usize> {
+        None
+    }
+}
+
+impl<R: RngCore, T: Trap, M: Bus> Chip8<R, T, M> {
+    /// Trap handler of the machine
+    pub fn trap(&self) -> &T {
+        &self.trap
+    }
+    /// Mutable trap handler of the machine
+    pub fn trap_mut(&mut self) -> &mut T {
+        &mut self.trap
+    }
+    /// Op: Handle an opcode that isn't valid in the current mode following the opcode policy.
+    pub(crate) fn op_unknown(&mut self, opcode: u16) -> Result<usize, Error> {
+        let cost = match self.quirks.unknown_opcode {
+            OpcodePolicy::Error => None,
+            OpcodePolicy::Nop => {
+                self.pc = self.pc.wrapping_add(2);
+                Some(27)
+            }
+            OpcodePolicy::Trap => {
+                let st = self.st;
+                let cpu = Cpu {
+                    v: &mut self.v.0,
+                    i: &mut self.i,
+                    pc: &mut self.pc,
+                    dt: &mut self.dt,
+                    st: &mut self.st,
+                    mem: &mut self.mem as &mut dyn Bus,
+                    keypad: self.keypad,
+                };
+                let cost = self.trap.trap(cpu, opcode);
+                if self.st != st {
+                    self.set_tone(self.st != 0, self.run_pos());
+                }
+                cost
+            }
+        };
+        cost.ok_or_else(|| Error::InvalidOp(self.fault()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Stores the low byte of the trapped opcode into V0
+    struct LowByte;
+
+    impl Trap for LowByte {
+        fn trap(&mut self, cpu: Cpu, opcode: u16) -> Option<usize> {
+            cpu.v[0] = opcode as u8;
+            // 0x0Dnn sets the sound timer
+            if opcode & 0xff00 == 0x0d00 {
+                *cpu.st = opcode as u8;
+            }
+            *cpu.pc += 2;
+            Some(10)
+        }
+    }
+
+    fn machine(policy: OpcodePolicy, rom: &[u8]) -> Chip8<Pcg32, LowByte> {
+        let quirks = Quirks {
+            unknown_opcode: policy,
+            ..Quirks::default()
+        };
+        TestMachine::new()
+            .quirks(quirks)
+            .rom(rom)
+            .build_with_trap(LowByte)
+    }
+
+    #[test]
+    fn unknown_opcodes() {
+        // The XO-CHIP instructions aren't valid in CHIP-8 mode
+        for opcode in [0x5122u16, 0x5123, 0xf000, 0xf101, 0xf002, 0xf13a].iter() {
+            let rom = opcode.to_be_bytes();
+            let mut chip8 = machine(OpcodePolicy::Error, &rom);
+            assert!(matches!(chip8.step(), Err(Error::InvalidOp(_))));
+            assert_eq!(chip8.pc(), 0x200);
+
+            let mut chip8 = machine(OpcodePolicy::Nop, &rom);
+            chip8.step().unwrap();
+            assert_eq!(chip8.pc(), 0x202, "{:04x}", opcode);
+
+            let mut chip8 = machine(OpcodePolicy::Trap, &rom);
+            chip8.step().unwrap();
+            assert_eq!((chip8.pc(), chip8.v()[0]), (0x202, *opcode as u8));
+        }
+    }
+
+    #[test]
+    fn machine_code_calls() {
+        // SYS, and the SUPER-CHIP instructions, are calls to machine code in CHIP-8 mode
+        for opcode in [0x0123u16, 0x00c1, 0x00fb, 0x00fc, 0x00fd, 0x00fe, 0x00ff].iter() {
+            let rom = opcode.to_be_bytes();
+            // Ignored by default, without moving on
+            let mut chip8 = machine(OpcodePolicy::Error, &rom);
+            chip8.step().unwrap();
+            assert_eq!((chip8.pc(), chip8.v()[0]), (0x200, 0), "{:04x}", opcode);
+            assert!(!chip8.hires());
+
+            let mut chip8 = machine(OpcodePolicy::Nop, &rom);
+            chip8.step().unwrap();
+            assert_eq!(chip8.pc(), 0x202, "{:04x}", opcode);
+
+            let mut chip8 = machine(OpcodePolicy::Trap, &rom);
+            chip8.step().unwrap();
+            assert_eq!((chip8.pc(), chip8.v()[0]), (0x202, *opcode as u8));
+        }
+    }
+
+    #[test]
+    fn schip_mode() {
+        let mut chip8 = machine(OpcodePolicy::Error, &[0x00, 0xff]);
+        chip8.set_mode(Mode::SuperChip);
+        chip8.step().unwrap();
+        assert!(chip8.hires());
+        assert_eq!(chip8.v()[0], 0);
+    }
+
+    #[test]
+    fn sound_timer() {
+        let mut chip8 = machine(OpcodePolicy::Trap, &[0x0d, 0x05, 0x0d, 0x00]);
+        chip8.step().unwrap();
+        assert_eq!(chip8.st(), 5);
+        assert!(chip8.tone());
+        chip8.step().unwrap();
+        assert!(!chip8.tone());
+    }
+}
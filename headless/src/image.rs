@@ -0,0 +1,160 @@
+//! Writers of the framebuffer in simple image formats, without external dependencies.
+
+use std::io::{self, Write};
+
+/// Grey levels for each combination of bit planes, like the SDL frontend
+const GREYS: [u8; 4] = [0, 255, 170, 85];
+/// Characters for each combination of bit planes in ASCII art
+const CHARS: [char; 4] = ['.', '#', '+', '*'];
+
+/// Write one byte per pixel holding its color as a grey scale PNG.
+pub fn write_png<W: Write>(
+    out: &mut W,
+    width: usize,
+    height: usize,
+    pixels: &[u8],
+) -> io::Result<()> {
+    // Every row starts with the filter type, 0 for none
+    let mut raw = Vec::with_capacity((width + 1) * height);
+    for row in pixels.chunks(width).take(height) {
+        raw.push(0);
+        raw.extend(row.iter().map(|c| GREYS[*c as usize & 3]));
+    }
+
+    out.write_all(b"\x89PNG\r\n\x1a\n")?;
+    let mut ihdr = Vec::with_capacity(13);
+    ihdr.extend_from_slice(&(width as u32).to_be_bytes());
+    ihdr.extend_from_slice(&(height as u32).to_be_bytes());
+    // 8 bits per sample, grey scale, deflate, adaptive filtering, no interlace
+    ihdr.extend_from_slice(&[8, 0, 0, 0, 0]);
+    write_chunk(out, b"IHDR", &ihdr)?;
+    write_chunk(out, b"IDAT", &zlib_stored(&raw))?;
+    write_chunk(out, b"IEND", &[])
+}
+
+/// Write a PNG chunk with its length and CRC.
+fn write_chunk<W: Write>(out: &mut W, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    out.write_all(&(data.len() as u32).to_be_bytes())?;
+    out.write_all(kind)?;
+    out.write_all(data)?;
+    let crc = !crc32_update(crc32_update(!0, kind), data);
+    out.write_all(&crc.to_be_bytes())
+}
+
+fn crc32_update(mut crc: u32, data: &[u8]) -> u32 {
+    for b in data {
+        crc ^= *b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                0xedb8_8320 ^ (crc >> 1)
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc
+}
+
+/// Wrap `data`, which must not be empty, in a zlib stream of uncompressed deflate blocks.
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    const MAX_BLOCK: usize = 0xffff;
+    let mut out = vec![0x78, 0x01];
+    let blocks = data.chunks(MAX_BLOCK).len();
+    for (i, block) in data.chunks(MAX_BLOCK).enumerate() {
+        out.push((i + 1 == blocks) as u8);
+        let len = block.len() as u16;
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(&(!len).to_le_bytes());
+        out.extend_from_slice(block);
+    }
+    let (mut a, mut b) = (1u32, 0u32);
+    for byte in data {
+        a = (a + *byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    out.extend_from_slice(&(b << 16 | a).to_be_bytes());
+    out
+}
+
+/// Write one bit per pixel, lit pixels set, as a binary PBM where lit pixels are black.
+pub fn write_pbm<W: Write>(
+    out: &mut W,
+    width: usize,
+    height: usize,
+    bits: &[u8],
+) -> io::Result<()> {
+    write!(out, "P4\n{} {}\n", width, height)?;
+    out.write_all(&bits[..width * height / 8])
+}
+
+/// Write one byte per pixel holding its color as ASCII art, one line per row.
+pub fn write_ascii<W: Write>(
+    out: &mut W,
+    width: usize,
+    height: usize,
+    pixels: &[u8],
+) -> io::Result<()> {
+    for row in pixels.chunks(width).take(height) {
+        let line: String = row.iter().map(|c| CHARS[*c as usize & 3]).collect();
+        writeln!(out, "{}", line)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checksums() {
+        assert_eq!(!crc32_update(!0, b"123456789"), 0xcbf4_3926);
+        let z = zlib_stored(b"Wikipedia");
+        assert_eq!(&z[..7], &[0x78, 0x01, 0x01, 0x09, 0x00, 0xf6, 0xff]);
+        assert_eq!(&z[7..16], b"Wikipedia");
+        // Adler-32
+        assert_eq!(&z[16..], &0x11e6_0398u32.to_be_bytes());
+
+        // Blocks are limited to 64 KiB, only the last one is final
+        let z = zlib_stored(&[0; 0x10000]);
+        assert_eq!(z.len(), 2 + 5 + 0xffff + 5 + 1 + 4);
+        assert_eq!(&z[2..7], &[0x00, 0xff, 0xff, 0x00, 0x00]);
+        assert_eq!(&z[2 + 5 + 0xffff..][..5], &[0x01, 0x01, 0x00, 0xfe, 0xff]);
+    }
+
+    #[test]
+    fn png() {
+        let mut out = Vec::new();
+        write_png(&mut out, 2, 2, &[0, 1, 2, 3]).unwrap();
+        assert_eq!(&out[..8], b"\x89PNG\r\n\x1a\n");
+
+        // Walk the chunks, checking their CRC
+        let mut chunks = Vec::new();
+        let mut rest = &out[8..];
+        while !rest.is_empty() {
+            let len = u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+            let (kind, data) = (&rest[4..8], &rest[8..8 + len]);
+            let crc = !crc32_update(crc32_update(!0, kind), data);
+            assert_eq!(&rest[8 + len..12 + len], &crc.to_be_bytes());
+            chunks.push((kind.to_vec(), data.to_vec()));
+            rest = &rest[12 + len..];
+        }
+        let kinds: Vec<_> = chunks.iter().map(|(k, _)| &k[..]).collect();
+        assert_eq!(kinds, [b"IHDR", b"IDAT", b"IEND"]);
+        assert_eq!(chunks[0].1, [0, 0, 0, 2, 0, 0, 0, 2, 8, 0, 0, 0, 0]);
+        // Rows of grey levels after their filter type
+        assert_eq!(&chunks[1].1[7..13], &[0, 0, 255, 0, 170, 85]);
+        assert!(chunks[2].1.is_empty());
+        assert_eq!(&out[out.len() - 4..], &[0xae, 0x42, 0x60, 0x82]);
+    }
+
+    #[test]
+    fn pbm_and_ascii() {
+        let mut out = Vec::new();
+        write_pbm(&mut out, 16, 2, &[0xf0, 0x0f, 0x80, 0x01, 0xff]).unwrap();
+        assert_eq!(out, b"P4\n16 2\n\xf0\x0f\x80\x01");
+
+        let mut out = Vec::new();
+        write_ascii(&mut out, 4, 2, &[0, 1, 2, 3, 1, 0, 0, 0]).unwrap();
+        assert_eq!(out, b".#+*\n#...\n");
+    }
+}
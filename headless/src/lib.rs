@@ -0,0 +1,102 @@
+//! Headless runner: runs a rom for a number of frames without a display and writes the final
+//! framebuffer, to test roms automatically.  The library holds what the runner shares with the
+//! SDL frontend and the golden image tests: the machine options, the keypad scripts and the
+//! image writers.
+
+pub mod image;
+pub mod options;
+
+use chip8::{self, Chip8};
+
+/// Keys held from a frame on
+pub struct KeyStep {
+    pub frame: u32,
+    pub keypad: u16,
+}
+
+/// Parse a keypad timeline of comma separated `FRAME=KEYS` steps, where KEYS are the hex digits
+/// of the keys held from FRAME on, or nothing to release them all.
+pub fn parse_keys(script: &str) -> Result<Vec<KeyStep>, String> {
+    let mut steps = Vec::new();
+    for step in script.split(',').filter(|s| !s.is_empty()) {
+        let (frame, keys) = step
+            .split_once('=')
+            .ok_or_else(|| format!("expected FRAME=KEYS, found `{}`", step))?;
+        let frame = frame
+            .trim()
+            .parse::<u32>()
+            .map_err(|e| format!("frame `{}`: {}", frame, e))?;
+        let mut keypad = 0;
+        for c in keys.trim().chars() {
+            let key = c
+                .to_digit(16)
+                .ok_or_else(|| format!("key `{}` is not a hex digit", c))?;
+            keypad |= 1 << key;
+        }
+        steps.push(KeyStep { frame, keypad });
+    }
+    steps.sort_by_key(|s| s.frame);
+    Ok(steps)
+}
+
+/// Run `frames` frames holding the keys of the timeline `keys`.  Returns the frame that failed
+/// along with its error.
+pub fn run(chip8: &mut Chip8, frames: u32, keys: &[KeyStep]) -> Result<(), (u32, chip8::Error)> {
+    let mut keypad = 0;
+    let mut steps = keys.iter().peekable();
+    for frame in 0..frames {
+        while let Some(step) = steps.next_if(|s| s.frame <= frame) {
+            keypad = step.keypad;
+        }
+        chip8.frame(keypad).map_err(|err| (frame, err))?;
+    }
+    Ok(())
+}
+
+/// Hash of the pixels with 32 bit FNV-1a
+pub fn fnv1a(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811c_9dc5, |h, b| {
+        (h ^ *b as u32).wrapping_mul(0x0100_0193)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys() {
+        let steps = parse_keys("60=5,10=a0,120=").unwrap();
+        let steps: Vec<_> = steps.iter().map(|s| (s.frame, s.keypad)).collect();
+        assert_eq!(steps, [(10, 1 << 0xa | 1), (60, 1 << 5), (120, 0)]);
+        assert_eq!(parse_keys(" 1 = F ,").unwrap()[0].keypad, 1 << 0xf);
+        assert!(parse_keys("").unwrap().is_empty());
+
+        assert!(parse_keys("5").err().unwrap().contains("FRAME=KEYS"));
+        assert!(parse_keys("x=1").err().unwrap().contains("frame `x`"));
+        assert!(parse_keys("1=g").err().unwrap().contains("key `g`"));
+    }
+
+    #[test]
+    fn timeline() {
+        // loop: SKP V0; JP loop; ADD V1, 1; JP loop, with V0 = 0
+        let mut chip8 = Chip8::new(0);
+        chip8
+            .load_rom(&[0xe0, 0x9e, 0x12, 0x00, 0x71, 0x01, 0x12, 0x00])
+            .unwrap();
+        run(&mut chip8, 10, &parse_keys("4=0,6=").unwrap()).unwrap();
+        // Key 0 was only held during frames 4 and 5
+        let counted = chip8.v()[1];
+        assert!(counted > 0);
+        run(&mut chip8, 10, &[]).unwrap();
+        assert_eq!(chip8.v()[1], counted);
+    }
+
+    #[test]
+    fn hash() {
+        // FNV-1a test vectors
+        assert_eq!(fnv1a(b""), 0x811c_9dc5);
+        assert_eq!(fnv1a(b"a"), 0xe40c_292c);
+        assert_eq!(fnv1a(b"foobar"), 0xbf9c_f968);
+    }
+}
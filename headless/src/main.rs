@@ -0,0 +1,132 @@
+use chip8::Chip8;
+
+use headless::{fnv1a, image, options, parse_keys, run};
+
+use clap::{App, Arg};
+
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::process;
+
+pub fn main() {
+    let app = App::new("Chip8-rs headless runner")
+        .version("0.0.1")
+        .author("Dhole")
+        .arg(
+            Arg::with_name("frames")
+                .short("n")
+                .long("frames")
+                .value_name("N")
+                .help("Sets the number of frames to run")
+                .takes_value(true)
+                .default_value("600")
+                .validator(|n| match n.parse::<u32>() {
+                    Ok(_) => Ok(()),
+                    Err(e) => Err(format!("{}", e)),
+                }),
+        )
+        .arg(
+            Arg::with_name("keys")
+                .short("k")
+                .long("keys")
+                .value_name("SCRIPT")
+                .help("Sets the keys held as FRAME=KEYS steps, for example 60=5,62=,120=4C")
+                .takes_value(true)
+                .validator(|script| parse_keys(&script).map(|_| ())),
+        )
+        .args(&options::args())
+        .arg(
+            Arg::with_name("seed")
+                .long("seed")
+                .value_name("N")
+                .help("Sets the seed of the random number generator")
+                .takes_value(true)
+                .default_value("0")
+                .validator(|n| match n.parse::<u64>() {
+                    Ok(_) => Ok(()),
+                    Err(e) => Err(format!("{}", e)),
+                }),
+        )
+        .arg(
+            Arg::with_name("output")
+                .short("o")
+                .long("output")
+                .value_name("FILE")
+                .help("Writes the screen to FILE as PNG (.png), PBM (.pbm) or ASCII art [default: ASCII art to stdout]")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("path")
+                .help("Path to the rom file")
+                .index(1)
+                .required(true),
+        )
+        .get_matches();
+
+    let frames = app
+        .value_of("frames")
+        .map(|n| n.parse::<u32>().expect("frames flag can be parsed as u32"))
+        .expect("frames argument is defined");
+    let keys = match app.value_of("keys") {
+        Some(script) => parse_keys(script).expect("keys flag can be parsed"),
+        None => Vec::new(),
+    };
+    let (mode, quirks) = options::machine(&app);
+    let seed = app
+        .value_of("seed")
+        .map(|n| n.parse::<u64>().expect("seed flag can be parsed as u64"))
+        .expect("seed argument is defined");
+    let path = app.value_of("path").expect("path argument is defined");
+
+    let rom = match fs::read(path) {
+        Ok(rom) => rom,
+        Err(err) => {
+            eprintln!("error: {}: {}", path, err);
+            process::exit(1);
+        }
+    };
+    let mut chip8 = Chip8::with_quirks(seed, quirks);
+    chip8.set_mode(mode);
+    let result = chip8
+        .load_rom(&rom)
+        .map_err(|err| (0, err))
+        .and_then(|_| run(&mut chip8, frames, &keys));
+    if let Err((_, err)) = result {
+        eprintln!("error: chip8: {}", err);
+        if let Some(fault) = err.fault() {
+            eprintln!("{}", fault);
+        }
+        process::exit(1);
+    }
+
+    let (width, height) = chip8.resolution();
+    let mut pixels = vec![0; width * height];
+    chip8.to_8bpp(&mut pixels).expect("buffer fits the screen");
+    if let Err(err) = write_screen(&chip8, app.value_of("output"), &pixels) {
+        eprintln!("error: {}", err);
+        process::exit(1);
+    }
+    println!("{:08x}", fnv1a(&pixels));
+}
+
+/// Write the screen to `output`, in the format given by its extension, or as ASCII art to stdout.
+fn write_screen(chip8: &Chip8, output: Option<&str>, pixels: &[u8]) -> io::Result<()> {
+    let (width, height) = chip8.resolution();
+    let path = match output {
+        Some(path) => Path::new(path),
+        None => return image::write_ascii(&mut io::stdout(), width, height, pixels),
+    };
+    let mut out = Vec::new();
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("png") => image::write_png(&mut out, width, height, pixels)?,
+        Some("pbm") => {
+            let mut bits = vec![0; width * height / 8];
+            chip8.to_1bpp(&mut bits).expect("buffer fits the screen");
+            image::write_pbm(&mut out, width, height, &bits)?
+        }
+        _ => image::write_ascii(&mut out, width, height, pixels)?,
+    }
+    fs::write(path, &out)
+        .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+}
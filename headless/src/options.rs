@@ -0,0 +1,89 @@
+//! Command line options of the machine, shared by the frontends.
+
+use chip8::{Mode, OpcodePolicy, Quirks, Timing};
+
+use clap::{Arg, ArgMatches};
+
+/// Options selecting the instruction set, the quirks and the timing of the machine
+pub fn args<'a, 'b>() -> [Arg<'a, 'b>; 4] {
+    [
+        Arg::with_name("mode")
+            .short("m")
+            .long("mode")
+            .value_name("MODE")
+            .help("Sets the instruction set")
+            .takes_value(true)
+            .possible_values(&["chip8", "schip", "xochip"])
+            .default_value("chip8"),
+        Arg::with_name("quirks")
+            .short("q")
+            .long("quirks")
+            .value_name("PRESET")
+            .help("Sets the compatibility quirks [default: chosen by mode]")
+            .takes_value(true)
+            .possible_values(&["vip", "chip48", "schip", "modern"]),
+        Arg::with_name("tickrate")
+            .short("t")
+            .long("tickrate")
+            .value_name("N")
+            .help("Runs N instructions per frame instead of timing each instruction")
+            .takes_value(true)
+            .validator(|n| match n.parse::<u16>() {
+                Ok(_) => Ok(()),
+                Err(e) => Err(format!("{}", e)),
+            }),
+        Arg::with_name("skip-unknown")
+            .long("skip-unknown")
+            .help("Skips unknown opcodes instead of stopping"),
+    ]
+}
+
+/// Instruction set and quirks selected by the options of `args`
+pub fn machine(app: &ArgMatches) -> (Mode, Quirks) {
+    let mode = match app.value_of("mode").expect("mode argument is defined") {
+        "schip" => Mode::SuperChip,
+        "xochip" => Mode::XoChip,
+        _ => Mode::Chip8,
+    };
+    let mut quirks = match (app.value_of("quirks"), mode) {
+        (Some("vip"), _) => Quirks::cosmac_vip(),
+        (Some("chip48"), _) => Quirks::chip48(),
+        (Some("schip"), _) | (None, Mode::SuperChip) => Quirks::super_chip(),
+        (Some("modern"), _) | (None, Mode::XoChip) => Quirks::modern(),
+        _ => Quirks::default(),
+    };
+    if let Some(n) = app.value_of("tickrate") {
+        quirks.timing = Timing::Tickrate(n.parse().expect("tickrate flag can be parsed as u16"));
+    }
+    if app.is_present("skip-unknown") {
+        quirks.unknown_opcode = OpcodePolicy::Nop;
+    }
+    (mode, quirks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::App;
+
+    fn parse(argv: &[&str]) -> (Mode, Quirks) {
+        let app = App::new("test").args(&args()).get_matches_from(argv);
+        machine(&app)
+    }
+
+    #[test]
+    fn presets() {
+        assert_eq!(parse(&["test"]), (Mode::Chip8, Quirks::default()));
+        assert_eq!(
+            parse(&["test", "-m", "schip"]),
+            (Mode::SuperChip, Quirks::super_chip())
+        );
+        assert_eq!(
+            parse(&["test", "-m", "xochip", "-q", "vip"]),
+            (Mode::XoChip, Quirks::cosmac_vip())
+        );
+        let (_, quirks) = parse(&["test", "-t", "500", "--skip-unknown"]);
+        assert_eq!(quirks.timing, Timing::Tickrate(500));
+        assert_eq!(quirks.unknown_opcode, OpcodePolicy::Nop);
+    }
+}
@@ -0,0 +1,318 @@
+//! Golden image regression suite: runs the bundled games with fixed seeds and scripted keys, and
+//! compares the final screen against the ASCII art checked in under `tests/golden`.  The cases
+//! go through the same key scripts, run loop and ASCII art writer as the `headless` runner.
+//!
+//! When a change of behaviour is intended, bless the new screens with
+//!
+//! ```text
+//! BLESS=1 cargo test -p headless --test golden --target x86_64-unknown-linux-gnu
+//! ```
+//!
+//! and review the diff of the golden files before committing them.
+
+use chip8::Chip8;
+
+use headless::{image, parse_keys, run};
+
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+/// Run of a rom whose final screen is compared against a golden image
+struct Case {
+    /// Name of the golden image
+    name: &'static str,
+    /// Rom file in `games/`
+    rom: &'static str,
+    frames: u32,
+    seed: u64,
+    /// Keys held from a frame on, as a script of the `--keys` option
+    keys: &'static str,
+}
+
+const CASES: &[Case] = &[
+    Case {
+        name: "test_opcode",
+        rom: "test_opcode.ch8",
+        frames: 60,
+        seed: 0,
+        keys: "",
+    },
+    Case {
+        name: "15puzzle",
+        rom: "15PUZZLE",
+        frames: 300,
+        seed: 1,
+        keys: "",
+    },
+    Case {
+        name: "blinky",
+        rom: "BLINKY",
+        frames: 300,
+        seed: 1,
+        keys: "",
+    },
+    Case {
+        name: "blitz",
+        rom: "BLITZ",
+        frames: 300,
+        seed: 1,
+        keys: "",
+    },
+    Case {
+        name: "brix",
+        rom: "BRIX",
+        frames: 300,
+        seed: 1,
+        keys: "",
+    },
+    // Move the paddle left, then right
+    Case {
+        name: "brix_keys",
+        rom: "BRIX",
+        frames: 300,
+        seed: 1,
+        keys: "60=4,120=,150=6,240=",
+    },
+    Case {
+        name: "connect4",
+        rom: "CONNECT4",
+        frames: 300,
+        seed: 1,
+        keys: "",
+    },
+    Case {
+        name: "guess",
+        rom: "GUESS",
+        frames: 300,
+        seed: 1,
+        keys: "",
+    },
+    Case {
+        name: "hidden",
+        rom: "HIDDEN",
+        frames: 300,
+        seed: 1,
+        keys: "",
+    },
+    Case {
+        name: "invaders",
+        rom: "INVADERS",
+        frames: 300,
+        seed: 1,
+        keys: "",
+    },
+    // Leave the title screen and shoot
+    Case {
+        name: "invaders_keys",
+        rom: "INVADERS",
+        frames: 600,
+        seed: 1,
+        keys: "60=5,70=,300=5,310=",
+    },
+    // Draw a pattern and replay it, the screen only shows a dot without input
+    Case {
+        name: "kaleid",
+        rom: "KALEID",
+        frames: 300,
+        seed: 1,
+        keys: "20=6,60=,70=2,110=,120=4,150=,160=0,170=",
+    },
+    Case {
+        name: "maze",
+        rom: "MAZE",
+        frames: 300,
+        seed: 1,
+        keys: "",
+    },
+    Case {
+        name: "maze_seed",
+        rom: "MAZE",
+        frames: 300,
+        seed: 2,
+        keys: "",
+    },
+    Case {
+        name: "merlin",
+        rom: "MERLIN",
+        frames: 300,
+        seed: 1,
+        keys: "",
+    },
+    Case {
+        name: "missile",
+        rom: "MISSILE",
+        frames: 300,
+        seed: 1,
+        keys: "",
+    },
+    Case {
+        name: "pong",
+        rom: "PONG",
+        frames: 300,
+        seed: 1,
+        keys: "",
+    },
+    // Move the left paddle up, after the last point since scoring recenters the paddles
+    Case {
+        name: "pong_keys",
+        rom: "PONG",
+        frames: 300,
+        seed: 1,
+        keys: "240=1,270=",
+    },
+    Case {
+        name: "pong2",
+        rom: "PONG2",
+        frames: 300,
+        seed: 1,
+        keys: "",
+    },
+    Case {
+        name: "puzzle",
+        rom: "PUZZLE",
+        frames: 300,
+        seed: 1,
+        keys: "",
+    },
+    Case {
+        name: "syzygy",
+        rom: "SYZYGY",
+        frames: 300,
+        seed: 1,
+        keys: "",
+    },
+    Case {
+        name: "tank",
+        rom: "TANK",
+        frames: 300,
+        seed: 1,
+        keys: "",
+    },
+    Case {
+        name: "tetris",
+        rom: "TETRIS",
+        frames: 300,
+        seed: 1,
+        keys: "",
+    },
+    // Move the piece left, then rotate it
+    Case {
+        name: "tetris_keys",
+        rom: "TETRIS",
+        frames: 300,
+        seed: 1,
+        keys: "150=5,200=,220=4,230=",
+    },
+    Case {
+        name: "tictac",
+        rom: "TICTAC",
+        frames: 300,
+        seed: 1,
+        keys: "",
+    },
+    Case {
+        name: "ufo",
+        rom: "UFO",
+        frames: 300,
+        seed: 1,
+        keys: "",
+    },
+    Case {
+        name: "vbrix",
+        rom: "VBRIX",
+        frames: 300,
+        seed: 1,
+        keys: "",
+    },
+    Case {
+        name: "vers",
+        rom: "VERS",
+        frames: 300,
+        seed: 1,
+        keys: "",
+    },
+    Case {
+        name: "wipeoff",
+        rom: "WIPEOFF",
+        frames: 300,
+        seed: 1,
+        keys: "",
+    },
+];
+
+fn root() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+}
+
+/// Run the case and draw the final screen as ASCII art, one line per row.
+fn run_case(case: &Case) -> Result<String, String> {
+    let path = root().join("../games").join(case.rom);
+    let rom = fs::read(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let keys = parse_keys(case.keys)?;
+    let mut chip8 = Chip8::new(case.seed);
+    chip8.load_rom(&rom).map_err(|e| e.to_string())?;
+    run(&mut chip8, case.frames, &keys).map_err(|(frame, e)| format!("frame {}: {}", frame, e))?;
+    let (width, height) = chip8.resolution();
+    let mut pixels = vec![0; width * height];
+    chip8.to_8bpp(&mut pixels).expect("buffer fits the screen");
+    let mut art = Vec::new();
+    image::write_ascii(&mut art, width, height, &pixels).map_err(|e| e.to_string())?;
+    Ok(String::from_utf8(art).expect("ASCII art is UTF-8"))
+}
+
+#[test]
+fn golden_images() {
+    let bless = env::var_os("BLESS").is_some();
+    let dir = root().join("tests/golden");
+    let mut failures = Vec::new();
+    let mut screens = HashMap::new();
+    for case in CASES {
+        let art = match run_case(case) {
+            Ok(art) => art,
+            Err(err) => {
+                failures.push(format!("{}: {}", case.name, err));
+                continue;
+            }
+        };
+        screens.insert(case.name, art.clone());
+        let path = dir.join(case.name).with_extension("txt");
+        if bless {
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(&path, &art).unwrap();
+            continue;
+        }
+        match fs::read_to_string(&path) {
+            Ok(golden) if golden == art => {}
+            Ok(golden) => failures.push(format!(
+                "{}: screen differs from {}\nexpected:\n{}actual:\n{}",
+                case.name,
+                path.display(),
+                golden,
+                art
+            )),
+            Err(err) => failures.push(format!("{}: {}: {}", case.name, path.display(), err)),
+        }
+    }
+    // The keys of a case must have a visible effect, or it only duplicates its no-input twin
+    for case in CASES.iter().filter(|case| case.name.ends_with("_keys")) {
+        let twin = case.name.trim_end_matches("_keys");
+        assert!(
+            CASES.iter().any(|case| case.name == twin),
+            "{}: no case {}",
+            case.name,
+            twin
+        );
+        if screens.get(case.name) == screens.get(twin) {
+            failures.push(format!("{}: same screen as {}", case.name, twin));
+        }
+    }
+    assert!(
+        failures.is_empty(),
+        "{} of {} golden images failed, run with BLESS=1 to accept the new screens\n\n{}",
+        failures.len(),
+        CASES.len(),
+        failures.join("\n")
+    );
+}
@@ -69,6 +69,16 @@ pub trait Pcd8544 {
         self.set_position(0, 0);
     }
 
+    // Update the columns x to x + width - 1 from a buffer with the layout of draw_buffer
+    fn draw_columns(&mut self, buffer: &[u8; 6*84], x: u8, width: u8) {
+        let start = x as usize * 6;
+        self.command(0x22); // vertical addressing
+        self.set_position(x, 0);
+        self.data(&buffer[start..start + width as usize * 6]);
+        self.command(0x20); // horizontal addressing
+        self.set_position(0, 0);
+    }
+
     fn clear(&mut self) {
         self.set_position(0, 0);
         self.data(&[0u8; 6*84]);
@@ -1,16 +1,20 @@
-use chip8::{self, Chip8};
+use chip8::{self, Audio, Chip8, Header, Player, Recorder, Rewind, RngState};
 
-use sdl2::audio::{AudioCallback, AudioSpecDesired};
+use headless::options;
+
+use sdl2::audio::AudioSpecDesired;
 use sdl2::event::Event;
 use sdl2::keyboard::Keycode;
 use sdl2::pixels::{Color, PixelFormatEnum};
 
-use rand::{self, RngCore};
-
 use clap::{App, Arg};
 
+use std::error;
+use std::fmt;
 use std::fs;
 use std::io::{self, Read};
+use std::mem;
+use std::process;
 use std::time::{Duration, Instant};
 
 #[derive(Debug)]
@@ -20,6 +24,25 @@ pub enum FrontError {
     Io(io::Error),
 }
 
+impl fmt::Display for FrontError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Chip8(err) => {
+                write!(f, "chip8: {}", err)?;
+                // Crash report with the state of the machine
+                if let Some(fault) = err.fault() {
+                    write!(f, "\n{}", fault)?;
+                }
+                Ok(())
+            }
+            Self::SDL2(err) => write!(f, "SDL2: {}", err),
+            Self::Io(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl error::Error for FrontError {}
+
 impl From<chip8::Error> for FrontError {
     fn from(err: chip8::Error) -> Self {
         Self::Chip8(err)
@@ -38,29 +61,14 @@ impl From<String> for FrontError {
     }
 }
 
-struct SquareWave {
-    phase_inc: f32,
-    phase: f32,
-    volume: f32,
-}
-
-impl AudioCallback for SquareWave {
-    type Channel = f32;
-
-    fn callback(&mut self, out: &mut [f32]) {
-        // Generate a square wave
-        for x in out.iter_mut() {
-            *x = if self.phase <= 0.5 {
-                self.volume
-            } else {
-                -self.volume
-            };
-            self.phase = (self.phase + self.phase_inc) % 1.0;
-        }
+pub fn main() {
+    if let Err(err) = try_main() {
+        eprintln!("error: {}", err);
+        process::exit(1);
     }
 }
 
-pub fn main() -> Result<(), FrontError> {
+fn try_main() -> Result<(), FrontError> {
     let app = App::new("Chip8-rs")
         .version("0.0.1")
         .author("Dhole")
@@ -77,6 +85,22 @@ pub fn main() -> Result<(), FrontError> {
                     Err(e) => Err(format!("{}", e)),
                 }),
         )
+        .args(&options::args())
+        .arg(
+            Arg::with_name("record")
+                .long("record")
+                .value_name("MOVIE")
+                .help("Records the keypad of every frame into a movie file")
+                .takes_value(true)
+                .conflicts_with("play"),
+        )
+        .arg(
+            Arg::with_name("play")
+                .long("play")
+                .value_name("MOVIE")
+                .help("Replays a movie file, with the settings it was recorded with")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("path")
                 .help("Path to the rom file")
@@ -89,6 +113,7 @@ pub fn main() -> Result<(), FrontError> {
         .value_of("scale")
         .map(|s| s.parse::<u32>().expect("scale flag can be parsed as u32"))
         .expect("scale argument is defined");
+    let (mode, quirks) = options::machine(&app);
     let path = app.value_of("path").expect("path argument is defined");
 
     let mut rom = Vec::new();
@@ -97,12 +122,95 @@ pub fn main() -> Result<(), FrontError> {
         .open(path)?
         .read_to_end(&mut rom)?;
 
-    let mut chip8 = Chip8::new(rand::random());
-    chip8.load_rom(&rom)?;
-    run(scale, &mut chip8)
+    let movie_data;
+    let mut movie_storage;
+    let (mut chip8, mut movie) = if let Some(movie_path) = app.value_of("play") {
+        movie_data = fs::read(movie_path)?;
+        let player = Player::new(&movie_data)?;
+        (player.header().machine(&rom)?, Some(Movie::Play(player)))
+    } else {
+        let seed = rand::random();
+        let mut chip8 = Chip8::with_quirks(seed, quirks);
+        chip8.set_mode(mode);
+        chip8.load_rom(&rom)?;
+        let movie = match app.value_of("record") {
+            Some(movie_path) => {
+                movie_storage = vec![0; MOVIE_BUFFER_SIZE];
+                let header = Header::new(&rom, seed, mode, quirks, MOVIE_CHECKSUM_INTERVAL);
+                let recorder = Recorder::new(&mut movie_storage, header)?;
+                Some(Movie::Record(recorder, movie_path))
+            }
+            None => None,
+        };
+        (chip8, movie)
+    };
+    let result = run(scale, path, &mut chip8, &mut movie);
+    // Keep the recording when the machine fails, to reproduce the failure
+    if let Some(Movie::Record(recorder, movie_path)) = movie {
+        fs::write(movie_path, recorder.data())?;
+        println!("Recorded {} frames into {}", recorder.frames(), movie_path);
+    }
+    result
+}
+
+/// Input movie being recorded or replayed
+enum Movie<'a> {
+    Record(Recorder<'a>, &'a str),
+    Play(Player<'a>),
+}
+
+/// Memory used to record a movie, enough for about 38 hours
+const MOVIE_BUFFER_SIZE: usize = 16 * 1024 * 1024;
+/// Frames between checksums of the machine in recorded movies
+const MOVIE_CHECKSUM_INTERVAL: u16 = 60;
+
+/// Memory used to record the history for rewinding
+const REWIND_BUFFER_SIZE: usize = 16 * 1024 * 1024;
+
+/// Key of the CHIP-8 keypad mapped to a key of the keyboard
+fn key_map(keycode: Keycode) -> Option<u8> {
+    Some(match keycode {
+        Keycode::Num1 => 0x1,
+        Keycode::Num2 => 0x2,
+        Keycode::Num3 => 0x3,
+        Keycode::Num4 => 0xC,
+        Keycode::Q => 0x4,
+        Keycode::W => 0x5,
+        Keycode::E => 0x6,
+        Keycode::R => 0xD,
+        Keycode::A => 0x7,
+        Keycode::S => 0x8,
+        Keycode::D => 0x9,
+        Keycode::F => 0xE,
+        Keycode::Z => 0xA,
+        Keycode::X => 0x0,
+        Keycode::C => 0xB,
+        Keycode::V => 0xF,
+        _ => return None,
+    })
+}
+
+/// Grey levels for each combination of bit planes
+const PALETTE: [u8; 4] = [0, 255, 170, 85];
+/// Decrease of the grey level of erased pixels on each frame
+const FADE_STEP: u8 = 30;
+/// Frames for an erased pixel to fade out completely
+const FADE_FRAMES: u8 = u8::MAX / FADE_STEP + 1;
+
+/// Longest time emulated at once, to avoid catching up after the process was stalled
+const MAX_RUN_DURATION: Duration = Duration::from_millis(100);
+
+/// Path of the file for the save state slot of the rom
+fn state_path(rom_path: &str, slot: u8) -> String {
+    format!("{}.state{}", rom_path, slot)
 }
 
-fn run<R: RngCore>(scale: u32, chip8: &mut Chip8<R>) -> Result<(), FrontError> {
+fn run<R: RngState>(
+    scale: u32,
+    rom_path: &str,
+    chip8: &mut Chip8<R>,
+    movie: &mut Option<Movie>,
+) -> Result<(), FrontError> {
     let sdl_context = sdl2::init()?;
     let video_subsystem = sdl_context.video()?;
     let audio_subsystem = sdl_context.audio()?;
@@ -110,17 +218,15 @@ fn run<R: RngCore>(scale: u32, chip8: &mut Chip8<R>) -> Result<(), FrontError> {
     let desired_spec = AudioSpecDesired {
         freq: Some(44100),
         channels: Some(1), // mono
-        samples: None,     // default sample size
+        samples: Some(512),
     };
 
-    let device = audio_subsystem.open_playback(None, &desired_spec, |spec| {
-        // initialize the audio callback
-        SquareWave {
-            phase_inc: 440.0 / spec.freq as f32,
-            phase: 0.0,
-            volume: 0.25,
-        }
-    })?;
+    let queue = audio_subsystem.open_queue::<f32, _>(None, &desired_spec)?;
+    let sample_rate = queue.spec().freq as u32;
+    let mut audio = Audio::new(sample_rate);
+    let mut samples =
+        vec![0.0; sample_rate as usize * MAX_RUN_DURATION.as_millis() as usize / 1000 + 1];
+    queue.resume();
 
     let window = video_subsystem
         .window(
@@ -142,8 +248,8 @@ fn run<R: RngCore>(scale: u32, chip8: &mut Chip8<R>) -> Result<(), FrontError> {
     let mut tex_display = texture_creator
         .create_texture_streaming(
             PixelFormatEnum::RGB24,
-            chip8::SCREEN_WIDTH as u32,
-            chip8::SCREEN_HEIGTH as u32,
+            chip8::HIRES_SCREEN_WIDTH as u32,
+            chip8::HIRES_SCREEN_HEIGTH as u32,
         )
         .map_err(|e| e.to_string())?;
 
@@ -151,8 +257,20 @@ fn run<R: RngCore>(scale: u32, chip8: &mut Chip8<R>) -> Result<(), FrontError> {
 
     let frame_duration = Duration::new(0, 1_000_000_000u32 / 60);
     let mut timestamp = Instant::now();
-    let mut keypad = 0u16;
+    let mut last_run = timestamp;
+    let mut timer = sdl_context.timer()?;
+    let mut last_run_ticks = timer.ticks();
+    let mut slot = 1;
+    let mut state = vec![0; chip8::STATE_SIZE];
+    let mut rewind_storage = vec![0; REWIND_BUFFER_SIZE];
+    let mut rewind = Rewind::new(&mut rewind_storage)?;
+    let mut rewinding = false;
+    let mut fading = 0;
+    // Keys held, for the frames of movies
+    let mut keypad: u16 = 0;
     'running: loop {
+        // Events happened since the last run, and are replayed at the same offset in the next one
+        let run_offset = |timestamp: u32| timestamp.saturating_sub(last_run_ticks) * 1000;
         for event in event_pump.poll_iter() {
             match event {
                 Event::Quit { .. }
@@ -161,106 +279,139 @@ fn run<R: RngCore>(scale: u32, chip8: &mut Chip8<R>) -> Result<(), FrontError> {
                     ..
                 } => break 'running,
                 Event::KeyDown {
-                    keycode: Some(keycode),
+                    keycode: Some(keycode @ (Keycode::F1 | Keycode::F2 | Keycode::F3 | Keycode::F4)),
                     ..
                 } => {
-                    keypad |= match keycode {
-                        Keycode::Num1 => 1 << 0x1,
-                        Keycode::Num2 => 1 << 0x2,
-                        Keycode::Num3 => 1 << 0x3,
-                        Keycode::Num4 => 1 << 0xC,
-                        Keycode::Q => 1 << 0x4,
-                        Keycode::W => 1 << 0x5,
-                        Keycode::E => 1 << 0x6,
-                        Keycode::R => 1 << 0xD,
-                        Keycode::A => 1 << 0x7,
-                        Keycode::S => 1 << 0x8,
-                        Keycode::D => 1 << 0x9,
-                        Keycode::F => 1 << 0xE,
-                        Keycode::Z => 1 << 0xA,
-                        Keycode::X => 1 << 0x0,
-                        Keycode::C => 1 << 0xB,
-                        Keycode::V => 1 << 0xF,
-                        _ => 0,
+                    slot = match keycode {
+                        Keycode::F1 => 1,
+                        Keycode::F2 => 2,
+                        Keycode::F3 => 3,
+                        _ => 4,
                     };
+                    println!("Selected state slot {}", slot);
                 }
+                Event::KeyDown {
+                    keycode: Some(Keycode::Backspace),
+                    ..
+                } if movie.is_none() => rewinding = true,
                 Event::KeyUp {
+                    keycode: Some(Keycode::Backspace),
+                    ..
+                } => rewinding = false,
+                Event::KeyDown {
+                    keycode: Some(Keycode::F5),
+                    ..
+                } => {
+                    let len = chip8.save_state(&mut state)?;
+                    fs::write(state_path(rom_path, slot), &state[..len])?;
+                    println!("Saved state to slot {}", slot);
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::F8),
+                    ..
+                } if movie.is_some() => println!("Unable to load states while a movie is active"),
+                Event::KeyDown {
+                    keycode: Some(Keycode::F8),
+                    ..
+                } => match fs::read(state_path(rom_path, slot)) {
+                    Ok(saved) => match chip8.load_state(&saved) {
+                        Ok(()) => println!("Loaded state from slot {}", slot),
+                        Err(e) => println!("Unable to load state from slot {}: {}", slot, e),
+                    },
+                    Err(e) => println!("Unable to load state from slot {}: {}", slot, e),
+                },
+                Event::KeyDown {
                     keycode: Some(keycode),
+                    timestamp,
                     ..
                 } => {
-                    keypad &= !match keycode {
-                        Keycode::Num1 => 1 << 0x1,
-                        Keycode::Num2 => 1 << 0x2,
-                        Keycode::Num3 => 1 << 0x3,
-                        Keycode::Num4 => 1 << 0xC,
-                        Keycode::Q => 1 << 0x4,
-                        Keycode::W => 1 << 0x5,
-                        Keycode::E => 1 << 0x6,
-                        Keycode::R => 1 << 0xD,
-                        Keycode::A => 1 << 0x7,
-                        Keycode::S => 1 << 0x8,
-                        Keycode::D => 1 << 0x9,
-                        Keycode::F => 1 << 0xE,
-                        Keycode::Z => 1 << 0xA,
-                        Keycode::X => 1 << 0x0,
-                        Keycode::C => 1 << 0xB,
-                        Keycode::V => 1 << 0xF,
-                        _ => 0,
-                    };
+                    if let Some(key) = key_map(keycode) {
+                        keypad |= 1 << key;
+                        if movie.is_none() {
+                            chip8.key_down_at(key, run_offset(timestamp));
+                        }
+                    }
+                }
+                Event::KeyUp {
+                    keycode: Some(keycode),
+                    timestamp,
+                    ..
+                } => {
+                    if let Some(key) = key_map(keycode) {
+                        keypad &= !(1 << key);
+                        if movie.is_none() {
+                            chip8.key_up_at(key, run_offset(timestamp));
+                        }
+                    }
                 }
                 _ => {}
             }
         }
 
-        chip8.frame(keypad)?;
-        if chip8.tone() {
-            device.resume();
+        if rewinding {
+            rewind.pop(chip8)?;
+            // Don't run the time spent rewinding when it stops
+            last_run = Instant::now();
+            last_run_ticks = timer.ticks();
         } else {
-            device.pause();
-        }
-
-        // tex_display.with_lock(None, |buffer: &mut [u8], pitch: usize| {
-        //     for y in 0..chip8::SCREEN_HEIGTH {
-        //         for x in 0..chip8::SCREEN_WIDTH / 8 {
-        //             let byte = chip8.fb()[y * chip8::SCREEN_WIDTH / 8 + x];
-        //             for i in 0..8 {
-        //                 let offset = y * pitch + (x * 8 + i) * 3;
-        //                 let v = if byte & 1 << (7 - i) != 0 { 255 } else { 0 };
-        //                 buffer[offset] = v;
-        //                 buffer[offset + 1] = v;
-        //                 buffer[offset + 2] = v;
-        //             }
-        //         }
-        //     }
-        // })?;
-
-        tex_display.with_lock(None, |buffer: &mut [u8], pitch: usize| {
-            for y in 0..chip8::SCREEN_HEIGTH {
-                for x in 0..chip8::SCREEN_WIDTH / 8 {
-                    let byte = chip8.fb()[y * chip8::SCREEN_WIDTH / 8 + x];
-                    for i in 0..8 {
-                        let offset = y * pitch + (x * 8 + i) * 3;
-                        let on = if byte & 1 << (7 - i) != 0 {
-                            true
-                        } else {
-                            false
-                        };
-                        const FACTOR: u8 = 30;
-                        let v = if on {
-                            255
-                        } else {
-                            buffer[offset].saturating_sub(FACTOR)
-                        };
-                        buffer[offset] = v;
-                        buffer[offset + 1] = v;
-                        buffer[offset + 2] = v;
+            // Run for the real time elapsed, so that the speed doesn't depend on the loop rate
+            let now = Instant::now();
+            let elapsed = now.duration_since(last_run).min(MAX_RUN_DURATION);
+            last_run = now;
+            last_run_ticks = timer.ticks();
+            // Movies run whole frames with the keys held, which is all they record
+            match movie {
+                Some(Movie::Record(recorder, _)) => recorder.frame(chip8, keypad)?,
+                Some(Movie::Play(player)) => {
+                    if !player.frame(chip8)? {
+                        println!("End of movie after {} frames", player.frames());
+                        *movie = None;
+                        chip8.set_keypad(keypad);
                     }
                 }
+                None => chip8.run_for(elapsed.as_micros() as u32)?,
+            }
+            rewind.push(chip8)?;
+            let len = audio.render(chip8, &mut samples);
+            // Drop the queued audio if it lags more than the longest run behind the machine
+            if queue.size() as usize > samples.len() * mem::size_of::<f32>() {
+                queue.clear();
             }
-        })?;
+            queue.queue(&samples[..len]);
+        }
 
-        canvas.clear();
-        canvas.copy(&tex_display, None, None)?;
+        // Update the texture while the screen changes and until the erased pixels fade out
+        if !chip8.dirty().is_empty() {
+            fading = FADE_FRAMES;
+            chip8.ack_dirty();
+        }
+        if fading != 0 {
+            fading -= 1;
+            let (width, _) = chip8.resolution();
+            // Lores pixels are drawn as blocks of texels in the hi-res texture
+            let px = chip8::HIRES_SCREEN_WIDTH / width;
+            tex_display.with_lock(None, |buffer: &mut [u8], pitch: usize| {
+                for (y, row) in chip8.rows().enumerate() {
+                    for (x, color) in row.enumerate() {
+                        for ty in y * px..(y + 1) * px {
+                            for tx in x * px..(x + 1) * px {
+                                let offset = ty * pitch + tx * 3;
+                                let v = if color != 0 {
+                                    PALETTE[color as usize]
+                                } else {
+                                    buffer[offset].saturating_sub(FADE_STEP)
+                                };
+                                buffer[offset] = v;
+                                buffer[offset + 1] = v;
+                                buffer[offset + 2] = v;
+                            }
+                        }
+                    }
+                }
+            })?;
+            canvas.clear();
+            canvas.copy(&tex_display, None, None)?;
+        }
         canvas.present();
         let now = Instant::now();
         let sleep_dur = frame_duration
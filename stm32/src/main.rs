@@ -24,7 +24,7 @@ use arrayvec::ArrayString;
 
 use pcd8544_hal::{self, Pcd8544, Pcd8544Spi};
 
-use chip8::{self, Chip8};
+use chip8::{self, Chip8, Rewind};
 static ROM_GUESS: &[u8] = include_bytes!("../../games/GUESS");
 static ROM_VBRIX: &[u8] = include_bytes!("../../games/VBRIX");
 static ROM_SYZYGY: &[u8] = include_bytes!("../../games/SYZYGY");
@@ -71,6 +71,10 @@ static ROM_TANK: &[u8] = include_bytes!("../../games/TANK");
 
 const SYSCLK: u32 = 72_000_000;
 
+/// Memory used to record the history for rewinding: the latest save state and the deltas of the
+/// previous frames, which fit in what's left of the 20 KiB of RAM next to the machine
+const REWIND_BUFFER_SIZE: usize = chip8::STATE_SIZE + 3 * 1024;
+
 fn key_pressed<O: OutputPin, I: InputPin>(r: &mut [O; 4], c: &mut [I; 4]) -> u16 {
     for pin in r.iter_mut() {
         pin.set_low().ok().unwrap();
@@ -136,7 +140,7 @@ fn key_map(k: u16) -> u16 {
 #[entry]
 fn main() -> ! {
     // Get access to the core peripherals from the cortex-m crate
-    let cp = cortex_m::Peripherals::take().unwrap();
+    let mut cp = cortex_m::Peripherals::take().unwrap();
     // Get access to the device specific peripherals from the peripheral access crate
     let dp = stm32::Peripherals::take().unwrap();
 
@@ -162,6 +166,13 @@ fn main() -> ! {
     // in order to configure the port. For pins 0-7, crl should be passed instead.
     let mut led = gpioc.pc13.into_push_pull_output(&mut gpioc.crh);
 
+    // Rewind button between PB0 and ground
+    let rewind_button = gpiob.pb0.into_pull_up_input(&mut gpiob.crl);
+
+    // Cycle counter used to measure the time between frames
+    cp.DCB.enable_trace();
+    cp.DWT.enable_cycle_counter();
+
     let mut delay = Delay::new(cp.SYST, clocks);
 
     // setup SPI
@@ -332,6 +343,8 @@ fn main() -> ! {
 
     let mut chip8 = Chip8::new(DWT::get_cycle_count() as u64);
     chip8.load_rom(roms[rom_n as usize].1).unwrap();
+    let mut rewind_storage = [0; REWIND_BUFFER_SIZE];
+    let mut rewind = Rewind::new(&mut rewind_storage).unwrap();
 
     // chip8 loop
 
@@ -339,11 +352,24 @@ fn main() -> ! {
     const DISP_HEIGHT: usize = 48;
     let mut disp_fb = [0; DISP_WIDTH * DISP_HEIGHT / 8];
     let mut fb_prev = [0; chip8::SCREEN_HEIGTH * chip8::SCREEN_WIDTH / 8];
+    let mut dirty_prev = chip8::Dirty::default();
     // let mut overtime: usize = 0;
+    const CYCLES_PER_US: u32 = SYSCLK / 1_000_000;
+    let mut cycles_prev = DWT::get_cycle_count();
     loop {
         block!(timer.wait()).unwrap();
-        let key = key_map(key_pressed(&mut keypad_r, &mut keypad_c));
-        chip8.frame(key).unwrap();
+        // Run for the time actually elapsed, in case the previous frame was late
+        let elapsed_us = DWT::get_cycle_count().wrapping_sub(cycles_prev) / CYCLES_PER_US;
+        cycles_prev = cycles_prev.wrapping_add(elapsed_us * CYCLES_PER_US);
+        if rewind_button.is_low().ok().unwrap() {
+            // Step back one frame per frame while the button is held
+            rewind.pop(&mut chip8).unwrap();
+        } else {
+            let key = key_map(key_pressed(&mut keypad_r, &mut keypad_c));
+            chip8.set_keypad(key);
+            chip8.run_for(elapsed_us).unwrap();
+            rewind.push(&chip8).unwrap();
+        }
         if chip8.tone() {
             pwm_tone.enable();
         } else {
@@ -352,13 +378,20 @@ fn main() -> ! {
         // DBG
         // disp_fb[0] = 0xff;
         // disp_fb[2] = 0xff;
+        // Pixels of the previous frame stay lit, so its changes are redrawn too
+        let dirty = chip8.dirty();
+        if dirty.union(dirty_prev).is_empty() {
+            led.toggle().unwrap();
+            continue;
+        }
         for b in disp_fb.iter_mut() {
             *b = 0x00;
         }
+        let fb = chip8.fb_ref();
         for y in 0..chip8::SCREEN_HEIGTH {
             for x in 0..chip8::SCREEN_WIDTH / 8 {
-                let byte = chip8.fb()[y * chip8::SCREEN_WIDTH / 8 + x]
-                    | fb_prev[y * chip8::SCREEN_WIDTH / 8 + x];
+                let byte =
+                    fb[y * chip8::SCREEN_WIDTH / 8 + x] | fb_prev[y * chip8::SCREEN_WIDTH / 8 + x];
                 for i in 0..8 {
                     let b = (byte & (1 << i)) >> i << (y % 8);
                     disp_fb[(10 + x * 8 + 7 - i) * DISP_HEIGHT / 8 + y / 8] |= b;
@@ -366,8 +399,16 @@ fn main() -> ! {
                 // disp_fb[y * DISP_WIDTH / 8 + 1 + x] = byte;
             }
         }
-        fb_prev.copy_from_slice(&chip8.fb());
-        pcd8544.draw_buffer(&disp_fb);
+        fb_prev.copy_from_slice(fb);
+        // Push only the columns of the display that changed
+        for col in dirty.union(dirty_prev).cols() {
+            if col >= chip8::SCREEN_WIDTH / 8 {
+                break;
+            }
+            pcd8544.draw_columns(&disp_fb, 10 + col as u8 * 8, 8);
+        }
+        dirty_prev = dirty;
+        chip8.ack_dirty();
         led.toggle().unwrap();
     }
 }